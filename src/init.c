@@ -34,8 +34,8 @@ SEXP handle_result(SEXP res_) {
     return (SEXP)res;
 }
 
-SEXP savvy_vellogd_impl__impl(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height) {
-    SEXP res = savvy_vellogd_impl__ffi(c_arg__filename, c_arg__width, c_arg__height);
+SEXP savvy_vellogd_impl__impl(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height, SEXP c_arg__max_fps, SEXP c_arg__queue_capacity, SEXP c_arg__feature_tags, SEXP c_arg__feature_values) {
+    SEXP res = savvy_vellogd_impl__ffi(c_arg__filename, c_arg__width, c_arg__height, c_arg__max_fps, c_arg__queue_capacity, c_arg__feature_tags, c_arg__feature_values);
     return handle_result(res);
 }
 
@@ -49,8 +49,43 @@ SEXP savvy_add_lottie_animation__impl(SEXP c_arg__filename) {
     return handle_result(res);
 }
 
-SEXP savvy_vellogd_with_server_impl__impl(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height, SEXP c_arg__server) {
-    SEXP res = savvy_vellogd_with_server_impl__ffi(c_arg__filename, c_arg__width, c_arg__height, c_arg__server);
+SEXP savvy_vellogd_with_server_impl__impl(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height, SEXP c_arg__listen, SEXP c_arg__max_fps, SEXP c_arg__queue_capacity, SEXP c_arg__feature_tags, SEXP c_arg__feature_values, SEXP c_arg__server, SEXP c_arg__address, SEXP c_arg__record, SEXP c_arg__name) {
+    SEXP res = savvy_vellogd_with_server_impl__ffi(c_arg__filename, c_arg__width, c_arg__height, c_arg__listen, c_arg__max_fps, c_arg__queue_capacity, c_arg__feature_tags, c_arg__feature_values, c_arg__server, c_arg__address, c_arg__record, c_arg__name);
+    return handle_result(res);
+}
+
+SEXP savvy_vellogd_attach_impl__impl(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height, SEXP c_arg__feature_tags, SEXP c_arg__feature_values, SEXP c_arg__name, SEXP c_arg__record) {
+    SEXP res = savvy_vellogd_attach_impl__ffi(c_arg__filename, c_arg__width, c_arg__height, c_arg__feature_tags, c_arg__feature_values, c_arg__name, c_arg__record);
+    return handle_result(res);
+}
+
+SEXP savvy_list_servers_impl__impl(void) {
+    SEXP res = savvy_list_servers_impl__ffi();
+    return handle_result(res);
+}
+
+SEXP savvy_queue_stats_impl__impl(void) {
+    SEXP res = savvy_queue_stats_impl__ffi();
+    return handle_result(res);
+}
+
+SEXP savvy_register_font_impl__impl(SEXP c_arg__family, SEXP c_arg__path, SEXP c_arg__face) {
+    SEXP res = savvy_register_font_impl__ffi(c_arg__family, c_arg__path, c_arg__face);
+    return handle_result(res);
+}
+
+SEXP savvy_set_font_fallbacks_impl__impl(SEXP c_arg__families, SEXP c_arg__script) {
+    SEXP res = savvy_set_font_fallbacks_impl__ffi(c_arg__families, c_arg__script);
+    return handle_result(res);
+}
+
+SEXP savvy_set_symbol_font_impl__impl(SEXP c_arg__family) {
+    SEXP res = savvy_set_symbol_font_impl__ffi(c_arg__family);
+    return handle_result(res);
+}
+
+SEXP savvy_rich_text_impl__impl(SEXP c_arg__x, SEXP c_arg__y, SEXP c_arg__text, SEXP c_arg__family, SEXP c_arg__face, SEXP c_arg__size, SEXP c_arg__color, SEXP c_arg__default_family, SEXP c_arg__default_face, SEXP c_arg__default_size, SEXP c_arg__default_color, SEXP c_arg__lineheight, SEXP c_arg__align, SEXP c_arg__hadj, SEXP c_arg__vadj, SEXP c_arg__angle, SEXP c_arg__halo_color, SEXP c_arg__halo_width, SEXP c_arg__feature_tags, SEXP c_arg__feature_values, SEXP c_arg__feature_spans, SEXP c_arg__max_width) {
+    SEXP res = savvy_rich_text_impl__ffi(c_arg__x, c_arg__y, c_arg__text, c_arg__family, c_arg__face, c_arg__size, c_arg__color, c_arg__default_family, c_arg__default_face, c_arg__default_size, c_arg__default_color, c_arg__lineheight, c_arg__align, c_arg__hadj, c_arg__vadj, c_arg__angle, c_arg__halo_color, c_arg__halo_width, c_arg__feature_tags, c_arg__feature_values, c_arg__feature_spans, c_arg__max_width);
     return handle_result(res);
 }
 
@@ -66,10 +101,17 @@ SEXP savvy_do_tracing__impl(SEXP c_arg__expr) {
 
 
 static const R_CallMethodDef CallEntries[] = {
-    {"savvy_vellogd_impl__impl", (DL_FUNC) &savvy_vellogd_impl__impl, 3},
+    {"savvy_vellogd_impl__impl", (DL_FUNC) &savvy_vellogd_impl__impl, 7},
     {"savvy_save_as_png__impl", (DL_FUNC) &savvy_save_as_png__impl, 1},
     {"savvy_add_lottie_animation__impl", (DL_FUNC) &savvy_add_lottie_animation__impl, 1},
-    {"savvy_vellogd_with_server_impl__impl", (DL_FUNC) &savvy_vellogd_with_server_impl__impl, 4},
+    {"savvy_vellogd_with_server_impl__impl", (DL_FUNC) &savvy_vellogd_with_server_impl__impl, 12},
+    {"savvy_vellogd_attach_impl__impl", (DL_FUNC) &savvy_vellogd_attach_impl__impl, 7},
+    {"savvy_list_servers_impl__impl", (DL_FUNC) &savvy_list_servers_impl__impl, 0},
+    {"savvy_queue_stats_impl__impl", (DL_FUNC) &savvy_queue_stats_impl__impl, 0},
+    {"savvy_register_font_impl__impl", (DL_FUNC) &savvy_register_font_impl__impl, 3},
+    {"savvy_set_font_fallbacks_impl__impl", (DL_FUNC) &savvy_set_font_fallbacks_impl__impl, 2},
+    {"savvy_set_symbol_font_impl__impl", (DL_FUNC) &savvy_set_symbol_font_impl__impl, 1},
+    {"savvy_rich_text_impl__impl", (DL_FUNC) &savvy_rich_text_impl__impl, 22},
     {"savvy_debuggd__impl", (DL_FUNC) &savvy_debuggd__impl, 0},
     {"savvy_do_tracing__impl", (DL_FUNC) &savvy_do_tracing__impl, 1},
     {NULL, NULL, 0}
@@ -1,6 +1,13 @@
-SEXP savvy_vellogd_impl__ffi(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height);
+SEXP savvy_vellogd_impl__ffi(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height, SEXP c_arg__max_fps, SEXP c_arg__queue_capacity, SEXP c_arg__feature_tags, SEXP c_arg__feature_values);
 SEXP savvy_save_as_png__ffi(SEXP c_arg__filename);
 SEXP savvy_add_lottie_animation__ffi(SEXP c_arg__filename);
-SEXP savvy_vellogd_with_server_impl__ffi(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height, SEXP c_arg__server);
+SEXP savvy_vellogd_with_server_impl__ffi(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height, SEXP c_arg__listen, SEXP c_arg__max_fps, SEXP c_arg__queue_capacity, SEXP c_arg__feature_tags, SEXP c_arg__feature_values, SEXP c_arg__server, SEXP c_arg__address, SEXP c_arg__record, SEXP c_arg__name);
+SEXP savvy_vellogd_attach_impl__ffi(SEXP c_arg__filename, SEXP c_arg__width, SEXP c_arg__height, SEXP c_arg__feature_tags, SEXP c_arg__feature_values, SEXP c_arg__name, SEXP c_arg__record);
+SEXP savvy_list_servers_impl__ffi(void);
+SEXP savvy_queue_stats_impl__ffi(void);
+SEXP savvy_register_font_impl__ffi(SEXP c_arg__family, SEXP c_arg__path, SEXP c_arg__face);
+SEXP savvy_set_font_fallbacks_impl__ffi(SEXP c_arg__families, SEXP c_arg__script);
+SEXP savvy_set_symbol_font_impl__ffi(SEXP c_arg__family);
+SEXP savvy_rich_text_impl__ffi(SEXP c_arg__x, SEXP c_arg__y, SEXP c_arg__text, SEXP c_arg__family, SEXP c_arg__face, SEXP c_arg__size, SEXP c_arg__color, SEXP c_arg__default_family, SEXP c_arg__default_face, SEXP c_arg__default_size, SEXP c_arg__default_color, SEXP c_arg__lineheight, SEXP c_arg__align, SEXP c_arg__hadj, SEXP c_arg__vadj, SEXP c_arg__angle, SEXP c_arg__halo_color, SEXP c_arg__halo_width, SEXP c_arg__feature_tags, SEXP c_arg__feature_values, SEXP c_arg__feature_spans, SEXP c_arg__max_width);
 SEXP savvy_debuggd__ffi(void);
 SEXP savvy_do_tracing__ffi(SEXP c_arg__expr);
@@ -7,10 +7,113 @@ pub use device_descriptor::DeviceDescriptor;
 
 pub use device_driver::DeviceDriver;
 use vellogd_shared::{
-    ffi::{R_GE_gcontext, R_NilValue, INTEGER},
+    ffi::*,
     protocol::{FillBrush, FillParams, StrokeParams},
 };
 
+/// A pattern specified via setPattern() API.
+pub enum Pattern {
+    Gradient(peniko::Gradient),
+    Tiling {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+        extend: peniko::Extend,
+        // A function to draw the content of the tile
+        fun: SEXP,
+    },
+}
+
+fn to_extend(extend: i32) -> peniko::Extend {
+    match extend {
+        1 => peniko::Extend::Pad,     // R_GE_patternExtendPad
+        2 => peniko::Extend::Repeat,  // R_GE_patternExtendRepeat
+        3 => peniko::Extend::Reflect, // R_GE_patternExtendReflect
+        _ => peniko::Extend::Pad,     // TODO: what should I do when R_GE_patternExtendNone?
+    }
+}
+
+/// Convert the pattern object to [Pattern]. Returns `None` if the pattern type
+/// is not supported.
+///
+/// # Safety
+///
+/// `pattern` must be a pattern object passed to setPattern() API.
+pub unsafe fn sexp_to_pattern(pattern: SEXP) -> Option<Pattern> {
+    match R_GE_patternType(pattern) {
+        1 => {
+            let x1 = R_GE_linearGradientX1(pattern);
+            let y1 = R_GE_linearGradientY1(pattern);
+            let x2 = R_GE_linearGradientX2(pattern);
+            let y2 = R_GE_linearGradientY2(pattern);
+            let extend = to_extend(R_GE_linearGradientExtend(pattern));
+
+            let num_stops = R_GE_linearGradientNumStops(pattern);
+
+            let color_stops_iter = (0..num_stops).map(|i| {
+                let offset = R_GE_linearGradientStop(pattern, i) as f32;
+                let [r, g, b, a] = R_GE_linearGradientColour(pattern, i).to_ne_bytes();
+                let color = peniko::Color::rgba8(r, g, b, a);
+                peniko::ColorStop { offset, color }
+            });
+            let color_stops = peniko::ColorStops::from_iter(color_stops_iter);
+
+            let mut gradient = peniko::Gradient::new_linear((x1, y1), (x2, y2)).with_extend(extend);
+            // Note: with_stops doesn't accept &[ColorStop] or ColorStops. Why?
+            gradient.stops = color_stops;
+
+            Some(Pattern::Gradient(gradient))
+        }
+        2 => {
+            let cx1 = R_GE_radialGradientCX1(pattern);
+            let cy1 = R_GE_radialGradientCY1(pattern);
+            let r1 = R_GE_radialGradientR1(pattern) as f32;
+            let cx2 = R_GE_radialGradientCX2(pattern);
+            let cy2 = R_GE_radialGradientCY2(pattern);
+            let r2 = R_GE_radialGradientR2(pattern) as f32;
+            let extend = to_extend(R_GE_radialGradientExtend(pattern));
+
+            let num_stops = R_GE_radialGradientNumStops(pattern);
+
+            let color_stops_iter = (0..num_stops).map(|i| {
+                let offset = R_GE_radialGradientStop(pattern, i) as f32;
+                let [r, g, b, a] = R_GE_radialGradientColour(pattern, i).to_ne_bytes();
+                let color = peniko::Color::rgba8(r, g, b, a);
+                peniko::ColorStop { offset, color }
+            });
+            let color_stops = peniko::ColorStops::from_iter(color_stops_iter);
+            let mut gradient =
+                peniko::Gradient::new_two_point_radial((cx1, cy1), r1, (cx2, cy2), r2)
+                    .with_extend(extend);
+            // Note: with_stops doesn't accept &[ColorStop] or ColorStops. Why?
+            gradient.stops = color_stops;
+
+            Some(Pattern::Gradient(gradient))
+        }
+        3 => Some(Pattern::Tiling {
+            x: R_GE_tilingPatternX(pattern),
+            y: R_GE_tilingPatternY(pattern),
+            width: R_GE_tilingPatternWidth(pattern),
+            height: R_GE_tilingPatternHeight(pattern),
+            extend: to_extend(R_GE_tilingPatternExtend(pattern)),
+            fun: R_GE_tilingPatternFunction(pattern),
+        }),
+        _ => None,
+    }
+}
+
+/// Run the function to draw the content of a tiling pattern.
+///
+/// # Safety
+///
+/// `fun` must be the function of a tiling pattern.
+pub unsafe fn draw_tile(fun: SEXP) {
+    let call = Rf_protect(Rf_lang1(fun));
+    Rf_eval(call, R_GlobalEnv);
+    Rf_unprotect(1);
+}
+
 pub fn gc_to_fill_params(gc: R_GE_gcontext) -> Option<FillParams> {
     gc_to_fill_params_with_flag(gc, true)
 }
@@ -1,12 +1,17 @@
 mod graphics;
 mod vello_device;
 
-use savvy::savvy;
+use savvy::{
+    savvy, IntegerSexp, NotAvailableValue, OwnedIntegerSexp, OwnedListSexp, OwnedRealSexp,
+    OwnedStringSexp, RealSexp, StringSexp,
+};
+use vellogd_shared::fonts::merge_font_features;
+use vellogd_shared::protocol::{FontFeature, RichText, TextAlign, TextHalo, TextSpan};
 
 use graphics::DeviceDescriptor;
 use graphics::DeviceDriver;
 use vello_device::VelloGraphicsDevice;
-use vello_device::VelloGraphicsDeviceWithServer;
+use vello_device::{ServerCommand, VelloGraphicsDeviceWithServer};
 
 #[cfg(debug_assertions)]
 mod debug_device;
@@ -27,9 +32,56 @@ macro_rules! add_tracing_point {
     };
 }
 
+fn to_max_fps(max_fps: i32) -> savvy::Result<u32> {
+    match u32::try_from(max_fps) {
+        Ok(max_fps) if max_fps > 0 => Ok(max_fps),
+        _ => Err(savvy::savvy_err!(
+            "max_fps must be a positive integer, but got {max_fps}"
+        )),
+    }
+}
+
+fn to_queue_capacity(queue_capacity: i32) -> savvy::Result<usize> {
+    match usize::try_from(queue_capacity) {
+        Ok(queue_capacity) if queue_capacity > 0 => Ok(queue_capacity),
+        _ => Err(savvy::savvy_err!(
+            "queue_capacity must be a positive integer, but got {queue_capacity}"
+        )),
+    }
+}
+
+// The OpenType features are passed as the pairs of the tags and the values
+fn to_font_features(tags: StringSexp, values: IntegerSexp) -> savvy::Result<Vec<FontFeature>> {
+    tags.iter()
+        .zip(values.iter())
+        .map(|(tag, value)| {
+            let feature = u16::try_from(*value)
+                .ok()
+                .and_then(|value| FontFeature::new(tag, value));
+            feature.ok_or_else(|| savvy::savvy_err!("invalid font feature: {tag} = {value}"))
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
 #[savvy]
-fn vellogd_impl(filename: &str, width: f64, height: f64) -> savvy::Result<()> {
-    let device_driver = VelloGraphicsDevice::new(filename, width, height)?;
+fn vellogd_impl(
+    filename: &str,
+    width: f64,
+    height: f64,
+    max_fps: i32,
+    queue_capacity: i32,
+    feature_tags: StringSexp,
+    feature_values: IntegerSexp,
+) -> savvy::Result<()> {
+    let device_driver = VelloGraphicsDevice::new(
+        filename,
+        width,
+        height,
+        to_max_fps(max_fps)?,
+        to_queue_capacity(queue_capacity)?,
+        to_font_features(feature_tags, feature_values)?,
+    )?;
 
     // TODO: the actual width and height is kept on the server's side.
     let device_descriptor = DeviceDescriptor::new(width, height);
@@ -42,32 +94,28 @@ fn vellogd_impl(filename: &str, width: f64, height: f64) -> savvy::Result<()> {
 // Currently, this is just for debugging purposes. But, in future, this can be
 // used for headless usages.
 #[savvy]
+#[cfg_attr(not(feature = "use_winit"), allow(unused_variables))]
 fn save_as_png(filename: &str) -> savvy::Result<()> {
     #[cfg(feature = "use_winit")]
     {
+        use vello_device::WindowController;
         use vellogd_shared::winit_app::VELLO_APP_PROXY;
 
-        VELLO_APP_PROXY
-            .tx
-            .send_event(vellogd_shared::protocol::Request::SaveAsPng {
-                filename: filename.into(),
-            })?;
+        VELLO_APP_PROXY.request_save_as_png(filename)?;
     }
 
     Ok(())
 }
 
 #[savvy]
+#[cfg_attr(not(feature = "use_winit"), allow(unused_variables))]
 fn add_lottie_animation(filename: &str) -> savvy::Result<()> {
     #[cfg(feature = "use_winit")]
     {
+        use vello_device::WindowController;
         use vellogd_shared::winit_app::VELLO_APP_PROXY;
 
-        VELLO_APP_PROXY
-            .tx
-            .send_event(vellogd_shared::protocol::Request::AddLottieAnimation {
-                filename: filename.into(),
-            })?;
+        VELLO_APP_PROXY.request_add_lottie_animation(filename)?;
     }
 
     Ok(())
@@ -100,14 +148,59 @@ fn add_lottie_animation(filename: &str) -> savvy::Result<()> {
 //     Ok(())
 // }
 
+// Note: the arguments correspond to the ones of the R function
+#[allow(clippy::too_many_arguments)]
 #[savvy]
 fn vellogd_with_server_impl(
     filename: &str,
     width: f64,
     height: f64,
+    listen: bool,
+    max_fps: i32,
+    queue_capacity: i32,
+    feature_tags: StringSexp,
+    feature_values: IntegerSexp,
     server: Option<&str>,
+    address: Option<&str>,
+    record: Option<&str>,
+    name: Option<&str>,
+) -> savvy::Result<()> {
+    let max_fps = to_max_fps(max_fps)?;
+    let queue_capacity = to_queue_capacity(queue_capacity)?;
+    let server = server.map(|bin| ServerCommand {
+        bin: bin.to_string(),
+        width,
+        height,
+        max_fps,
+        queue_capacity,
+        name: name.map(|x| x.to_string()),
+    });
+    let features = to_font_features(feature_tags, feature_values)?;
+    let device_driver = VelloGraphicsDeviceWithServer::new(
+        filename, server, address, listen, record, width, height, features,
+    )?;
+
+    // TODO: the actual width and height is kept on the server's side.
+    let device_descriptor = DeviceDescriptor::new(width, height);
+
+    device_driver.create_device::<VelloGraphicsDeviceWithServer>(device_descriptor, "vellogd")?;
+
+    Ok(())
+}
+
+#[savvy]
+fn vellogd_attach_impl(
+    filename: &str,
+    width: f64,
+    height: f64,
+    feature_tags: StringSexp,
+    feature_values: IntegerSexp,
+    name: Option<&str>,
+    record: Option<&str>,
 ) -> savvy::Result<()> {
-    let device_driver = VelloGraphicsDeviceWithServer::new(filename, server, width, height)?;
+    let features = to_font_features(feature_tags, feature_values)?;
+    let device_driver =
+        VelloGraphicsDeviceWithServer::attach(filename, name, record, width, height, features)?;
 
     // TODO: the actual width and height is kept on the server's side.
     let device_descriptor = DeviceDescriptor::new(width, height);
@@ -117,6 +210,186 @@ fn vellogd_with_server_impl(
     Ok(())
 }
 
+// Returns the list of name, pid, and address, which is converted to a
+// data.frame on R's side.
+#[savvy]
+fn list_servers_impl() -> savvy::Result<savvy::Sexp> {
+    let servers = vellogd_shared::discovery::list_servers();
+
+    let mut name = OwnedStringSexp::new(servers.len())?;
+    let mut pid = OwnedIntegerSexp::new(servers.len())?;
+    let mut address = OwnedStringSexp::new(servers.len())?;
+    for (i, server) in servers.iter().enumerate() {
+        match &server.name {
+            Some(x) => name.set_elt(i, x)?,
+            None => name.set_na(i)?,
+        }
+        pid.set_elt(i, server.pid as i32)?;
+        address.set_elt(i, &server.address)?;
+    }
+
+    let mut out = OwnedListSexp::new(3, true)?;
+    out.set_name_and_value(0, "name", name)?;
+    out.set_name_and_value(1, "pid", pid)?;
+    out.set_name_and_value(2, "address", address)?;
+    out.into()
+}
+
+// Returns the statistics of the queue between the R session and the window of
+// the current device.
+#[savvy]
+fn queue_stats_impl() -> savvy::Result<savvy::Sexp> {
+    use vello_device::with_current_device;
+
+    let stats = with_current_device(|device| device.get_queue_stats())?;
+
+    let mut out = OwnedListSexp::new(5, true)?;
+    let fields = [
+        ("depth", stats.depth),
+        ("capacity", stats.capacity),
+        ("peak", stats.peak),
+        ("blocked", stats.blocked),
+        ("coalesced", stats.coalesced),
+    ];
+    for (i, (name, value)) in fields.into_iter().enumerate() {
+        // Note: R's integer is too small for these counters
+        out.set_name_and_value(i, name, OwnedRealSexp::try_from_scalar(value as f64)?)?;
+    }
+    out.into()
+}
+
+// Returns the family name in the font file.
+#[savvy]
+fn register_font_impl(family: &str, path: &str, face: i32) -> savvy::Result<savvy::Sexp> {
+    let data = std::fs::read(path)
+        .map_err(|e| savvy::savvy_err!("failed to read the font file {path}: {e}"))?;
+    let name = vellogd_shared::fonts::register_font(family, face, data)
+        .map_err(|e| savvy::Error::new(e.to_string()))?;
+    name.as_str().try_into()
+}
+
+#[savvy]
+fn set_font_fallbacks_impl(families: StringSexp, script: Option<&str>) -> savvy::Result<()> {
+    let families = families.iter().map(|x| x.to_string()).collect();
+    vellogd_shared::fonts::set_font_fallbacks(script, families)
+        .map_err(|e| savvy::Error::new(e.to_string()))
+}
+
+#[savvy]
+fn set_symbol_font_impl(family: &str) -> savvy::Result<()> {
+    vellogd_shared::fonts::set_symbol_font(family).map_err(|e| savvy::Error::new(e.to_string()))
+}
+
+// The colours are packed into doubles on R's side because R's integer is too
+// small for 32-bit RGBA. NA means the default.
+fn to_color(x: f64) -> Option<peniko::Color> {
+    if x.is_na() {
+        return None;
+    }
+    let [r, g, b, a] = (x as u32).to_le_bytes();
+    Some(peniko::Color::rgba8(r, g, b, a))
+}
+
+fn to_text_align(align: &str) -> savvy::Result<TextAlign> {
+    match align {
+        "left" => Ok(TextAlign::Left),
+        "center" => Ok(TextAlign::Center),
+        "right" => Ok(TextAlign::Right),
+        "justify" => Ok(TextAlign::Justify),
+        _ => Err(savvy::savvy_err!("unknown alignment: {align}")),
+    }
+}
+
+// Note: text, family, face, size, and color are the vectors of the spans, and
+// the rest are the defaults and the parameters of the whole text. halo_color
+// is NA if the text has no halo. The font features are flattened;
+// feature_spans is the index of the span (1-based) the feature belongs to, or
+// 0 for the whole text.
+#[allow(clippy::too_many_arguments)]
+#[savvy]
+fn rich_text_impl(
+    x: f64,
+    y: f64,
+    text: StringSexp,
+    family: StringSexp,
+    face: IntegerSexp,
+    size: RealSexp,
+    color: RealSexp,
+    default_family: &str,
+    default_face: i32,
+    default_size: f64,
+    default_color: f64,
+    lineheight: f64,
+    align: &str,
+    hadj: f64,
+    vadj: f64,
+    angle: f64,
+    halo_color: f64,
+    halo_width: f64,
+    feature_tags: StringSexp,
+    feature_values: IntegerSexp,
+    feature_spans: IntegerSexp,
+    max_width: Option<f64>,
+) -> savvy::Result<()> {
+    use vello_device::with_current_device;
+
+    let features = to_font_features(feature_tags, feature_values)?;
+    let features_of = |i: usize| {
+        features
+            .iter()
+            .zip(feature_spans.iter())
+            .filter(|(_, span)| **span as usize == i)
+            .map(|(feature, _)| *feature)
+            .collect::<Vec<_>>()
+    };
+
+    let spans = text
+        .iter()
+        .zip(family.iter())
+        .zip(face.iter())
+        .zip(size.iter())
+        .zip(color.iter())
+        .enumerate()
+        .map(|(i, ((((text, family), face), size), color))| TextSpan {
+            text: text.to_string(),
+            family: (!family.is_na()).then(|| family.to_string()),
+            face: (!face.is_na()).then_some(*face),
+            size: (!size.is_na()).then_some(*size as f32),
+            color: to_color(*color),
+            features: features_of(i + 1),
+        })
+        .collect();
+
+    let mut text = RichText {
+        spans,
+        family: default_family.to_string(),
+        face: default_face,
+        size: default_size as f32,
+        color: to_color(default_color).unwrap_or(peniko::Color::BLACK),
+        lineheight: lineheight as f32,
+        features: features_of(0),
+        max_width: max_width.map(|x| x as f32),
+        align: to_text_align(align)?,
+        halo: to_color(halo_color).map(|color| TextHalo {
+            color,
+            width: halo_width as f32,
+        }),
+    };
+
+    with_current_device(|device| {
+        // The features specified on the call take precedence over the
+        // device's defaults
+        text.features = merge_font_features(device.default_font_features(), &text.features);
+        device.request_draw_rich_text(
+            kurbo::Point::new(x, y),
+            text,
+            angle as f32,
+            hadj as f32,
+            vadj as f32,
+        )
+    })
+}
+
 #[savvy]
 fn debuggd() -> savvy::Result<()> {
     #[cfg(debug_assertions)]
@@ -135,6 +408,7 @@ fn debuggd() -> savvy::Result<()> {
 }
 
 #[savvy]
+#[cfg_attr(not(feature = "fastrace"), allow(unused_variables))]
 fn do_tracing(expr: &str) -> savvy::Result<()> {
     #[cfg(feature = "fastrace")]
     {
@@ -143,6 +417,9 @@ fn do_tracing(expr: &str) -> savvy::Result<()> {
 
         fastrace::set_reporter(RConsoleReporter, Config::default());
 
+        // Count only the cache lookups during the evaluation
+        vellogd_shared::cache::take_cache_stats();
+
         {
             let root = fastrace::Span::root("root", fastrace::prelude::SpanContext::random());
             let _guard = root.set_local_parent();
@@ -151,6 +428,17 @@ fn do_tracing(expr: &str) -> savvy::Result<()> {
         }
 
         fastrace::flush();
+
+        // Note: the caches on the server process are not included
+        for stats in vellogd_shared::cache::take_cache_stats() {
+            savvy::r_eprintln!(
+                "[{}] {} hits, {} misses ({:.1}% hit rate)",
+                stats.name,
+                stats.hits,
+                stats.misses,
+                stats.hit_rate() * 100.0
+            );
+        }
     }
     Ok(())
 }
@@ -2,52 +2,114 @@ use std::os::raw::c_uint;
 use std::sync::atomic::Ordering;
 
 use super::xy_to_path;
-use super::WindowController;
+use super::{register_device, unregister_device, WindowController};
 use crate::add_tracing_point;
+use crate::graphics::draw_tile;
 use crate::graphics::gc_to_fill_params;
 use crate::graphics::gc_to_fill_params_with_flag;
 use crate::graphics::gc_to_stroke_params;
+use crate::graphics::sexp_to_pattern;
 use crate::graphics::DeviceDriver;
+use crate::graphics::Pattern;
 use crate::vello_device::xy_to_path_with_hole;
 use vellogd_shared::ffi::*;
+use vellogd_shared::fonts::load_font_file;
+use vellogd_shared::protocol::convert_to_image;
+use vellogd_shared::protocol::FontFeature;
 use vellogd_shared::protocol::GlyphParams;
 use vellogd_shared::protocol::Request;
 use vellogd_shared::protocol::Response;
-use vellogd_shared::text_layouter::fontface_to_weight_and_style;
+use vellogd_shared::protocol::RichText;
+use vellogd_shared::text_layouter::build_rich_layout;
+use vellogd_shared::text_layouter::line_spacing;
 use vellogd_shared::text_layouter::TextLayouter;
 use vellogd_shared::text_layouter::TextMetric;
-use vellogd_shared::winit_app::convert_to_image;
 use vellogd_shared::winit_app::FillPattern;
+use vellogd_shared::winit_app::VelloAppProxy;
 use vellogd_shared::winit_app::VELLO_APP_PROXY;
 
 pub struct VelloGraphicsDevice {
+    #[allow(dead_code)] // TODO: not used yet
     filename: String,
     layout: parley::Layout<peniko::Brush>,
+    // The OpenType features applied to all the text by default
+    features: Vec<FontFeature>,
 }
 
 impl VelloGraphicsDevice {
-    pub(crate) fn new(filename: &str, width: f64, height: f64) -> savvy::Result<Self> {
+    pub(crate) fn new(
+        filename: &str,
+        width: f64,
+        height: f64,
+        max_fps: u32,
+        queue_capacity: usize,
+        features: Vec<FontFeature>,
+    ) -> savvy::Result<Self> {
         VELLO_APP_PROXY.set_size(width as u32, height as u32);
+        VELLO_APP_PROXY.set_max_fps(max_fps);
+        VELLO_APP_PROXY.set_queue_capacity(queue_capacity);
         Ok(Self {
             filename: filename.into(),
             layout: parley::Layout::new(),
+            features,
         })
     }
 }
 
-impl WindowController for VelloGraphicsDevice {
+impl WindowController for VelloAppProxy {
     fn send_event(&self, event: Request) -> savvy::Result<()> {
-        VELLO_APP_PROXY.tx.send_event(event)?;
+        self.tx.send_event(event)?;
         Ok(())
     }
 
     fn recv_response(&self) -> savvy::Result<Response> {
-        let receiver = VELLO_APP_PROXY.rx.lock()?;
+        let receiver = self
+            .rx
+            .lock()
+            .map_err(|e| savvy::Error::new(format!("failed to lock the receiver: {e}")))?;
         let res = receiver.recv()?;
         Ok(res)
     }
 }
 
+impl WindowController for VelloGraphicsDevice {
+    fn send_event(&self, event: Request) -> savvy::Result<()> {
+        VELLO_APP_PROXY.send_event(event)
+    }
+
+    fn recv_response(&self) -> savvy::Result<Response> {
+        VELLO_APP_PROXY.recv_response()
+    }
+
+    fn default_font_features(&self) -> &[FontFeature] {
+        &self.features
+    }
+
+    // This device draws on the scene directly, so the text is also drawn here.
+    // Sending it to the event loop would put it above the later drawings.
+    fn request_draw_rich_text(
+        &self,
+        pos: kurbo::Point,
+        text: RichText,
+        angle: f32,
+        hadj: f32,
+        vadj: f32,
+    ) -> savvy::Result<()> {
+        let mut layout = parley::Layout::new();
+        build_rich_layout(&mut layout, &text);
+
+        VELLO_APP_PROXY.scene.lock().draw_rich_layout(
+            &layout,
+            &text,
+            pos,
+            angle as f64,
+            hadj as f64,
+            vadj as f64,
+        );
+        Ok(())
+    }
+}
+
 impl TextLayouter for VelloGraphicsDevice {
     fn layout_mut(&mut self) -> &mut parley::Layout<peniko::Brush> {
         &mut self.layout
@@ -56,21 +118,34 @@ impl TextLayouter for VelloGraphicsDevice {
     fn layout_ref(&self) -> &parley::Layout<peniko::Brush> {
         &self.layout
     }
+
+    fn font_features(&self) -> &[FontFeature] {
+        &self.features
+    }
 }
 
 impl DeviceDriver for VelloGraphicsDevice {
-    fn activate(&mut self, _: DevDesc) {
+    fn activate(&mut self, dd: DevDesc) {
         add_tracing_point!();
 
-        match self.request_new_window() {
+        register_device::<Self>(&dd);
+
+        // There's only one device in the same process
+        let size = (
+            VELLO_APP_PROXY.width.load(Ordering::Relaxed),
+            VELLO_APP_PROXY.height.load(Ordering::Relaxed),
+        );
+        match self.request_new_window(0, size) {
             Ok(_) => {}
             Err(e) => savvy::r_eprintln!("Failed to activate: {e}"),
         }
     }
 
-    fn close(&mut self, _: DevDesc) {
+    fn close(&mut self, dd: DevDesc) {
         add_tracing_point!();
 
+        unregister_device(&dd);
+
         match self.request_close_window() {
             Ok(_) => {}
             Err(e) => savvy::r_eprintln!("Failed to close window: {e}"),
@@ -85,9 +160,7 @@ impl DeviceDriver for VelloGraphicsDevice {
     //     device_Mode is called whenever the graphics engine
     //     starts drawing (mode=1) or stops drawing (mode=0)
     fn mode(&mut self, mode: i32, _: DevDesc) {
-        VELLO_APP_PROXY
-            .stop_rendering
-            .store(mode == 1, Ordering::Relaxed);
+        VELLO_APP_PROXY.scene.set_drawing(mode == 1);
     }
 
     fn new_page(&mut self, gc: R_GE_gcontext, _: DevDesc) {
@@ -101,14 +174,7 @@ impl DeviceDriver for VelloGraphicsDevice {
     }
 
     fn clip(&mut self, from: (f64, f64), to: (f64, f64), _: DevDesc) {
-        let window_width = VELLO_APP_PROXY.width.load(Ordering::Relaxed) as f64;
-        let window_height = VELLO_APP_PROXY.height.load(Ordering::Relaxed) as f64;
-
-        if from.0 <= 0.0 && from.1 <= 0.0 && to.0 >= window_width && to.1 >= window_height {
-            VELLO_APP_PROXY.scene.pop_clip();
-        } else {
-            VELLO_APP_PROXY.scene.push_clip(from.into(), to.into());
-        }
+        VELLO_APP_PROXY.scene.lock().clip(from.into(), to.into());
     }
 
     fn circle(&mut self, center: (f64, f64), r: f64, gc: R_GE_gcontext, _: DevDesc) {
@@ -119,6 +185,7 @@ impl DeviceDriver for VelloGraphicsDevice {
         if fill_params.is_some() || stroke_params.is_some() {
             VELLO_APP_PROXY
                 .scene
+                .lock()
                 .draw_circle(center.into(), r, fill_params, stroke_params);
         }
     }
@@ -129,6 +196,7 @@ impl DeviceDriver for VelloGraphicsDevice {
         if let Some(stroke_params) = gc_to_stroke_params(gc) {
             VELLO_APP_PROXY
                 .scene
+                .lock()
                 .draw_line(from.into(), to.into(), stroke_params);
         }
     }
@@ -139,9 +207,11 @@ impl DeviceDriver for VelloGraphicsDevice {
         let fill_params = gc_to_fill_params(gc);
         let stroke_params = gc_to_stroke_params(gc);
         if fill_params.is_some() || stroke_params.is_some() {
-            VELLO_APP_PROXY
-                .scene
-                .draw_polygon(xy_to_path(x, y, true), fill_params, stroke_params);
+            VELLO_APP_PROXY.scene.lock().draw_polygon(
+                xy_to_path(x, y, true),
+                fill_params,
+                stroke_params,
+            );
         }
     }
 
@@ -159,7 +229,7 @@ impl DeviceDriver for VelloGraphicsDevice {
         let fill_params = gc_to_fill_params_with_flag(gc, winding);
         let stroke_params = gc_to_stroke_params(gc);
         if fill_params.is_some() || stroke_params.is_some() {
-            VELLO_APP_PROXY.scene.draw_polygon(
+            VELLO_APP_PROXY.scene.lock().draw_polygon(
                 xy_to_path_with_hole(x, y, nper),
                 fill_params,
                 stroke_params,
@@ -174,6 +244,7 @@ impl DeviceDriver for VelloGraphicsDevice {
         if let Some(stroke_params) = stroke_params {
             VELLO_APP_PROXY
                 .scene
+                .lock()
                 .draw_polyline(xy_to_path(x, y, false), stroke_params);
         }
     }
@@ -184,9 +255,12 @@ impl DeviceDriver for VelloGraphicsDevice {
         let fill_params = gc_to_fill_params(gc);
         let stroke_params = gc_to_stroke_params(gc);
         if fill_params.is_some() || stroke_params.is_some() {
-            VELLO_APP_PROXY
-                .scene
-                .draw_rect(from.into(), to.into(), fill_params, stroke_params);
+            VELLO_APP_PROXY.scene.lock().draw_rect(
+                from.into(),
+                to.into(),
+                fill_params,
+                stroke_params,
+            );
         }
     }
 
@@ -211,32 +285,17 @@ impl DeviceDriver for VelloGraphicsDevice {
         .to_string();
         let size = (gc.cex * gc.ps) as f32;
         let lineheight = gc.lineheight as f32;
-        let (weight, style) = fontface_to_weight_and_style(gc.fontface);
-        self.build_layout(text, &family, weight, style, size, lineheight);
-
-        let layout_width = self.layout.width() as f64;
-        let window_height = VELLO_APP_PROXY.height.load(Ordering::Relaxed) as f64;
-
-        for line in self.layout.lines() {
-            let line_metrics = line.metrics();
-            let transform = vello::kurbo::Affine::translate((
-                -(layout_width * hadj),
-                (line_metrics.baseline - line_metrics.line_height) as f64, // TODO: is this correct?
-            ))
-            .then_rotate(-angle.to_radians())
-            .then_translate((pos.0, window_height - pos.1).into()); // Y-axis is flipped
-
-            for item in line.items() {
-                // ignore inline box
-                let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item else {
-                    continue;
-                };
-
-                VELLO_APP_PROXY
-                    .scene
-                    .draw_glyph(glyph_run, color, transform);
-            }
-        }
+        let features = self.features.clone();
+        self.build_layout(text, &family, gc.fontface, size, lineheight, &features);
+
+        VELLO_APP_PROXY.scene.lock().draw_layout(
+            &self.layout,
+            color,
+            pos.into(),
+            angle,
+            hadj,
+            line_spacing(size, lineheight),
+        );
     }
 
     fn glyph(
@@ -255,12 +314,19 @@ impl DeviceDriver for VelloGraphicsDevice {
     ) {
         add_tracing_point!();
 
+        let font_id = match load_font_file(fontfile, index as u32) {
+            Ok(font_id) => font_id,
+            Err(e) => {
+                savvy::r_eprintln!("Failed to read {fontfile}: {e}");
+                return;
+            }
+        };
+
         let [r, g, b, a] = colour.to_ne_bytes();
         let color = peniko::Color::rgba8(r, g, b, a);
         let glyph_params = GlyphParams {
-            fontfile,
-            index: index as u32,
-            family,
+            font_id,
+            family: family.into(),
             weight_raw: weight as f32,
             style_raw: style as u32,
             angle: angle.to_radians(),
@@ -268,9 +334,13 @@ impl DeviceDriver for VelloGraphicsDevice {
             color,
         };
 
-        VELLO_APP_PROXY
+        if let Err(e) = VELLO_APP_PROXY
             .scene
-            .draw_glyph_raw(glyph_ids, x, y, glyph_params);
+            .lock()
+            .draw_glyph_raw(glyph_ids, x, y, &glyph_params)
+        {
+            savvy::r_eprintln!("Failed to draw glyphs: {e}");
+        }
     }
 
     fn raster(
@@ -288,8 +358,6 @@ impl DeviceDriver for VelloGraphicsDevice {
 
         let alpha = gc.col.to_ne_bytes()[3];
 
-        let scale = (size.0 / pixels.0 as f64, size.1 / pixels.1 as f64);
-
         let image = convert_to_image(
             raster,
             pixels.0 as usize,
@@ -298,12 +366,10 @@ impl DeviceDriver for VelloGraphicsDevice {
             alpha,
         );
 
-        let window_height = VELLO_APP_PROXY.height.load(Ordering::Relaxed) as f64;
-        let pos = (pos.0, window_height - (pos.1 + size.1)); // change to top-left corner
-
         VELLO_APP_PROXY
             .scene
-            .draw_raster(&image, scale, pos.into(), angle);
+            .lock()
+            .draw_raster(&image, pos.into(), size, angle);
     }
 
     // TODO
@@ -337,130 +403,37 @@ impl DeviceDriver for VelloGraphicsDevice {
             }
         }
 
-        match unsafe { R_GE_patternType(pattern) } {
-            1 => unsafe {
-                let x1 = R_GE_linearGradientX1(pattern);
-                let y1 = R_GE_linearGradientY1(pattern);
-                let x2 = R_GE_linearGradientX2(pattern);
-                let y2 = R_GE_linearGradientY2(pattern);
-                let extend = match R_GE_linearGradientExtend(pattern) {
-                    1 => peniko::Extend::Pad,     // R_GE_patternExtendPad
-                    2 => peniko::Extend::Repeat,  // R_GE_patternExtendRepeat
-                    3 => peniko::Extend::Reflect, // R_GE_patternExtendReflect
-                    _ => peniko::Extend::Pad, // TODO: what should I do when R_GE_patternExtendNone?
-                };
-
-                let num_stops = R_GE_linearGradientNumStops(pattern);
-
-                let color_stops_iter = (0..num_stops).map(|i| {
-                    let offset = R_GE_linearGradientStop(pattern, i) as f32;
-                    let [r, g, b, a] = R_GE_linearGradientColour(pattern, i).to_ne_bytes();
-                    let color = peniko::Color::rgba8(r, g, b, a);
-                    peniko::ColorStop { offset, color }
-                });
-                let color_stops = peniko::ColorStops::from_iter(color_stops_iter);
-
-                let mut gradient =
-                    peniko::Gradient::new_linear((x1, y1), (x2, y2)).with_extend(extend);
-                // Note: with_stops doesn't accept &[ColorStop] or ColorStops. Why?
-                gradient.stops = color_stops;
-
+        match unsafe { sexp_to_pattern(pattern) } {
+            Some(Pattern::Gradient(gradient)) => {
                 let index = VELLO_APP_PROXY
                     .scene
                     .register_pattern(FillPattern::Gradient(gradient));
-                Rf_ScalarInteger(index as i32)
-            },
-            2 => unsafe {
-                let cx1 = R_GE_radialGradientCX1(pattern);
-                let cy1 = R_GE_radialGradientCY1(pattern);
-                let r1 = R_GE_radialGradientR1(pattern) as f32;
-                let cx2 = R_GE_radialGradientCX2(pattern);
-                let cy2 = R_GE_radialGradientCY2(pattern);
-                let r2 = R_GE_radialGradientR2(pattern) as f32;
-                let extend = match R_GE_radialGradientExtend(pattern) {
-                    1 => peniko::Extend::Pad,     // R_GE_patternExtendPad
-                    2 => peniko::Extend::Repeat,  // R_GE_patternExtendRepeat
-                    3 => peniko::Extend::Reflect, // R_GE_patternExtendReflect
-                    _ => peniko::Extend::Pad, // TODO: what should I do when R_GE_patternExtendNone?
-                };
-
-                let num_stops = R_GE_radialGradientNumStops(pattern);
-
-                let color_stops_iter = (0..num_stops).map(|i| {
-                    let offset = R_GE_radialGradientStop(pattern, i) as f32;
-                    let [r, g, b, a] = R_GE_radialGradientColour(pattern, i).to_ne_bytes();
-                    let color = peniko::Color::rgba8(r, g, b, a);
-                    peniko::ColorStop { offset, color }
-                });
-                let color_stops = peniko::ColorStops::from_iter(color_stops_iter);
-                let mut gradient =
-                    peniko::Gradient::new_two_point_radial((cx1, cy1), r1, (cx2, cy2), r2)
-                        .with_extend(extend);
-                // Note: with_stops doesn't accept &[ColorStop] or ColorStops. Why?
-                gradient.stops = color_stops;
-
-                let index = VELLO_APP_PROXY
-                    .scene
-                    .register_pattern(FillPattern::Gradient(gradient));
-                Rf_ScalarInteger(index as i32)
-            },
-            3 => unsafe {
-                let x = R_GE_tilingPatternX(pattern);
-                let y = R_GE_tilingPatternY(pattern);
-                let width = R_GE_tilingPatternWidth(pattern);
-                let height = R_GE_tilingPatternHeight(pattern);
-                let extend = match R_GE_tilingPatternExtend(pattern) {
-                    1 => peniko::Extend::Pad,     // R_GE_patternExtendPad
-                    2 => peniko::Extend::Repeat,  // R_GE_patternExtendRepeat
-                    3 => peniko::Extend::Reflect, // R_GE_patternExtendReflect
-                    _ => peniko::Extend::Pad, // TODO: what should I do when R_GE_patternExtendNone?
-                };
-
-                // Do not reflect the tile drawing to screen
-                //
-                // TODO: this is not perfect because mode() API call will mess
-                // this flag. So, probably another flag or using accumulated
-                // value instead of a bool is needed.
-                VELLO_APP_PROXY
-                    .stop_rendering
-                    .store(true, Ordering::Relaxed);
-
-                // the pattern tile is drawn on the screen of original sizes,
-                // but it needs to be clipped at the specified area.
-                let mut y_transform = VELLO_APP_PROXY.y_transform.lock().unwrap();
-                let orig_y_transform = *y_transform;
-                // TODO: to match the actual pixels and logical sizes, this needs to be scaled.
-                *y_transform = y_transform.then_translate((-x, -y).into());
-                // release lock, otherwise it deadlocks...
-                drop(y_transform);
-
-                // Use a new scene to preserve the current scene
-                let tmp_scene = vello::Scene::new();
-                let orig_scene = VELLO_APP_PROXY.scene.replace_edited_scene(tmp_scene);
+                unsafe { Rf_ScalarInteger(index as i32) }
+            }
+            Some(Pattern::Tiling {
+                x,
+                y,
+                width,
+                height,
+                extend,
+                fun,
+            }) => {
+                // The drawings are not reflected to the screen until the tile
+                // is saved.
+                VELLO_APP_PROXY.scene.lock().begin_tile(x, y);
 
                 // Run drawing function
-                let fun = R_GE_tilingPatternFunction(pattern);
-                let call = Rf_protect(Rf_lang1(fun));
-                Rf_eval(call, R_GlobalEnv);
-                Rf_unprotect(1);
-
-                self.request_register_tile(width, height, extend).unwrap();
-                let index = VELLO_APP_PROXY.rx.lock().unwrap().recv().unwrap();
-
-                // restore
-                let _ = VELLO_APP_PROXY.scene.replace_edited_scene(orig_scene);
-                *VELLO_APP_PROXY.y_transform.lock().unwrap() = orig_y_transform;
-                VELLO_APP_PROXY
-                    .stop_rendering
-                    .store(false, Ordering::Relaxed);
-
-                if let Response::PatternRegistered { index } = index {
-                    Rf_ScalarInteger(index as i32)
-                } else {
-                    R_NilValue
+                unsafe { draw_tile(fun) };
+
+                match self.request_register_tile(width, height, extend) {
+                    Ok(index) => unsafe { Rf_ScalarInteger(index as i32) },
+                    Err(e) => {
+                        savvy::r_eprintln!("Failed to register the pattern: {e}");
+                        unsafe { R_NilValue }
+                    }
                 }
-            },
-            _ => unsafe { R_NilValue },
+            }
+            None => unsafe { R_NilValue },
         }
     }
 
@@ -9,11 +9,17 @@ mod no_winit;
 #[cfg(not(feature = "winit"))]
 pub use no_winit::VelloGraphicsDevice;
 
+mod scene_log;
 mod with_server;
 
+use std::{cell::RefCell, collections::HashMap, os::raw::c_void};
+
 use savvy::savvy_err;
-use vellogd_shared::protocol::{Request, Response};
-pub use with_server::VelloGraphicsDeviceWithServer;
+use vellogd_shared::{
+    ffi::{DevDesc, GEgetDevice, Rf_curDevice},
+    protocol::{FontFeature, QueueStats, Request, Response, RichText},
+};
+pub use with_server::{ServerCommand, VelloGraphicsDeviceWithServer};
 
 fn xy_to_path(x: &[f64], y: &[f64], close: bool) -> kurbo::BezPath {
     let mut path = kurbo::BezPath::new();
@@ -70,16 +76,37 @@ pub trait WindowController {
     fn send_event(&self, event: Request) -> savvy::Result<()>;
     fn recv_response(&self) -> savvy::Result<Response>;
 
-    fn get_window_sizes(&self) -> savvy::Result<(u32, u32)> {
-        self.send_event(Request::GetWindowSizes)?;
+    /// The OpenType features the device applies to the text by default.
+    fn default_font_features(&self) -> &[FontFeature] {
+        &[]
+    }
+
+    /// Send a request and wait for the response. `Response::Error` is
+    /// converted to an error.
+    fn request(&self, event: Request) -> savvy::Result<Response> {
+        self.send_event(event)?;
         match self.recv_response()? {
+            Response::Error { kind, message } => Err(savvy_err!("{kind}: {message}")),
+            res => Ok(res),
+        }
+    }
+
+    fn get_queue_stats(&self) -> savvy::Result<QueueStats> {
+        match self.request(Request::GetQueueStats)? {
+            Response::QueueStats { stats } => Ok(stats),
+            _ => Err(savvy_err!("Unexpected result")),
+        }
+    }
+
+    fn get_window_sizes(&self) -> savvy::Result<(u32, u32)> {
+        match self.request(Request::GetWindowSizes)? {
             Response::WindowSizes { width, height } => Ok((width, height)),
             _ => Err(savvy_err!("Unexpected result")),
         }
     }
 
-    fn request_new_window(&self) -> savvy::Result<()> {
-        self.send_event(Request::NewWindow)
+    fn request_new_window(&self, device_id: u32, size: (u32, u32)) -> savvy::Result<()> {
+        self.send_event(Request::NewWindow { device_id, size })
     }
 
     fn request_close_window(&self) -> savvy::Result<()> {
@@ -94,22 +121,130 @@ pub trait WindowController {
         self.send_event(Request::SetBaseColor { color })
     }
 
-    fn request_save_as_png<T: ToString>(&self, filename: T) -> savvy::Result<()> {
-        self.send_event(Request::SaveAsPng {
-            filename: filename.to_string(),
+    /// Draw the rich text. See `Request::DrawRichText`.
+    fn request_draw_rich_text(
+        &self,
+        pos: kurbo::Point,
+        text: RichText,
+        angle: f32,
+        hadj: f32,
+        vadj: f32,
+    ) -> savvy::Result<()> {
+        self.send_event(Request::DrawRichText {
+            pos,
+            text,
+            angle,
+            hadj,
+            vadj,
         })
     }
 
+    #[cfg_attr(not(feature = "winit"), allow(dead_code))]
+    fn request_save_as_png<T: ToString>(&self, filename: T) -> savvy::Result<()>
+    where
+        Self: Sized,
+    {
+        match self.request(Request::SaveAsPng {
+            filename: filename.to_string(),
+        })? {
+            Response::Done => Ok(()),
+            _ => Err(savvy_err!("Unexpected result")),
+        }
+    }
+
+    #[cfg_attr(not(feature = "winit"), allow(dead_code))]
+    fn request_add_lottie_animation<T: ToString>(&self, filename: T) -> savvy::Result<()>
+    where
+        Self: Sized,
+    {
+        match self.request(Request::AddLottieAnimation {
+            filename: filename.to_string(),
+        })? {
+            Response::Done => Ok(()),
+            _ => Err(savvy_err!("Unexpected result")),
+        }
+    }
+
+    /// Rasterize the tile drawn so far and register it as a pattern. Returns
+    /// the index of the pattern.
     fn request_register_tile(
         &self,
         width: f64,
         height: f64,
         extend: peniko::Extend,
-    ) -> savvy::Result<()> {
-        self.send_event(Request::SaveAsTile {
+    ) -> savvy::Result<usize> {
+        match self.request(Request::SaveAsTile {
             width,
             height,
             extend,
-        })
+        })? {
+            Response::PatternRegistered { index } => Ok(index),
+            _ => Err(savvy_err!("Unexpected result")),
+        }
+    }
+
+    fn request_register_gradient(&self, gradient: peniko::Gradient) -> savvy::Result<usize> {
+        match self.request(Request::RegisterGradient { gradient })? {
+            Response::PatternRegistered { index } => Ok(index),
+            _ => Err(savvy_err!("Unexpected result")),
+        }
+    }
+}
+
+// The devices of this package, keyed by the pointer to the device-specific
+// data, so that the R functions can find the current device. The value casts
+// the pointer back to the device.
+type DeviceCast = unsafe fn(*mut c_void) -> *const dyn WindowController;
+
+thread_local! {
+    static DEVICES: RefCell<HashMap<usize, DeviceCast>> = RefCell::new(HashMap::new());
+}
+
+unsafe fn cast_device<T: WindowController + 'static>(
+    ptr: *mut c_void,
+) -> *const dyn WindowController {
+    ptr as *const T
+}
+
+/// Register the device on activation. `T` must be the type of the
+/// device-specific data.
+fn register_device<T: WindowController + 'static>(dd: &DevDesc) {
+    DEVICES.with_borrow_mut(|devices| {
+        devices.insert(dd.deviceSpecific as usize, cast_device::<T>);
+    });
+}
+
+/// Unregister the device on closing.
+fn unregister_device(dd: &DevDesc) {
+    DEVICES.with_borrow_mut(|devices| {
+        devices.remove(&(dd.deviceSpecific as usize));
+    });
+}
+
+/// Call the function with the current device. This fails if the current
+/// device is not the one of this package.
+pub(crate) fn with_current_device<R>(
+    f: impl FnOnce(&dyn WindowController) -> savvy::Result<R>,
+) -> savvy::Result<R> {
+    // Note: GEcurrentDevice() is not used here because it opens a new device
+    // if there's none.
+    let ptr = unsafe {
+        let num = Rf_curDevice();
+        let gd = if num > 0 {
+            GEgetDevice(num)
+        } else {
+            std::ptr::null_mut()
+        };
+        if gd.is_null() || (*gd).dev.is_null() {
+            return Err(savvy_err!("No device is open"));
+        }
+        (*(*gd).dev).deviceSpecific
+    };
+
+    let cast = DEVICES.with_borrow(|devices| devices.get(&(ptr as usize)).copied());
+    match cast {
+        // Safety: the pointer is registered only while the device is alive
+        Some(cast) => f(unsafe { &*cast(ptr) }),
+        None => Err(savvy_err!("The current device is not a vellogd device")),
     }
 }
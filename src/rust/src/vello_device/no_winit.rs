@@ -5,7 +5,14 @@ use savvy::savvy_err;
 pub struct VelloGraphicsDevice {}
 
 impl VelloGraphicsDevice {
-    pub(crate) fn new(filename: &str, _width: f64, _height: f64) -> savvy::Result<Self> {
+    pub(crate) fn new(
+        _filename: &str,
+        _width: f64,
+        _height: f64,
+        _max_fps: u32,
+        _queue_capacity: usize,
+        _features: Vec<vellogd_shared::protocol::FontFeature>,
+    ) -> savvy::Result<Self> {
         Err(savvy_err!("This method is not supported on macOS"))
     }
 }
@@ -13,8 +20,8 @@ impl VelloGraphicsDevice {
 impl DeviceDriver for VelloGraphicsDevice {
     fn create_device<T: DeviceDriver>(
         self,
-        device_descriptor: crate::graphics::DeviceDescriptor,
-        device_name: &'static str,
+        _device_descriptor: crate::graphics::DeviceDescriptor,
+        _device_name: &'static str,
     ) -> savvy::Result<()> {
         Err(savvy_err!("This method is not supported on macOS"))
     }
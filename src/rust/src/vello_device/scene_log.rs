@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+
+use vellogd_shared::protocol::Request;
+
+/// The log stops recording when its approximate size exceeds this. The images
+/// are counted by their pixel data, and the other requests by their in-memory
+/// size. Without the limit, a page with many large rasters could keep
+/// gigabytes of memory only for the case the server dies.
+pub(crate) const MAX_SCENE_LOG_BYTES: usize = 256 * 1024 * 1024;
+
+// The requests in a sequence. The images are stored separately so that each
+// image is kept only once however many times it's registered.
+enum Entry {
+    Request(Request),
+    Image(u64),
+}
+
+/// A log of the requests needed to restore the current state of the server.
+/// This is used to replay the requests on a respawned server when the server
+/// process dies.
+///
+/// If the log gets larger than `MAX_SCENE_LOG_BYTES`, the scene is dropped and
+/// only the window is restored. The log is enabled again on the next page
+/// unless a pattern is lost, because the patterns are referred by the index
+/// across pages.
+#[derive(Default)]
+pub(crate) struct SceneLog {
+    // The size of the window, if it's opened
+    window: Option<(u32, u32)>,
+    base_color: Option<u32>,
+    // The requests to register patterns. Since the patterns are referred by
+    // the index, these are kept across pages and replayed in the same order.
+    patterns: Vec<Entry>,
+    // The requests to draw the tile currently being drawn, if any.
+    tile: Option<Vec<Entry>>,
+    // The requests to draw the current page.
+    page: Vec<Entry>,
+    // The RegisterImage requests, by the image id
+    images: HashMap<u64, Request>,
+    // The approximate size of the log
+    bytes: usize,
+    // True if the scene is dropped because of the size
+    overflowed: bool,
+    // True if a pattern is dropped while overflowed
+    patterns_lost: bool,
+}
+
+fn request_size(event: &Request) -> usize {
+    let data_size = match event {
+        Request::RegisterImage { width, height, .. } => *width as usize * *height as usize * 4,
+        _ => 0,
+    };
+    std::mem::size_of::<Request>() + data_size
+}
+
+impl SceneLog {
+    /// Record a request. The requests that expect a response should be
+    /// recorded only after the response is received.
+    ///
+    /// Returns true if the scene is dropped by this request because the log
+    /// exceeds `MAX_SCENE_LOG_BYTES`.
+    pub(crate) fn record(&mut self, event: &Request) -> bool {
+        match event {
+            Request::NewWindow { size, .. } => self.window = Some(*size),
+            Request::CloseWindow => self.window = None,
+            Request::NewPage => self.new_page(),
+            Request::SetBaseColor { color } => self.base_color = Some(*color),
+            // this is not a part of the scene
+            Request::SetDrawingMode { .. } => {}
+            _ if self.overflowed => {
+                if matches!(
+                    event,
+                    Request::SaveAsTile { .. } | Request::RegisterGradient { .. }
+                ) {
+                    self.patterns_lost = true;
+                }
+            }
+            Request::PrepareForSaveAsTile { .. } => {
+                self.tile = Some(vec![self.entry(event)]);
+            }
+            Request::SaveAsTile { .. } => {
+                let tile = self.tile.take().unwrap_or_default();
+                self.patterns.extend(tile);
+                let entry = self.entry(event);
+                self.patterns.push(entry);
+            }
+            Request::RegisterGradient { .. } => {
+                let entry = self.entry(event);
+                self.patterns.push(entry);
+            }
+            _ if event.can_be_batched() => {
+                let entry = self.entry(event);
+                match self.tile.as_mut() {
+                    Some(tile) => tile.push(entry),
+                    None => self.page.push(entry),
+                }
+            }
+            // Note: NewPage clears the animations on the server side as well
+            Request::AddLottieAnimation { .. } => {
+                let entry = self.entry(event);
+                self.page.push(entry);
+            }
+            // Other requests (e.g. GetWindowSizes) don't modify the scene.
+            _ => {}
+        }
+
+        if self.overflowed || self.bytes <= MAX_SCENE_LOG_BYTES {
+            return false;
+        }
+
+        self.patterns_lost = !self.patterns.is_empty();
+        self.overflowed = true;
+        self.patterns.clear();
+        self.tile = None;
+        self.page.clear();
+        self.images.clear();
+        self.bytes = 0;
+        true
+    }
+
+    // Convert the request to an entry and count its size. An image that is
+    // already in the log is not counted again.
+    fn entry(&mut self, event: &Request) -> Entry {
+        if let Request::RegisterImage { id, .. } = event {
+            if !self.images.contains_key(id) {
+                self.bytes += request_size(event);
+                self.images.insert(*id, event.clone());
+            }
+            return Entry::Image(*id);
+        }
+
+        self.bytes += request_size(event);
+        Entry::Request(event.clone())
+    }
+
+    fn new_page(&mut self) {
+        self.page.clear();
+
+        if self.overflowed {
+            self.overflowed = self.patterns_lost;
+        }
+
+        // Keep only the images the patterns refer to, and count the size
+        // again.
+        let mut images = std::mem::take(&mut self.images);
+        let mut bytes = 0;
+        for entry in self.patterns.iter().chain(self.tile.iter().flatten()) {
+            bytes += match entry {
+                Entry::Image(id) => match images.remove(id) {
+                    Some(image) => {
+                        let size = request_size(&image);
+                        self.images.insert(*id, image);
+                        size
+                    }
+                    None => 0,
+                },
+                Entry::Request(request) => request_size(request),
+            };
+        }
+        self.bytes = bytes;
+    }
+
+    /// True if the scene can be restored by replaying the log.
+    pub(crate) fn is_complete(&self) -> bool {
+        !self.overflowed
+    }
+
+    fn resolve<'a>(&'a self, entries: &'a [Entry]) -> impl Iterator<Item = Request> + 'a {
+        entries.iter().filter_map(|entry| match entry {
+            Entry::Request(request) => Some(request.clone()),
+            Entry::Image(id) => self.images.get(id).cloned(),
+        })
+    }
+
+    /// The requests to replay, in the order to send. `device_id` is the one
+    /// assigned by the new server.
+    pub(crate) fn replay_requests(&self, device_id: u32) -> Vec<Request> {
+        let mut requests = Vec::new();
+
+        if let Some(size) = self.window {
+            requests.push(Request::NewWindow { device_id, size });
+        }
+
+        // The patterns must be registered before the page refers to them.
+        requests.extend(self.resolve(&self.patterns));
+
+        if let Some(color) = self.base_color {
+            requests.push(Request::SetBaseColor { color });
+        }
+        requests.extend(self.resolve(&self.page));
+
+        // If a tile is being drawn, the subsequent draw requests go to the tile.
+        if let Some(tile) = &self.tile {
+            requests.extend(self.resolve(tile));
+        }
+
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vellogd_shared::protocol::{ImageBytes, StrokeParams};
+
+    use super::*;
+
+    fn line(x: f64) -> Request {
+        Request::DrawLine {
+            p0: kurbo::Point::new(x, 0.0),
+            p1: kurbo::Point::new(x, 1.0),
+            stroke_params: StrokeParams {
+                color: peniko::Color::BLACK,
+                stroke: kurbo::Stroke::new(1.0),
+            },
+        }
+    }
+
+    fn image(id: u64, width: u32, height: u32) -> Request {
+        // Note: the size is calculated from the width and the height, so the
+        // data doesn't need to be allocated.
+        Request::RegisterImage {
+            id,
+            data: ImageBytes::Inline(Vec::new()),
+            width,
+            height,
+        }
+    }
+
+    fn gradient() -> Request {
+        Request::RegisterGradient {
+            gradient: peniko::Gradient::new_linear((0.0, 0.0), (1.0, 1.0)),
+        }
+    }
+
+    // Summarize the requests to compare, e.g. "DrawLine(2)" or "RegisterImage(1)"
+    fn summary(requests: &[Request]) -> Vec<String> {
+        requests
+            .iter()
+            .map(|request| match request {
+                Request::DrawLine { p0, .. } => format!("DrawLine({})", p0.x),
+                Request::RegisterImage { id, .. } => format!("RegisterImage({id})"),
+                Request::NewWindow { device_id, size } => {
+                    format!("NewWindow({device_id}, {}x{})", size.0, size.1)
+                }
+                Request::SetBaseColor { color } => format!("SetBaseColor({color})"),
+                request => {
+                    let debug = format!("{request:?}");
+                    debug
+                        .split(|c: char| !c.is_alphanumeric())
+                        .next()
+                        .unwrap()
+                        .to_string()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replay_order() {
+        let mut log = SceneLog::default();
+        log.record(&Request::NewWindow {
+            device_id: 1,
+            size: (100, 200),
+        });
+        log.record(&Request::SetBaseColor { color: 255 });
+        log.record(&line(1.0));
+        log.record(&gradient());
+        log.record(&Request::PrepareForSaveAsTile { x: 0.0, y: 0.0 });
+        log.record(&line(2.0));
+        log.record(&Request::SaveAsTile {
+            width: 1.0,
+            height: 1.0,
+            extend: peniko::Extend::Repeat,
+        });
+        log.record(&line(3.0));
+        log.record(&Request::PrepareForSaveAsTile { x: 0.0, y: 0.0 });
+        log.record(&line(4.0));
+        // These are not a part of the scene
+        log.record(&Request::SetDrawingMode { drawing: true });
+        log.record(&Request::GetWindowSizes);
+
+        // The new device id is used, the patterns come first, and the tile
+        // being drawn comes last.
+        assert_eq!(
+            summary(&log.replay_requests(7)),
+            [
+                "NewWindow(7, 100x200)",
+                "RegisterGradient",
+                "PrepareForSaveAsTile",
+                "DrawLine(2)",
+                "SaveAsTile",
+                "SetBaseColor(255)",
+                "DrawLine(1)",
+                "DrawLine(3)",
+                "PrepareForSaveAsTile",
+                "DrawLine(4)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_new_page() {
+        let mut log = SceneLog::default();
+        log.record(&line(1.0));
+        log.record(&image(1, 2, 2));
+        log.record(&gradient());
+        log.record(&Request::NewPage);
+        log.record(&line(2.0));
+
+        assert_eq!(
+            summary(&log.replay_requests(0)),
+            ["RegisterGradient", "DrawLine(2)"]
+        );
+        assert!(log.images.is_empty());
+        assert_eq!(log.bytes, 2 * std::mem::size_of::<Request>());
+        assert!(log.is_complete());
+    }
+
+    #[test]
+    fn test_images_are_kept_once() {
+        let mut log = SceneLog::default();
+
+        // An image used in a pattern
+        log.record(&Request::PrepareForSaveAsTile { x: 0.0, y: 0.0 });
+        log.record(&image(1, 10, 10));
+        log.record(&Request::SaveAsTile {
+            width: 1.0,
+            height: 1.0,
+            extend: peniko::Extend::Pad,
+        });
+
+        // The same image on the page
+        log.record(&image(1, 10, 10));
+        log.record(&image(2, 20, 20));
+        log.record(&image(2, 20, 20));
+
+        assert_eq!(log.images.len(), 2);
+        let requests = log.replay_requests(0);
+        assert_eq!(
+            summary(&requests),
+            [
+                "PrepareForSaveAsTile",
+                "RegisterImage(1)",
+                "SaveAsTile",
+                "RegisterImage(1)",
+                "RegisterImage(2)",
+                "RegisterImage(2)",
+            ]
+        );
+
+        // The image only on the page is dropped on a new page, and the one
+        // the pattern refers to is kept.
+        log.record(&Request::NewPage);
+        assert_eq!(log.images.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(log.bytes, 3 * std::mem::size_of::<Request>() + 10 * 10 * 4);
+        assert_eq!(
+            summary(&log.replay_requests(0)),
+            ["PrepareForSaveAsTile", "RegisterImage(1)", "SaveAsTile"]
+        );
+    }
+
+    #[test]
+    fn test_overflow_without_patterns() {
+        let mut log = SceneLog::default();
+        log.record(&Request::NewWindow {
+            device_id: 1,
+            size: (100, 200),
+        });
+        log.record(&line(1.0));
+
+        // 256 MB + 4 bytes
+        assert!(log.record(&image(1, 1024 * 1024 * 64 + 1, 1)));
+        assert!(!log.is_complete());
+        assert!(log.overflowed);
+        assert!(!log.patterns_lost);
+        assert!(log.images.is_empty());
+
+        // The requests are ignored until the next page
+        assert!(!log.record(&line(2.0)));
+        assert_eq!(summary(&log.replay_requests(0)), ["NewWindow(0, 100x200)"]);
+
+        log.record(&Request::NewPage);
+        assert!(log.is_complete());
+        log.record(&line(3.0));
+        assert_eq!(
+            summary(&log.replay_requests(0)),
+            ["NewWindow(0, 100x200)", "DrawLine(3)"]
+        );
+    }
+
+    #[test]
+    fn test_overflow_with_patterns() {
+        let mut log = SceneLog::default();
+        log.record(&gradient());
+        assert!(log.record(&image(1, 1024 * 1024 * 64 + 1, 1)));
+        assert!(log.patterns_lost);
+
+        // The patterns are referred by the index, so the log cannot be
+        // enabled again once a pattern is lost.
+        log.record(&Request::NewPage);
+        assert!(!log.is_complete());
+        log.record(&line(1.0));
+        assert!(log.replay_requests(0).is_empty());
+    }
+
+    #[test]
+    fn test_pattern_lost_while_overflowed() {
+        let mut log = SceneLog::default();
+        assert!(log.record(&image(1, 1024 * 1024 * 64 + 1, 1)));
+        assert!(!log.patterns_lost);
+
+        log.record(&gradient());
+        assert!(log.patterns_lost);
+        log.record(&Request::NewPage);
+        assert!(!log.is_complete());
+    }
+}
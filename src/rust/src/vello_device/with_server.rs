@@ -1,99 +1,929 @@
-use ipc_channel::ipc::{IpcOneShotServer, IpcReceiver, IpcSender};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    time::Duration,
+};
+
+use ipc_channel::ipc::{IpcOneShotServer, IpcSender};
+use std::os::raw::c_uint;
+
 use vellogd_shared::{
-    ffi::{DevDesc, R_GE_gcontext},
-    protocol::{Request, Response},
+    discovery::{list_servers, ServerEntry},
+    ffi::{DevDesc, R_GE_gcontext, R_NilValue, Rf_ScalarInteger, SEXP},
+    fonts::{font_file_request, font_requests, load_font_file},
+    protocol::{
+        image_id, FillBrush, FillParams, FontFeature, GlyphParams, ImageBytes, Request, Response,
+        PROTOCOL_VERSION,
+    },
+    recording::{Recorder, RecordingFormat},
     text_layouter::{TextLayouter, TextMetric},
+    transport::{Address, BoxedReceiver, BoxedSender, TransportError},
 };
 
+// The tests are not linked with R, so the messages go to the standard error
+// instead of R's console.
+#[cfg(not(test))]
+use savvy::{r_eprint, r_eprintln};
+#[cfg(test)]
+use std::{eprint as r_eprint, eprintln as r_eprintln};
+
 use crate::{
     add_tracing_point,
-    graphics::{gc_to_fill_params, gc_to_fill_params_with_flag, gc_to_stroke_params, DeviceDriver},
+    graphics::{
+        draw_tile, gc_to_fill_params, gc_to_fill_params_with_flag, gc_to_stroke_params,
+        sexp_to_pattern, DeviceDriver, Pattern,
+    },
+};
+
+use super::{
+    register_device,
+    scene_log::{SceneLog, MAX_SCENE_LOG_BYTES},
+    unregister_device, xy_to_path, xy_to_path_with_hole, WindowController,
 };
 
-use super::{xy_to_path, xy_to_path_with_hole, WindowController};
+// The buffered draw requests are flushed at this interval even if no flush is
+// triggered by the R session, so that the result is visible on the window.
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+// The buffer is flushed when it reaches this number of requests to avoid
+// sending too large data at once.
+const MAX_BATCH_SIZE: usize = 10_000;
+
+// The requests are counted above, not the bytes. So, the requests that can be
+// large (i.e. images) are sent on their own instead of in a batch; otherwise,
+// a batch of a few large images could exceed MAX_MESSAGE_SIZE.
+fn should_batch(event: &Request) -> bool {
+    event.can_be_batched() && !matches!(event, Request::RegisterImage { .. })
+}
+
+// Give up waiting for the server to connect after this so that the R session
+// is never stuck (e.g. when the server fails to start).
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Kill the server if it doesn't exit in this time after the shutdown request.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sending requests one by one is costly when there are many primitives to
+// draw (e.g. a scatter plot with 100k points). So, the draw requests are
+// buffered and sent as a batch.
+struct RequestBuffer {
+    tx: BoxedSender<Request>,
+    // The id of the device assigned by the server
+    device_id: u32,
+    requests: Vec<Request>,
+    // The requests sent so far, to replay on a respawned server
+    log: SceneLog,
+    // If Some, all the requests are recorded to the file
+    recorder: Option<Recorder>,
+    // The ids of the images the server has
+    images: HashSet<u64>,
+    // The number of the font requests the server has (c.f. fonts::font_requests())
+    fonts_sent: usize,
+    // The ids of the font files the server has (c.f. fonts::load_font_file())
+    font_files: HashSet<u64>,
+    // The features the server supports (c.f. SERVER_CAPABILITIES)
+    capabilities: HashSet<String>,
+    // The index of the pattern on the server, by the index R knows. This is
+    // None if the pattern is lost when the server is respawned.
+    patterns: Vec<Option<u32>>,
+    // Whether the fill with a lost pattern is already warned
+    lost_pattern_warned: bool,
+}
+
+struct SendError {
+    error: TransportError,
+    // The request that is not recorded to the log and needs to be sent again
+    // after recovery.
+    unsent: Option<Box<Request>>,
+}
+
+impl RequestBuffer {
+    fn new(
+        tx: BoxedSender<Request>,
+        device_id: u32,
+        capabilities: HashSet<String>,
+        recorder: Option<Recorder>,
+    ) -> Self {
+        Self {
+            tx,
+            device_id,
+            capabilities,
+            requests: Vec::new(),
+            log: SceneLog::default(),
+            recorder,
+            images: HashSet::new(),
+            fonts_sent: 0,
+            font_files: HashSet::new(),
+            patterns: Vec::new(),
+            lost_pattern_warned: false,
+        }
+    }
+
+    /// Remember the pattern registered on the server, and returns the index
+    /// for R.
+    fn add_pattern(&mut self, server_index: usize) -> usize {
+        self.patterns.push(Some(server_index as u32));
+        self.patterns.len() - 1
+    }
+
+    // Mark all the patterns so far as lost.
+    fn forget_patterns(&mut self) {
+        for pattern in self.patterns.iter_mut() {
+            *pattern = None;
+        }
+        self.lost_pattern_warned = false;
+    }
+
+    // Point the fill to the pattern on the current server. If the pattern is
+    // lost, the fill is dropped because the server would fill the shape with
+    // another pattern or nothing.
+    //
+    // Note: this must be called on the R session's thread
+    fn resolve_pattern(&mut self, event: &mut Request) {
+        let Some(fill_params) = event.fill_params_mut() else {
+            return;
+        };
+        let Some(FillParams {
+            brush: FillBrush::PatternRef(index),
+            ..
+        }) = fill_params
+        else {
+            return;
+        };
+
+        match self.patterns.get(*index as usize) {
+            Some(Some(server_index)) => *index = *server_index,
+            _ => {
+                if !self.lost_pattern_warned {
+                    r_eprintln!(
+                        "The pattern was lost when the server was restarted, so the fill is skipped."
+                    );
+                    self.lost_pattern_warned = true;
+                }
+                *fill_params = None;
+            }
+        }
+    }
+
+    /// Returns the capability the server lacks to handle the request, if any.
+    fn missing_capability(&self, event: &Request) -> Option<&'static str> {
+        event
+            .required_capabilities()
+            .into_iter()
+            .find(|x| !self.capabilities.contains(*x))
+    }
+
+    /// Returns the request to register the image, or None if the server
+    /// already has the image.
+    fn register_image_request(
+        &self,
+        id: u64,
+        raster: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Option<Request> {
+        if self.images.contains(&id) {
+            return None;
+        }
+        Some(Request::RegisterImage {
+            id,
+            data: ImageBytes::new(raster, self.tx.supports_shared_memory()),
+            width,
+            height,
+        })
+    }
+
+    // Note: this must be called on the R session's thread
+    fn record(&mut self, event: &Request) {
+        let Some(recorder) = self.recorder.as_mut() else {
+            return;
+        };
+
+        let mut res = recorder.record(event);
+        // Flush at the points where the R session waits for the next
+        // operation, not on every draw request.
+        if res.is_ok() && !event.can_be_batched() {
+            res = recorder.flush();
+        }
+
+        // Stop recording instead of failing the drawing
+        if let Err(e) = res {
+            r_eprintln!("Failed to record the request, so the recording is stopped: {e}");
+            self.recorder = None;
+        }
+    }
+
+    // Note: this must be called on the R session's thread
+    fn log_request(&mut self, event: &Request) {
+        if self.log.record(event) {
+            r_eprintln!(
+                "The current page uses more than {} MB, so it won't be restored if the server dies.",
+                MAX_SCENE_LOG_BYTES / 1024 / 1024
+            );
+        }
+    }
+
+    // Send the fonts registered after the last time so that the server can lay
+    // out the text with them. `record` is false when they are sent again to a
+    // respawned server.
+    fn send_fonts(&mut self, record: bool) -> Result<(), TransportError> {
+        for request in font_requests(self.fonts_sent) {
+            if record {
+                self.record(&request);
+            }
+            // The preceding draw requests must arrive before this request.
+            self.flush()?;
+            self.tx.send_message(request)?;
+            self.fonts_sent += 1;
+        }
+        Ok(())
+    }
+
+    // Send the font file used by DrawGlyph unless the server already has it.
+    fn send_font_file(&mut self, id: u64, record: bool) -> Result<(), TransportError> {
+        if self.font_files.contains(&id) {
+            return Ok(());
+        }
+        // Note: the server reports the unknown font when drawing
+        let Some(request) = font_file_request(id) else {
+            return Ok(());
+        };
+        if record {
+            self.record(&request);
+        }
+        // The preceding draw requests must arrive before this request.
+        self.flush()?;
+        self.tx.send_message(request)?;
+        self.font_files.insert(id);
+        Ok(())
+    }
+
+    fn send(&mut self, mut event: Request) -> Result<(), SendError> {
+        self.resolve_pattern(&mut event);
+
+        let res = match &event {
+            Request::DrawText { .. } | Request::DrawRichText { .. } => self.send_fonts(true),
+            Request::DrawGlyph { glyph_params, .. } => {
+                self.send_font_file(glyph_params.font_id, true)
+            }
+            _ => Ok(()),
+        };
+        if let Err(error) = res {
+            return Err(SendError {
+                error,
+                unsent: Some(Box::new(event)),
+            });
+        }
+
+        match &event {
+            // The server already has the image
+            Request::RegisterImage { id, .. } if !self.images.insert(*id) => return Ok(()),
+            // The server clears the images on a new page
+            Request::NewPage => self.images.clear(),
+            _ => {}
+        }
+
+        self.record(&event);
+
+        if should_batch(&event) {
+            self.log_request(&event);
+            self.requests.push(event);
+            if self.requests.len() >= MAX_BATCH_SIZE {
+                self.flush().map_err(|error| SendError {
+                    error,
+                    unsent: None,
+                })?;
+            }
+        } else {
+            // The preceding draw requests must arrive before this request.
+            if let Err(error) = self.flush() {
+                return Err(SendError {
+                    error,
+                    unsent: Some(Box::new(event)),
+                });
+            }
+
+            // Note: the requests that expect a response are recorded after
+            // the response is received. Otherwise, the request would be sent
+            // twice on recovery.
+            let unsent = event.expects_response().then(|| Box::new(event.clone()));
+            if unsent.is_none() {
+                self.log_request(&event);
+            }
+
+            self.tx
+                .send_message(event)
+                .map_err(|error| SendError { error, unsent })?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), TransportError> {
+        if self.requests.is_empty() {
+            return Ok(());
+        }
+        let requests = std::mem::take(&mut self.requests);
+        self.tx.send_message(Request::Batch(requests))?;
+        Ok(())
+    }
+
+    // Send all the requests in the log via the new channels.
+    fn replay(
+        &mut self,
+        tx: BoxedSender<Request>,
+        device_id: u32,
+        capabilities: HashSet<String>,
+        rx: &BoxedReceiver<Response>,
+    ) -> Result<(), TransportError> {
+        self.tx = tx;
+        self.device_id = device_id;
+        self.capabilities = capabilities;
+        // These are already recorded in the log
+        self.requests.clear();
+        self.images.clear();
+        // The new server doesn't have the fonts yet
+        self.fonts_sent = 0;
+        self.font_files.clear();
+        self.send_fonts(false)?;
+
+        for event in self.log.replay_requests(device_id) {
+            match &event {
+                Request::RegisterImage { id, .. } => {
+                    self.images.insert(*id);
+                }
+                Request::DrawGlyph { glyph_params, .. } => {
+                    self.send_font_file(glyph_params.font_id, false)?;
+                }
+                _ => {}
+            }
+
+            if should_batch(&event) {
+                self.requests.push(event);
+                if self.requests.len() >= MAX_BATCH_SIZE {
+                    self.flush()?;
+                }
+            } else {
+                self.flush()?;
+                let expects_response = event.expects_response();
+                self.tx.send_message(event)?;
+                if expects_response {
+                    // The result should be the same as the one received
+                    // before, so it can be ignored.
+                    let _ = rx.recv_message()?;
+                }
+            }
+        }
+
+        self.flush()
+    }
+}
+
+// Flush the buffer periodically. The thread exits when the device is dropped.
+fn spawn_flush_thread(buffer: Weak<Mutex<RequestBuffer>>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(FLUSH_INTERVAL);
+
+        let Some(buffer) = buffer.upgrade() else {
+            break;
+        };
+        let Ok(mut buffer) = buffer.lock() else {
+            break;
+        };
+        // If this fails, the same error should happen on the R session's side
+        // soon, so it can be ignored here.
+        let _ = buffer.flush();
+    });
+}
 
 pub struct VelloGraphicsDeviceWithServer {
+    #[allow(dead_code)] // TODO: not used yet
     filename: String,
     layout: parley::Layout<peniko::Brush>,
-    process: Option<std::process::Child>,
-    tx: IpcSender<Request>,
-    rx: IpcReceiver<Response>,
+    // The OpenType features applied to all the text by default
+    features: Vec<FontFeature>,
+    // The size of the window to open
+    size: (u32, u32),
+    // If this is Some, the server is respawned when the server process dies.
+    server: Option<ServerCommand>,
+    process: Mutex<Option<std::process::Child>>,
+    buffer: Arc<Mutex<RequestBuffer>>,
+    rx: Mutex<BoxedReceiver<Response>>,
 }
 
 impl Drop for VelloGraphicsDeviceWithServer {
     fn drop(&mut self) {
-        if let Some(c) = self.process.as_mut() {
-            c.kill().unwrap();
+        let Ok(mut process) = self.process.lock() else {
+            return;
+        };
+
+        // If other devices might be attached to the server, the server is not
+        // shut down. The server exits by itself when all the devices are
+        // closed, so just wait for it on a background thread to reap the
+        // process.
+        let shared = matches!(&self.server, Some(server) if server.name.is_some());
+        match (process.take(), shared) {
+            (Some(mut c), true) => {
+                std::thread::spawn(move || {
+                    let _ = c.wait();
+                });
+            }
+            (Some(c), false) => match self.buffer.lock() {
+                Ok(mut buffer) => shutdown_server(&mut buffer, c),
+                Err(_) => kill_process(Some(c)),
+            },
+            // The server is not the one spawned by this device
+            (None, _) => {}
+        }
+    }
+}
+
+/// How to spawn the server process.
+pub struct ServerCommand {
+    /// The path to the server binary
+    pub bin: String,
+    pub width: f64,
+    pub height: f64,
+    pub max_fps: u32,
+    pub queue_capacity: usize,
+    /// If Some, other devices can attach to the server by this name
+    pub name: Option<String>,
+}
+
+// The channels to the server, the id of the device assigned by the server, and
+// the capabilities of the server.
+struct Connection {
+    tx: BoxedSender<Request>,
+    rx: BoxedReceiver<Response>,
+    device_id: u32,
+    capabilities: HashSet<String>,
+}
+
+fn kill_process(process: Option<std::process::Child>) {
+    if let Some(mut c) = process {
+        // The process might have already exited
+        let _ = c.kill();
+        let _ = c.wait();
+    }
+}
+
+// Ask the server to exit so that it can finish the pending requests (e.g.
+// SaveAsPng). If it doesn't exit in time, kill it.
+fn shutdown_server(buffer: &mut RequestBuffer, mut process: std::process::Child) {
+    let requested = buffer
+        .flush()
+        .and_then(|_| buffer.tx.send_message(Request::Shutdown))
+        .is_ok();
+
+    // Note: the acknowledgement is not read here because it might block
+    // forever if the server hangs. Instead, wait for the process to exit,
+    // which also reaps the process.
+    if requested {
+        let deadline = std::time::Instant::now() + SHUTDOWN_TIMEOUT;
+        while std::time::Instant::now() < deadline {
+            match process.try_wait() {
+                Ok(Some(_)) => return,
+                Ok(None) => std::thread::sleep(Duration::from_millis(50)),
+                Err(_) => break,
+            }
         }
+        r_eprintln!("The server didn't exit in time, so it's killed.");
     }
+
+    kill_process(Some(process));
 }
 
 impl VelloGraphicsDeviceWithServer {
+    /// If `address` is specified, connect to the server at the address (or,
+    /// wait for the server to connect to the address if `listen` is true)
+    /// instead of spawning the server process.
+    ///
+    /// If `record` is specified, the requests are recorded to the file, which
+    /// can be replayed by `vellogd-server --replay`.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         filename: &str,
-        server: Option<&str>,
+        server: Option<ServerCommand>,
+        address: Option<&str>,
+        listen: bool,
+        record: Option<&str>,
         width: f64,
         height: f64,
+        features: Vec<FontFeature>,
     ) -> savvy::Result<Self> {
-        // server -> controller
-        let (rx_server, rx_server_name) = IpcOneShotServer::<Response>::new().unwrap();
-
-        let server_process = if let Some(server_bin) = server {
-            // spawn a server process
-            let res = std::process::Command::new(server_bin)
-                .args([
-                    rx_server_name,
-                    (width as u32).to_string(),
-                    (height as u32).to_string(),
-                ])
-                .spawn();
-
-            match res {
-                Ok(c) => {
-                    savvy::r_eprintln!("Server runs at PID {}", c.id());
-                    Some(c)
-                }
-                Err(e) => {
-                    let msg = format!("failed to spawn the process: {e}");
-                    return Err(savvy::Error::new(&msg));
-                }
-            }
-        } else {
-            savvy::r_eprintln!("rx_server_name: {rx_server_name}");
-            None
-        };
+        let recorder = record
+            .map(|path| {
+                let format = RecordingFormat::from_path(path);
+                Recorder::create(path, format, width as u32, height as u32).map_err(|e| {
+                    savvy::Error::new(format!("failed to create the recording {path}: {e}"))
+                })
+            })
+            .transpose()?;
+
+        // The server is not spawned if it's a remote one.
+        let server = server.filter(|_| address.is_none());
 
-        // establish connections of both direction
-        let (tx, rx) = match rx_server.accept() {
-            Ok((rx, Response::Connect { server_name })) => {
-                savvy::r_eprint!("Connecting to {server_name}...");
-                let tx: IpcSender<Request> = IpcSender::connect(server_name).unwrap();
-                tx.send(Request::ConnectionReady).unwrap();
-                (tx, rx)
+        let (process, connection) = match (address, &server) {
+            (Some(address), _) => (None, handshake_remote(address, listen)?),
+            (None, Some(server)) => {
+                let (process, connection) = spawn_server(server)?;
+                (Some(process), connection)
             }
-            Ok((_, data)) => panic!("got unexpected data: {data:?}"),
-            Err(e) => panic!("failed to accept connection: {e}"),
+            // Attach to the server running on this machine, if any
+            (None, None) => (None, handshake_remote(&find_server(None)?.address, false)?),
         };
-        savvy::r_eprintln!("connected!");
+        r_eprintln!("connected!");
+
+        let Connection {
+            tx,
+            rx,
+            device_id,
+            capabilities,
+        } = connection;
+        let buffer = Arc::new(Mutex::new(RequestBuffer::new(
+            tx,
+            device_id,
+            capabilities,
+            recorder,
+        )));
+        spawn_flush_thread(Arc::downgrade(&buffer));
 
         Ok(Self {
             filename: filename.into(),
             layout: parley::Layout::new(),
-            process: server_process,
+            features,
+            size: (width as u32, height as u32),
+            server,
+            process: Mutex::new(process),
+            buffer,
+            rx: Mutex::new(rx),
+        })
+    }
+
+    /// Open a new device on the server running on this machine. If `name` is
+    /// None, there must be only one server.
+    pub(crate) fn attach(
+        filename: &str,
+        name: Option<&str>,
+        record: Option<&str>,
+        width: f64,
+        height: f64,
+        features: Vec<FontFeature>,
+    ) -> savvy::Result<Self> {
+        let server = find_server(name)?;
+        Self::new(
+            filename,
+            None,
+            Some(&server.address),
+            false,
+            record,
+            width,
+            height,
+            features,
+        )
+    }
+
+    fn lock_buffer(&self) -> savvy::Result<MutexGuard<'_, RequestBuffer>> {
+        self.buffer
+            .lock()
+            .map_err(|e| savvy::Error::new(format!("failed to lock the buffer: {e}")))
+    }
+
+    fn lock_rx(&self) -> savvy::Result<MutexGuard<'_, BoxedReceiver<Response>>> {
+        self.rx
+            .lock()
+            .map_err(|e| savvy::Error::new(format!("failed to lock the receiver: {e}")))
+    }
+
+    // Respawn the server and restore the scene by replaying the recorded
+    // requests.
+    fn recover(&self, cause: &TransportError) -> savvy::Result<()> {
+        let Some(server) = &self.server else {
+            return Err(savvy::Error::new(format!(
+                "lost the connection to the server: {cause}"
+            )));
+        };
+
+        r_eprintln!("Lost the connection to the server ({cause}). Restarting the server...");
+
+        // Reap the dead process
+        {
+            let mut process = self
+                .process
+                .lock()
+                .map_err(|e| savvy::Error::new(format!("failed to lock the process: {e}")))?;
+            kill_process(process.take());
+        }
+
+        let (process, connection) = spawn_server(server)?;
+        r_eprintln!("connected!");
+
+        // Note: the devices attached to the dead server are not restored.
+        let Connection {
             tx,
             rx,
-        })
+            device_id,
+            capabilities,
+        } = connection;
+        let mut buffer = self.lock_buffer()?;
+        let mut rx_orig = self.lock_rx()?;
+        *rx_orig = rx;
+        if let Ok(mut p) = self.process.lock() {
+            *p = Some(process);
+        }
+
+        if !buffer.log.is_complete() {
+            r_eprintln!("The current page was too large to keep, so it's not restored.");
+            // The log doesn't have the patterns to register them again.
+            buffer.forget_patterns();
+        }
+        buffer.replay(tx, device_id, capabilities, &rx_orig)?;
+
+        Ok(())
+    }
+
+    // Try recovery if the server is gone.
+    fn send_event_with_recovery(&self, event: Request) -> savvy::Result<()> {
+        let res = {
+            let mut buffer = self.lock_buffer()?;
+            // An older server might not be able to handle the request
+            if let Some(capability) = buffer.missing_capability(&event) {
+                return Err(version_mismatch_error(&format!(
+                    "the server doesn't support {capability}"
+                )));
+            }
+            buffer.send(event)
+        };
+        if let Err(SendError { error, unsent }) = res {
+            self.recover(&error)?;
+            if let Some(event) = unsent {
+                self.lock_buffer()?
+                    .send(*event)
+                    .map_err(|e| savvy::Error::from(e.error))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> savvy::Result<()> {
+        let res = self.lock_buffer()?.flush();
+        if let Err(e) = res {
+            self.recover(&e)?;
+        }
+        Ok(())
+    }
+
+    // Since the callbacks of the graphics device cannot return an error, the
+    // failure is just reported.
+    fn send_event_or_warn(&self, event: Request) {
+        if let Err(e) = self.send_event(event) {
+            r_eprintln!("Failed to send a request: {e}");
+        }
     }
 }
 
+// Spawn a server process and connect to it.
+fn spawn_server(server: &ServerCommand) -> savvy::Result<(std::process::Child, Connection)> {
+    // server -> controller
+    let (rx_server, rx_server_name) = IpcOneShotServer::<Response>::new()?;
+
+    let mut command = std::process::Command::new(&server.bin);
+    command.args([
+        rx_server_name.clone(),
+        "--width".to_string(),
+        (server.width as u32).to_string(),
+        "--height".to_string(),
+        (server.height as u32).to_string(),
+        "--max-fps".to_string(),
+        server.max_fps.to_string(),
+        "--queue-capacity".to_string(),
+        server.queue_capacity.to_string(),
+    ]);
+    // Listen on a free port of the loopback interface so that other devices
+    // can find the server and attach to it.
+    if let Some(name) = &server.name {
+        command.args(["--listen", "tcp://127.0.0.1:0", "--name", name]);
+    }
+
+    let process = command
+        .spawn()
+        .map_err(|e| savvy::Error::new(format!("failed to spawn the process: {e}")))?;
+    r_eprintln!("Server runs at PID {}", process.id());
+
+    // establish connections of both direction
+    match handshake_ipc(rx_server, rx_server_name) {
+        Ok(connection) => Ok((process, connection)),
+        Err(e) => {
+            kill_process(Some(process));
+            Err(e)
+        }
+    }
+}
+
+// Find the server running on this machine by the name.
+fn find_server(name: Option<&str>) -> savvy::Result<ServerEntry> {
+    let servers = list_servers();
+    let describe = |servers: &[ServerEntry]| {
+        servers
+            .iter()
+            .map(|x| {
+                format!(
+                    "{} (PID {})",
+                    x.name.as_deref().unwrap_or("<unnamed>"),
+                    x.pid
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let server = match name {
+        Some(name) => servers
+            .iter()
+            .find(|x| x.name.as_deref() == Some(name))
+            .ok_or_else(|| {
+                savvy::Error::new(format!(
+                    "no server named {name} is found. The running servers are: {}",
+                    describe(&servers)
+                ))
+            })?,
+        None => match servers.as_slice() {
+            [server] => server,
+            [] => return Err(savvy::Error::new("no running server is found")),
+            _ => {
+                return Err(savvy::Error::new(format!(
+                    "multiple servers are running, so please specify the name: {}",
+                    describe(&servers)
+                )))
+            }
+        },
+    };
+
+    if server.protocol_version != PROTOCOL_VERSION {
+        return Err(version_mismatch_error(&format!(
+            "the server uses protocol version {} while vellogd uses {PROTOCOL_VERSION}",
+            server.protocol_version
+        )));
+    }
+
+    Ok(server.clone())
+}
+
+// If the server is of a different version, the data cannot be decoded
+// correctly. In that case, it's likely to fail to deserialize the data, or to
+// get some unexpected data.
+//
+// Returns the server name, the device id, and the capabilities.
+fn check_connect_response(res: Response) -> savvy::Result<(String, u32, HashSet<String>)> {
+    match res {
+        Response::Connect {
+            server_name,
+            protocol_version,
+            capabilities,
+            device_id,
+            ..
+        } => {
+            if protocol_version != PROTOCOL_VERSION {
+                return Err(version_mismatch_error(&format!(
+                    "the server uses protocol version {protocol_version} while vellogd uses {PROTOCOL_VERSION}"
+                )));
+            }
+            Ok((server_name, device_id, capabilities.into_iter().collect()))
+        }
+        data => Err(version_mismatch_error(&format!(
+            "got unexpected data on handshake: {data:?}"
+        ))),
+    }
+}
+
+fn send_connection_ready(tx: &BoxedSender<Request>) -> savvy::Result<()> {
+    tx.send_message(Request::ConnectionReady {
+        protocol_version: PROTOCOL_VERSION,
+    })?;
+    Ok(())
+}
+
+fn handshake_ipc(
+    rx_server: IpcOneShotServer<Response>,
+    rx_server_name: String,
+) -> savvy::Result<Connection> {
+    // IpcOneShotServer has no accept with timeout, so accept on another thread
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(rx_server.accept());
+    });
+
+    let (rx, (server_name, device_id, capabilities)) = match receiver.recv_timeout(ACCEPT_TIMEOUT) {
+        Ok(Ok((rx, data))) => (rx, check_connect_response(data)?),
+        // An I/O error means the connection itself failed (e.g. the server
+        // crashed on startup), which is not about the version.
+        Ok(Err(e)) => match *e {
+            bincode::ErrorKind::Io(e) => {
+                return Err(savvy::Error::new(format!(
+                    "failed to accept the connection from the server: {e}"
+                )))
+            }
+            e => {
+                return Err(version_mismatch_error(&format!(
+                    "failed to decode the handshake: {e}"
+                )))
+            }
+        },
+        Err(_) => {
+            // Connect by itself to let the thread finish
+            if let Ok(tx) = IpcSender::<Response>::connect(rx_server_name) {
+                let _ = tx.send(Response::Done);
+            }
+            return Err(savvy::Error::new(format!(
+                "the server didn't connect in {} seconds",
+                ACCEPT_TIMEOUT.as_secs()
+            )));
+        }
+    };
+
+    r_eprint!("Connecting to {server_name}...");
+
+    let tx: IpcSender<Request> = IpcSender::connect(server_name)?;
+    let tx: BoxedSender<Request> = Box::new(tx);
+    send_connection_ready(&tx)?;
+
+    Ok(Connection {
+        tx,
+        rx: Box::new(rx),
+        device_id,
+        capabilities,
+    })
+}
+
+fn handshake_remote(address: &str, listen: bool) -> savvy::Result<Connection> {
+    let address: Address = address.parse()?;
+
+    let (tx, rx) = if listen {
+        let listener = address.listen(false)?;
+        r_eprint!("Waiting for the server to connect to {address}...");
+        listener.accept_timeout(ACCEPT_TIMEOUT)?
+    } else {
+        r_eprint!("Connecting to {address}...");
+        address.connect()?
+    };
+
+    let (_, device_id, capabilities) = check_connect_response(rx.recv_message()?)?;
+    send_connection_ready(&tx)?;
+
+    Ok(Connection {
+        tx,
+        rx,
+        device_id,
+        capabilities,
+    })
+}
+
+fn version_mismatch_error(detail: &str) -> savvy::Error {
+    let msg = format!(
+        "The server binary seems incompatible with this version of vellogd ({detail}).
+Please re-download the server binary by `vellogd:::download_server()`."
+    );
+    savvy::Error::new(&msg)
+}
+
 impl WindowController for VelloGraphicsDeviceWithServer {
-    fn send_event(&self, event: vellogd_shared::protocol::Request) -> savvy::Result<()> {
-        self.tx.send(event)?;
-        Ok(())
+    fn send_event(&self, event: Request) -> savvy::Result<()> {
+        self.send_event_with_recovery(event)
     }
 
-    fn recv_response(&self) -> savvy::Result<vellogd_shared::protocol::Response> {
-        let res = self.rx.recv()?;
+    fn recv_response(&self) -> savvy::Result<Response> {
+        let res = self.lock_rx()?.recv_message()?;
+        Ok(res)
+    }
+
+    fn default_font_features(&self) -> &[FontFeature] {
+        &self.features
+    }
+
+    fn request(&self, event: Request) -> savvy::Result<Response> {
+        self.send_event(event.clone())?;
+
+        let res = self.lock_rx()?.recv_message();
+        let res = match res {
+            Ok(res) => res,
+            // The server died before responding. Retry on the new server.
+            Err(e) => {
+                self.recover(&e)?;
+                self.send_event(event.clone())?;
+                self.recv_response()?
+            }
+        };
+
+        if let Response::Error { kind, message } = res {
+            return Err(savvy::savvy_err!("{kind}: {message}"));
+        }
+
+        let mut buffer = self.lock_buffer()?;
+        buffer.log_request(&event);
+        // R refers to the pattern by the index this returns.
+        if let Response::PatternRegistered { index } = res {
+            let index = buffer.add_pattern(index);
+            return Ok(Response::PatternRegistered { index });
+        }
         Ok(res)
     }
 }
@@ -106,36 +936,74 @@ impl TextLayouter for VelloGraphicsDeviceWithServer {
     fn layout_ref(&self) -> &parley::Layout<peniko::Brush> {
         &self.layout
     }
+
+    fn font_features(&self) -> &[FontFeature] {
+        &self.features
+    }
 }
 
 impl DeviceDriver for VelloGraphicsDeviceWithServer {
-    fn activate(&mut self, _: DevDesc) {
+    fn activate(&mut self, dd: DevDesc) {
         add_tracing_point!();
 
-        self.request_new_window().unwrap();
+        register_device::<Self>(&dd);
+
+        let res = self
+            .lock_buffer()
+            .map(|buffer| buffer.device_id)
+            .and_then(|device_id| self.request_new_window(device_id, self.size));
+        if let Err(e) = res {
+            r_eprintln!("Failed to activate: {e}");
+        }
     }
 
-    fn close(&mut self, _: DevDesc) {
+    fn close(&mut self, dd: DevDesc) {
         add_tracing_point!();
 
-        self.request_close_window().unwrap();
+        unregister_device(&dd);
+
+        if let Err(e) = self.request_close_window() {
+            r_eprintln!("Failed to close window: {e}");
+        }
     }
 
     // TODO
     // fn deactivate(&mut self, _: DevDesc) {}
 
-    // TODO
-    // fn mode(&mut self, mode: i32, _: DevDesc) {}
+    fn mode(&mut self, mode: i32, _: DevDesc) {
+        add_tracing_point!();
+
+        let res = self
+            .send_event(Request::SetDrawingMode { drawing: mode == 1 })
+            .and_then(|_| {
+                // The R session finished drawing, so flush the buffered requests
+                if mode == 0 {
+                    self.flush()?;
+                }
+                Ok(())
+            });
+        if let Err(e) = res {
+            r_eprintln!("Failed to send requests: {e}");
+        }
+    }
 
     fn new_page(&mut self, gc: R_GE_gcontext, _: DevDesc) {
         add_tracing_point!();
 
-        self.request_set_base_color(gc.fill).unwrap();
-        self.request_new_page().unwrap();
+        let res = self
+            .request_set_base_color(gc.fill)
+            .and_then(|_| self.request_new_page());
+        if let Err(e) = res {
+            r_eprintln!("Failed to create a new page: {e}");
+        }
     }
 
-    // TODO
-    // fn clip(&mut self, from: (f64, f64), to: (f64, f64), _: DevDesc) {}
+    fn clip(&mut self, from: (f64, f64), to: (f64, f64), _: DevDesc) {
+        self.send_event_or_warn(Request::Clip {
+            p0: from.into(),
+            p1: to.into(),
+        });
+    }
 
     fn circle(&mut self, center: (f64, f64), r: f64, gc: R_GE_gcontext, _: DevDesc) {
         add_tracing_point!();
@@ -143,13 +1011,12 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
         let fill_params = gc_to_fill_params(gc);
         let stroke_params = gc_to_stroke_params(gc);
         if fill_params.is_some() || stroke_params.is_some() {
-            self.send_event(Request::DrawCircle {
+            self.send_event_or_warn(Request::DrawCircle {
                 center: center.into(),
                 radius: r,
                 fill_params,
                 stroke_params,
-            })
-            .unwrap();
+            });
         }
     }
 
@@ -157,12 +1024,11 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
         add_tracing_point!();
 
         if let Some(stroke_params) = gc_to_stroke_params(gc) {
-            self.send_event(Request::DrawLine {
+            self.send_event_or_warn(Request::DrawLine {
                 p0: from.into(),
                 p1: to.into(),
                 stroke_params,
-            })
-            .unwrap();
+            });
         }
     }
 
@@ -172,12 +1038,11 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
         let fill_params = gc_to_fill_params(gc);
         let stroke_params = gc_to_stroke_params(gc);
         if fill_params.is_some() || stroke_params.is_some() {
-            self.send_event(Request::DrawPolygon {
+            self.send_event_or_warn(Request::DrawPolygon {
                 path: xy_to_path(x, y, true),
                 fill_params,
                 stroke_params,
-            })
-            .unwrap();
+            });
         }
     }
 
@@ -193,12 +1058,11 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
         let fill_params = gc_to_fill_params_with_flag(gc, winding);
         let stroke_params = gc_to_stroke_params(gc);
         if fill_params.is_some() || stroke_params.is_some() {
-            self.send_event(Request::DrawPolygon {
+            self.send_event_or_warn(Request::DrawPolygon {
                 path: xy_to_path_with_hole(x, y, nper),
                 fill_params,
                 stroke_params,
-            })
-            .unwrap();
+            });
         }
     }
 
@@ -207,11 +1071,10 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
 
         let stroke_params = gc_to_stroke_params(gc);
         if let Some(stroke_params) = stroke_params {
-            self.send_event(Request::DrawPolyline {
+            self.send_event_or_warn(Request::DrawPolyline {
                 path: xy_to_path(x, y, false),
                 stroke_params,
-            })
-            .unwrap();
+            });
         }
     }
 
@@ -221,13 +1084,12 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
         let fill_params = gc_to_fill_params(gc);
         let stroke_params = gc_to_stroke_params(gc);
         if fill_params.is_some() || stroke_params.is_some() {
-            self.send_event(Request::DrawRect {
+            self.send_event_or_warn(Request::DrawRect {
                 p0: from.into(),
                 p1: to.into(),
                 fill_params,
                 stroke_params,
-            })
-            .unwrap();
+            });
         }
     }
 
@@ -253,7 +1115,7 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
         let fill_params = gc_to_fill_params(gc);
         let stroke_params = gc_to_stroke_params(gc);
         if fill_params.is_some() || stroke_params.is_some() {
-            self.send_event(Request::DrawText {
+            self.send_event_or_warn(Request::DrawText {
                 pos: pos.into(),
                 text: text.into(),
                 color,
@@ -263,23 +1125,92 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
                 face: gc.fontface,
                 angle: angle as _,
                 hadj: hadj as _,
-            })
-            .unwrap();
+                features: self.features.clone(),
+            });
         }
     }
 
-    // TODO
-    // fn raster<T: AsRef<[u32]>>(
-    //     &mut self,
-    //     raster: crate::graphics::Raster<T>,
-    //     pos: (f64, f64),
-    //     size: (f64, f64),
-    //     angle: f64,
-    //     interpolate: bool,
-    //     gc: R_GE_gcontext,
-    //     _: DevDesc,
-    // ) {
-    // }
+    fn glyph(
+        &mut self,
+        glyph_ids: &[u32],
+        x: &[f64],
+        y: &[f64],
+        fontfile: &str,
+        index: i32,
+        family: &str,
+        weight: f64,
+        style: i32,
+        angle: f64,
+        size: f64,
+        colour: c_uint,
+    ) {
+        add_tracing_point!();
+
+        let font_id = match load_font_file(fontfile, index as u32) {
+            Ok(font_id) => font_id,
+            Err(e) => {
+                r_eprintln!("Failed to read {fontfile}: {e}");
+                return;
+            }
+        };
+
+        let [r, g, b, a] = colour.to_ne_bytes();
+        let color = peniko::Color::rgba8(r, g, b, a);
+        let glyph_params = GlyphParams {
+            font_id,
+            family: family.into(),
+            weight_raw: weight as f32,
+            style_raw: style as u32,
+            angle: angle.to_radians(),
+            size: size as f32,
+            color,
+        };
+
+        self.send_event_or_warn(Request::DrawGlyph {
+            glyph_ids: glyph_ids.to_vec(),
+            x: x.to_vec(),
+            y: y.to_vec(),
+            glyph_params,
+        });
+    }
+
+    fn raster(
+        &mut self,
+        raster: &[u8],
+        pixels: (u32, u32),
+        pos: (f64, f64), // bottom left corner
+        size: (f64, f64),
+        angle: f64,
+        _interpolate: bool, // TODO
+        gc: R_GE_gcontext,
+        _: DevDesc,
+    ) {
+        add_tracing_point!();
+
+        let alpha = gc.col.to_ne_bytes()[3];
+
+        // Send the pixels only when the server doesn't have the same image
+        let id = image_id(raster, pixels.0, pixels.1);
+        let register_request = self
+            .lock_buffer()
+            .map(|buffer| buffer.register_image_request(id, raster, pixels.0, pixels.1));
+        match register_request {
+            Ok(Some(request)) => self.send_event_or_warn(request),
+            Ok(None) => {}
+            Err(e) => {
+                r_eprintln!("Failed to register the image: {e}");
+                return;
+            }
+        }
+
+        self.send_event_or_warn(Request::DrawRaster {
+            image_id: id,
+            alpha,
+            pos: pos.into(),
+            size,
+            angle,
+        });
+    }
 
     // TODO
     // fn capture(&mut self, _: DevDesc) -> savvy::ffi::SEXP {
@@ -308,6 +1239,44 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
         self.get_text_width(text, gc)
     }
 
+    fn set_pattern(&mut self, pattern: SEXP, _: DevDesc) -> SEXP {
+        unsafe {
+            if pattern == R_NilValue {
+                return Rf_ScalarInteger(-1);
+            }
+        }
+
+        let res = match unsafe { sexp_to_pattern(pattern) } {
+            Some(Pattern::Gradient(gradient)) => self.request_register_gradient(gradient),
+            Some(Pattern::Tiling {
+                x,
+                y,
+                width,
+                height,
+                extend,
+                fun,
+            }) => {
+                // The drawings between these requests go to the tile on the
+                // server side.
+                self.send_event_or_warn(Request::PrepareForSaveAsTile { x, y });
+                unsafe { draw_tile(fun) };
+                self.request_register_tile(width, height, extend)
+            }
+            None => return unsafe { R_NilValue },
+        };
+
+        match res {
+            Ok(index) => unsafe { Rf_ScalarInteger(index as i32) },
+            Err(e) => {
+                r_eprintln!("Failed to register the pattern: {e}");
+                unsafe { R_NilValue }
+            }
+        }
+    }
+
+    // TODO: release the pattern on the server side as well
+    // fn release_pattern(&mut self, _ref: SEXP, _: DevDesc) {}
+
     // TODO
     // fn on_exit(&mut self, _: DevDesc) {}
 
@@ -329,3 +1298,203 @@ impl DeviceDriver for VelloGraphicsDeviceWithServer {
     // TODO
     // fn eventHelper(&mut self, _: DevDesc, code: i32) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use vellogd_shared::{
+        protocol::StrokeParams,
+        transport::{MessageReceiver, MessageSender},
+    };
+
+    use vellogd_shared::protocol::SERVER_CAPABILITIES;
+
+    use super::*;
+
+    // A sender that keeps the messages instead of sending them
+    #[derive(Clone, Default)]
+    struct MockSender(Arc<Mutex<Vec<Request>>>);
+
+    impl MessageSender<Request> for MockSender {
+        fn send_message(&self, msg: Request) -> Result<(), TransportError> {
+            self.0.lock().unwrap().push(msg);
+            Ok(())
+        }
+    }
+
+    impl MockSender {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+                .iter()
+                .map(summary)
+                .collect()
+        }
+    }
+
+    struct MockReceiver;
+
+    impl MessageReceiver<Response> for MockReceiver {
+        fn recv_message(&self) -> Result<Response, TransportError> {
+            Ok(Response::PatternRegistered { index: 0 })
+        }
+    }
+
+    fn summary(request: &Request) -> String {
+        match request {
+            Request::Batch(requests) => {
+                let requests: Vec<_> = requests.iter().map(summary).collect();
+                format!("Batch[{}]", requests.join(", "))
+            }
+            Request::NewWindow { device_id, .. } => format!("NewWindow({device_id})"),
+            Request::RegisterImage { id, .. } => format!("RegisterImage({id})"),
+            Request::DrawRect {
+                fill_params: Some(fill_params),
+                ..
+            } => match fill_params.brush {
+                FillBrush::PatternRef(index) => format!("DrawRect(pattern {index})"),
+                FillBrush::Color(_) => "DrawRect(color)".to_string(),
+            },
+            Request::DrawRect { .. } => "DrawRect".to_string(),
+            request => {
+                let debug = format!("{request:?}");
+                debug
+                    .split(|c: char| !c.is_alphanumeric())
+                    .next()
+                    .unwrap()
+                    .to_string()
+            }
+        }
+    }
+
+    fn line() -> Request {
+        Request::DrawLine {
+            p0: kurbo::Point::new(0.0, 0.0),
+            p1: kurbo::Point::new(1.0, 1.0),
+            stroke_params: StrokeParams {
+                color: peniko::Color::BLACK,
+                stroke: kurbo::Stroke::new(1.0),
+            },
+        }
+    }
+
+    fn image(id: u64) -> Request {
+        Request::RegisterImage {
+            id,
+            data: ImageBytes::Inline(vec![0; 4]),
+            width: 1,
+            height: 1,
+        }
+    }
+
+    fn rect_with_pattern(index: u32) -> Request {
+        Request::DrawRect {
+            p0: kurbo::Point::new(0.0, 0.0),
+            p1: kurbo::Point::new(1.0, 1.0),
+            fill_params: Some(FillParams {
+                brush: FillBrush::PatternRef(index),
+                use_nonzero_rule: true,
+            }),
+            stroke_params: None,
+        }
+    }
+
+    // Do what VelloGraphicsDeviceWithServer::request() does for a pattern.
+    // `server_index` is the index the server would return.
+    fn register_gradient(buffer: &mut RequestBuffer, server_index: usize) -> usize {
+        let event = Request::RegisterGradient {
+            gradient: peniko::Gradient::new_linear((0.0, 0.0), (1.0, 1.0)),
+        };
+        assert!(buffer.send(event.clone()).is_ok());
+        buffer.log_request(&event);
+        buffer.add_pattern(server_index)
+    }
+
+    fn new_buffer(tx: &MockSender) -> RequestBuffer {
+        let capabilities = SERVER_CAPABILITIES.iter().map(|x| x.to_string()).collect();
+        RequestBuffer::new(Box::new(tx.clone()), 1, capabilities, None)
+    }
+
+    #[test]
+    fn test_send() {
+        let tx = MockSender::default();
+        let mut buffer = new_buffer(&tx);
+
+        assert!(buffer.send(line()).is_ok());
+        assert!(buffer.send(image(1)).is_ok());
+        // The server already has the image
+        assert!(buffer.send(image(1)).is_ok());
+        assert!(buffer.send(line()).is_ok());
+        assert!(buffer.flush().is_ok());
+
+        // The image is sent on its own, after the preceding requests
+        assert_eq!(
+            tx.take(),
+            ["Batch[DrawLine]", "RegisterImage(1)", "Batch[DrawLine]"]
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let tx = MockSender::default();
+        let mut buffer = new_buffer(&tx);
+
+        assert!(buffer
+            .send(Request::NewWindow {
+                device_id: 1,
+                size: (100, 100),
+            })
+            .is_ok());
+        let pattern = register_gradient(&mut buffer, 0);
+        assert!(buffer.send(image(1)).is_ok());
+        assert!(buffer.send(line()).is_ok());
+        assert!(buffer.send(rect_with_pattern(pattern as u32)).is_ok());
+        assert!(buffer.flush().is_ok());
+        tx.take();
+
+        let new_tx = MockSender::default();
+        let rx: BoxedReceiver<Response> = Box::new(MockReceiver);
+        let capabilities = buffer.capabilities.clone();
+        assert!(buffer
+            .replay(Box::new(new_tx.clone()), 2, capabilities, &rx)
+            .is_ok());
+
+        assert_eq!(
+            new_tx.take(),
+            [
+                "NewWindow(2)",
+                "RegisterGradient",
+                "RegisterImage(1)",
+                "Batch[DrawLine, DrawRect(pattern 0)]",
+            ]
+        );
+        // The old channel is not used anymore
+        assert!(tx.take().is_empty());
+
+        // The server has the image after the replay
+        assert!(buffer.send(image(1)).is_ok());
+        assert!(buffer.flush().is_ok());
+        assert!(new_tx.take().is_empty());
+    }
+
+    #[test]
+    fn test_lost_patterns() {
+        let tx = MockSender::default();
+        let mut buffer = new_buffer(&tx);
+
+        assert_eq!(register_gradient(&mut buffer, 0), 0);
+        assert_eq!(register_gradient(&mut buffer, 1), 1);
+
+        // The new server has no patterns, so it starts from 0 again
+        buffer.forget_patterns();
+        assert_eq!(register_gradient(&mut buffer, 0), 2);
+        tx.take();
+
+        // The fill with a lost pattern is dropped, and the index of R is
+        // mapped to the one of the server.
+        assert!(buffer.send(rect_with_pattern(1)).is_ok());
+        assert!(buffer.send(rect_with_pattern(2)).is_ok());
+        assert!(buffer.flush().is_ok());
+        assert_eq!(tx.take(), ["Batch[DrawRect, DrawRect(pattern 0)]"]);
+    }
+}
@@ -0,0 +1,106 @@
+use clap::{builder::RangedU64ValueParser, value_parser, ArgGroup, Parser};
+use vellogd_shared::{
+    queue::DEFAULT_QUEUE_CAPACITY, transport::Address, winit_app::DEFAULT_MAX_FPS,
+};
+
+/// The server of vellogd, which shows the window on behalf of the R session.
+///
+/// Usually, this is launched by `vellogd_with_server()`, but this can also be
+/// launched manually to show the window on a different machine from R's one
+/// (`--listen`, `--connect`), or to replay a recording (`--replay`).
+#[derive(Parser, Debug)]
+#[command(version, about)]
+#[command(group(
+    ArgGroup::new("endpoint")
+        .required(true)
+        .args(["server_name", "listen", "connect", "replay"])
+        .multiple(true)
+))]
+pub(crate) struct Cli {
+    /// The name of the IPC server of the client. This is specified by the R
+    /// session.
+    pub server_name: Option<String>,
+
+    /// Wait for the clients to connect to the address (`tcp://HOST:PORT` or
+    /// `ws://HOST:PORT`). Each client opens its own window. If the port is 0,
+    /// a free port is used.
+    ///
+    /// This can be combined with the server name so that other devices can
+    /// attach to the server launched by the R session.
+    #[arg(long, value_name = "ADDRESS", value_parser = parse_address)]
+    pub listen: Option<Address>,
+
+    /// Allow `--listen` on an address other machines can reach. There's no
+    /// authentication, so anyone who can reach the port can draw on the
+    /// window, write PNG files, and shut down the server. Prefer a loopback
+    /// address with an SSH tunnel.
+    #[arg(long, requires = "listen")]
+    pub allow_remote: bool,
+
+    /// The name to find the server by `vellogd_attach(name)`. The server is
+    /// discoverable only when `--listen` is specified.
+    #[arg(long, requires = "listen")]
+    pub name: Option<String>,
+
+    /// Connect to the client waiting at the address (`tcp://HOST:PORT` or
+    /// `ws://HOST:PORT`).
+    #[arg(long, value_name = "ADDRESS", value_parser = parse_address, conflicts_with_all = ["server_name", "listen"])]
+    pub connect: Option<Address>,
+
+    /// Replay the recording created by `vellogd_with_server(record = ...)`.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["server_name", "listen", "connect"])]
+    pub replay: Option<String>,
+
+    /// Render the result of the replay to the PNG file without opening a
+    /// window.
+    #[arg(long, value_name = "FILE", requires = "replay")]
+    pub png: Option<String>,
+
+    /// The width of the window in pixels.
+    #[arg(long, default_value_t = 480, value_parser = value_parser!(u32).range(1..))]
+    pub width: u32,
+
+    /// The height of the window in pixels [default: the same as the width]
+    #[arg(long, value_parser = value_parser!(u32).range(1..))]
+    pub height: Option<u32>,
+
+    /// The maximum number of frames per second. The window is redrawn only
+    /// when the plot changes or an animation is playing.
+    #[arg(long, value_name = "FPS", default_value_t = DEFAULT_MAX_FPS, value_parser = value_parser!(u32).range(1..=240))]
+    pub max_fps: u32,
+
+    /// The maximum number of the requests waiting for the window. If the
+    /// window falls behind, the R session waits until the window catches up.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_QUEUE_CAPACITY, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub queue_capacity: usize,
+
+    /// The title of the window.
+    #[arg(long, default_value = "vellogd")]
+    pub title: String,
+
+    /// The background colour until the R session sets it, in CSS colour
+    /// syntax (e.g. `white`, `#RRGGBB`, or `#RRGGBBAA`).
+    #[arg(long, value_name = "COLOR", default_value = "transparent", value_parser = parse_color)]
+    pub background: u32,
+
+    /// Don't open any window. The requests are processed as usual, which is
+    /// useful to run the server on a machine without display (e.g. on CI).
+    #[arg(long)]
+    pub headless: bool,
+
+    /// The level of the messages to show (off, error, warn, info, debug, or
+    /// trace).
+    #[arg(long, value_name = "LEVEL", default_value = "info")]
+    pub log_level: log::LevelFilter,
+}
+
+fn parse_address(s: &str) -> Result<Address, String> {
+    s.parse::<Address>().map_err(|e| e.to_string())
+}
+
+// Convert to the same representation as R's colour so that this can be used
+// as a base color.
+fn parse_color(s: &str) -> Result<u32, String> {
+    let color = peniko::Color::parse(s).ok_or_else(|| format!("invalid colour: {s}"))?;
+    Ok(u32::from_ne_bytes([color.r, color.g, color.b, color.a]))
+}
@@ -0,0 +1,420 @@
+// Host multiple devices on one server. Each device has its own app (i.e. its
+// own window and scene), and the requests are routed to the app by the device
+// id.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use vellogd_shared::{
+    protocol::{AppResponseRelay, Request},
+    queue::EventQueue,
+    winit_app::VelloApp,
+};
+use winit::{
+    application::ApplicationHandler,
+    event_loop::{ActiveEventLoop, ControlFlow},
+};
+
+use crate::{create_app, AppConfig};
+
+pub(crate) enum DeviceEvent<T: AppResponseRelay> {
+    /// A new device is connected. The app is created on the receiving thread
+    /// of the device so that the event loop doesn't need to know the config.
+    Attach {
+        device_id: u32,
+        app: Box<VelloApp<'static, T>>,
+    },
+    /// A request of the device that cannot be drawn on the scene directly
+    Request { device_id: u32, request: Request },
+    /// The device is disconnected
+    Detach { device_id: u32 },
+}
+
+/// Pass the events to the server app. The number of the events waiting for
+/// the app is bounded by the queue.
+pub(crate) struct EventSink<T: AppResponseRelay> {
+    send: Arc<dyn Fn(DeviceEvent<T>) -> bool + Send + Sync>,
+    queue: Arc<EventQueue>,
+}
+
+impl<T: AppResponseRelay> Clone for EventSink<T> {
+    fn clone(&self) -> Self {
+        Self {
+            send: self.send.clone(),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T: AppResponseRelay> EventSink<T> {
+    /// `send` passes the event to the app and returns false if the app is
+    /// gone. The app must release the slot of every event.
+    pub(crate) fn new(
+        queue: Arc<EventQueue>,
+        send: impl Fn(DeviceEvent<T>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            send: Arc::new(send),
+            queue,
+        }
+    }
+
+    /// Send the event. This blocks while the queue is full, which stops
+    /// receiving the requests from the device until the app catches up.
+    /// Returns false if the app is gone.
+    pub(crate) fn send(&self, event: DeviceEvent<T>) -> bool {
+        self.queue.acquire();
+        let sent = (self.send)(event);
+        if !sent {
+            self.queue.release();
+        }
+        sent
+    }
+
+    /// Send the event only to wake up the app. This never blocks, and the
+    /// event is dropped if other events are pending.
+    pub(crate) fn wake(&self, event: DeviceEvent<T>) {
+        if self.queue.acquire_if_idle() && !(self.send)(event) {
+            self.queue.release();
+        }
+    }
+}
+
+/// Create the apps of the devices and route the requests to them. This is
+/// cloned to every thread receiving the requests.
+pub(crate) struct Router<T: AppResponseRelay> {
+    config: Arc<AppConfig>,
+    sink: EventSink<T>,
+    next_device_id: Arc<AtomicU32>,
+}
+
+impl<T: AppResponseRelay> Clone for Router<T> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            sink: self.sink.clone(),
+            next_device_id: self.next_device_id.clone(),
+        }
+    }
+}
+
+impl<T: AppResponseRelay + Send + 'static> Router<T> {
+    pub(crate) fn new(config: AppConfig, sink: EventSink<T>) -> Self {
+        Self {
+            config: Arc::new(config),
+            sink,
+            next_device_id: Arc::new(AtomicU32::new(1)),
+        }
+    }
+
+    pub(crate) fn new_device_id(&self) -> u32 {
+        self.next_device_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Create an app for the device and attach it to the server. Returns the
+    /// function to forward the requests of the device, which draws them on
+    /// the scene or sends them to the app.
+    pub(crate) fn attach(&self, device_id: u32, tx: T) -> impl FnMut(Request) -> bool {
+        let sink = self.sink.clone();
+        let waker = {
+            let sink = sink.clone();
+            Arc::new(move || {
+                sink.wake(DeviceEvent::Request {
+                    device_id,
+                    request: Request::RedrawWindow,
+                });
+            })
+        };
+
+        let (mut app, mut request_handler) = create_app(&self.config, tx, Some(waker));
+        app.set_queue(sink.queue.clone());
+        let attached = sink.send(DeviceEvent::Attach {
+            device_id,
+            app: Box::new(app),
+        });
+
+        move |request| {
+            if !attached {
+                return false;
+            }
+            match request_handler.handle(request) {
+                Some(request) => sink.send(DeviceEvent::Request { device_id, request }),
+                None => true,
+            }
+        }
+    }
+
+    pub(crate) fn detach(&self, device_id: u32) {
+        self.sink.send(DeviceEvent::Detach { device_id });
+    }
+}
+
+pub(crate) struct ServerApp<T: AppResponseRelay> {
+    devices: HashMap<u32, VelloApp<'static, T>>,
+    queue: Arc<EventQueue>,
+    // If false, the server keeps waiting for new devices even after all the
+    // devices are detached.
+    exit_when_empty: bool,
+}
+
+impl<T: AppResponseRelay> ServerApp<T> {
+    pub(crate) fn new(queue: Arc<EventQueue>, exit_when_empty: bool) -> Self {
+        Self {
+            devices: HashMap::new(),
+            queue,
+            exit_when_empty,
+        }
+    }
+
+    /// Process the event. `event_loop` is None on headless mode. Returns false
+    /// if the server should exit.
+    pub(crate) fn handle(
+        &mut self,
+        event: DeviceEvent<T>,
+        event_loop: Option<&ActiveEventLoop>,
+    ) -> bool {
+        // Release the slot first so that the devices can send the next event
+        // while this one is processed.
+        self.queue.release();
+
+        match event {
+            DeviceEvent::Attach { device_id, app } => {
+                log::info!("device {device_id} is attached");
+                self.devices.insert(device_id, *app);
+            }
+            DeviceEvent::Detach { device_id } => {
+                // The window is closed when the app is dropped
+                self.devices.remove(&device_id);
+                log::info!("device {device_id} is detached");
+                if self.devices.is_empty() && self.exit_when_empty {
+                    return false;
+                }
+            }
+            // Since the events are processed in order, the requests sent
+            // before this (e.g. SaveAsPng) are already finished here.
+            DeviceEvent::Request {
+                device_id,
+                request: Request::Shutdown,
+            } => {
+                log::info!("device {device_id} requested to shut down the server");
+                if let Some(app) = self.devices.get_mut(&device_id) {
+                    app.handle_request(Request::Shutdown);
+                }
+                // The windows are closed when the apps are dropped
+                self.devices.clear();
+                return false;
+            }
+            DeviceEvent::Request { device_id, request } => {
+                let Some(app) = self.devices.get_mut(&device_id) else {
+                    log::warn!("got a request for unknown device {device_id}");
+                    return true;
+                };
+                match (request, event_loop) {
+                    (Request::NewWindow { size, .. }, Some(event_loop)) => {
+                        app.open_window(event_loop, size)
+                    }
+                    // There's no window, but the size should be the same
+                    (Request::NewWindow { size, .. }, None) => app.set_size(size.0, size.1),
+                    // These requests are about the window
+                    (Request::RedrawWindow | Request::CloseWindow, None) => {}
+                    (request, _) => app.handle_request(request),
+                }
+            }
+        }
+        true
+    }
+}
+
+impl<T: AppResponseRelay + 'static> ApplicationHandler<DeviceEvent<T>> for ServerApp<T> {
+    // The windows are opened by Request::NewWindow
+    fn resumed(&mut self, _event_loop: &ActiveEventLoop) {}
+
+    fn suspended(&mut self, event_loop: &ActiveEventLoop) {
+        for app in self.devices.values_mut() {
+            app.suspended(event_loop);
+        }
+    }
+
+    // Each app ignores the events of the other apps' windows
+    fn window_event(
+        &mut self,
+        event_loop: &ActiveEventLoop,
+        window_id: winit::window::WindowId,
+        event: winit::event::WindowEvent,
+    ) {
+        for app in self.devices.values_mut() {
+            app.window_event(event_loop, window_id, event.clone());
+        }
+    }
+
+    fn user_event(&mut self, event_loop: &ActiveEventLoop, event: DeviceEvent<T>) {
+        if !self.handle(event, Some(event_loop)) {
+            event_loop.exit();
+        }
+    }
+
+    // Sleep until the earliest frame of the apps
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        let next_frame = self
+            .devices
+            .values()
+            .filter_map(|app| match app.schedule_redraw() {
+                ControlFlow::WaitUntil(next_frame) => Some(next_frame),
+                _ => None,
+            })
+            .min();
+        event_loop.set_control_flow(next_frame.map_or(ControlFlow::Wait, ControlFlow::WaitUntil));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Mutex},
+        time::Duration,
+    };
+
+    use vellogd_shared::protocol::{QueueStats, Response};
+
+    use super::*;
+
+    // Keep the responses to the device
+    #[derive(Clone, Default)]
+    struct Responses(Arc<Mutex<Vec<Response>>>);
+
+    impl AppResponseRelay for Responses {
+        fn respond(&self, response: Response) {
+            self.0.lock().unwrap().push(response);
+        }
+    }
+
+    type Events = mpsc::Receiver<DeviceEvent<Responses>>;
+
+    // A sink that passes the events to a channel instead of the event loop
+    fn sink(capacity: usize) -> (EventSink<Responses>, Events, Arc<EventQueue>) {
+        let queue = Arc::new(EventQueue::new(capacity));
+        let (tx, rx) = mpsc::channel();
+        let sink = EventSink::new(queue.clone(), move |event| tx.send(event).is_ok());
+        (sink, rx, queue)
+    }
+
+    fn detach(device_id: u32) -> DeviceEvent<Responses> {
+        DeviceEvent::Detach { device_id }
+    }
+
+    #[test]
+    fn test_send_blocks_at_capacity() {
+        let (sink, events, queue) = sink(2);
+        let mut server = ServerApp::new(queue.clone(), false);
+
+        assert!(sink.send(detach(1)));
+        assert!(sink.send(detach(2)));
+
+        let (acquired_tx, acquired_rx) = mpsc::channel();
+        let sink2 = sink.clone();
+        std::thread::spawn(move || {
+            let _ = acquired_tx.send(sink2.send(detach(3)));
+        });
+        assert!(acquired_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        assert_eq!(queue.stats().blocked, 1);
+
+        // Processing an event releases the slot
+        assert!(server.handle(events.recv().unwrap(), None));
+        assert_eq!(acquired_rx.recv_timeout(Duration::from_secs(10)), Ok(true));
+
+        while let Ok(event) = events.try_recv() {
+            assert!(server.handle(event, None));
+        }
+        let stats = queue.stats();
+        assert_eq!((stats.depth, stats.peak, stats.blocked), (0, 2, 1));
+    }
+
+    #[test]
+    fn test_send_to_gone_app() {
+        let queue = Arc::new(EventQueue::new(1));
+        let sink = EventSink::<Responses>::new(queue.clone(), |_| false);
+
+        // The slot is returned, so this doesn't block
+        assert!(!sink.send(detach(1)));
+        assert!(!sink.send(detach(1)));
+        sink.wake(detach(1));
+        assert_eq!(queue.stats().depth, 0);
+    }
+
+    #[test]
+    fn test_wake_is_coalesced() {
+        let (sink, events, queue) = sink(4);
+        let mut server = ServerApp::new(queue.clone(), false);
+
+        sink.wake(detach(1));
+        sink.wake(detach(1));
+        assert!(sink.send(detach(1)));
+        sink.wake(detach(1));
+        assert_eq!(events.try_iter().count(), 2);
+        assert_eq!(queue.stats().coalesced, 2);
+
+        // The queue is not empty until the events are processed
+        sink.wake(detach(1));
+        assert_eq!(queue.stats().coalesced, 3);
+        server.handle(detach(1), None);
+        server.handle(detach(1), None);
+        sink.wake(detach(1));
+        assert_eq!(events.try_iter().count(), 1);
+        assert_eq!(queue.stats().coalesced, 3);
+    }
+
+    // The statistics vellogd_queue_stats() gets from the server
+    #[test]
+    fn test_queue_stats_response() {
+        let (sink, events, queue) = sink(8);
+        let mut server = ServerApp::new(queue.clone(), false);
+        let config = AppConfig {
+            width: 100,
+            height: 100,
+            max_fps: 60,
+            queue_capacity: 8,
+            title: "test".to_string(),
+            background: 0xffffffff,
+        };
+        let router = Router::new(config, sink);
+
+        let responses = Responses::default();
+        let mut forward = router.attach(1, responses.clone());
+        assert!(server.handle(events.recv().unwrap(), None));
+
+        assert!(forward(Request::GetQueueStats));
+        assert!(forward(Request::GetQueueStats));
+        assert_eq!(queue.stats().depth, 2);
+        for event in events.try_iter() {
+            assert!(server.handle(event, None));
+        }
+
+        // The request being processed doesn't count
+        let stats: Vec<QueueStats> = responses
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|res| match res {
+                Response::QueueStats { stats } => *stats,
+                res => panic!("unexpected response: {res:?}"),
+            })
+            .collect();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            (stats[0].depth, stats[0].capacity, stats[0].peak),
+            (1, 8, 2)
+        );
+        assert_eq!(
+            (stats[1].depth, stats[1].capacity, stats[1].peak),
+            (0, 8, 2)
+        );
+    }
+}
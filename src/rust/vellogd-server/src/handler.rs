@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use vellogd_shared::{
+    fonts::handle_font_request,
+    protocol::Request,
+    text_layouter::{build_rich_layout, line_spacing, TextLayouter},
+    winit_app::{LockedScene, SceneDrawer},
+};
+
+pub(crate) struct SceneRequestHandler {
+    pub scene: SceneDrawer,
+    // Since the text is laid out on the server side, the handler needs to
+    // hold its own layout.
+    layout: parley::Layout<peniko::Brush>,
+    // The images registered by Request::RegisterImage
+    images: HashMap<u64, peniko::Image>,
+}
+
+impl TextLayouter for SceneRequestHandler {
+    fn layout_mut(&mut self) -> &mut parley::Layout<peniko::Brush> {
+        &mut self.layout
+    }
+
+    fn layout_ref(&self) -> &parley::Layout<peniko::Brush> {
+        &self.layout
+    }
+}
+
+impl SceneRequestHandler {
+    pub(crate) fn new(scene: SceneDrawer) -> Self {
+        Self {
+            scene,
+            layout: parley::Layout::new(),
+            images: HashMap::new(),
+        }
+    }
+
+    /// Draw the request on the scene if it's a draw request. Otherwise, return
+    /// the request as it is so that the caller can forward it to the event loop.
+    pub(crate) fn handle(&mut self, event: Request) -> Option<Request> {
+        match event {
+            Request::Batch(events) => self.handle_batch(events),
+            event if event.can_be_batched() => self.handle_event(event),
+            // The fonts need to be registered before the subsequent text is
+            // laid out on this thread.
+            event @ (Request::RegisterFont { .. }
+            | Request::SetFontFallbacks { .. }
+            | Request::SetSymbolFont { .. }
+            | Request::RegisterFontFile { .. }) => {
+                if let Some(Err(e)) = handle_font_request(event) {
+                    log::warn!("failed to set up the font: {e}");
+                }
+            }
+            event => {
+                // The client forgets the images on a new page as well
+                if let Request::NewPage = event {
+                    self.images.clear();
+                }
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    fn handle_event(&mut self, event: Request) {
+        // Note: SceneDrawer is cloned to avoid borrowing self while drawing
+        let drawer = self.scene.clone();
+        let mut scene = drawer.lock();
+        self.draw(&mut scene, event);
+    }
+
+    // Apply all the requests while holding the lock of the scene
+    fn handle_batch(&mut self, events: Vec<Request>) {
+        let drawer = self.scene.clone();
+        let mut scene = drawer.lock();
+        for event in events {
+            self.draw(&mut scene, event);
+        }
+    }
+
+    fn draw(&mut self, scene: &mut LockedScene, event: Request) {
+        match event {
+            Request::DrawCircle {
+                center,
+                radius,
+                fill_params,
+                stroke_params,
+            } => {
+                scene.draw_circle(center, radius, fill_params, stroke_params);
+            }
+            Request::DrawLine {
+                p0,
+                p1,
+                stroke_params,
+            } => {
+                scene.draw_line(p0, p1, stroke_params);
+            }
+            Request::DrawPolygon {
+                path,
+                fill_params,
+                stroke_params,
+            } => {
+                scene.draw_polygon(path, fill_params, stroke_params);
+            }
+            Request::DrawPolyline {
+                path,
+                stroke_params,
+            } => {
+                scene.draw_polyline(path, stroke_params);
+            }
+            Request::DrawRect {
+                p0,
+                p1,
+                fill_params,
+                stroke_params,
+            } => {
+                scene.draw_rect(p0, p1, fill_params, stroke_params);
+            }
+            Request::DrawText {
+                pos,
+                text,
+                color,
+                size,
+                lineheight,
+                family,
+                face,
+                angle,
+                hadj,
+                features,
+            } => {
+                self.build_layout(text, &family, face, size, lineheight, &features);
+                scene.draw_layout(
+                    &self.layout,
+                    color,
+                    pos,
+                    angle as f64,
+                    hadj as f64,
+                    line_spacing(size, lineheight),
+                );
+            }
+            Request::DrawRichText {
+                pos,
+                text,
+                angle,
+                hadj,
+                vadj,
+            } => {
+                build_rich_layout(&mut self.layout, &text);
+                scene.draw_rich_layout(
+                    &self.layout,
+                    &text,
+                    pos,
+                    angle as f64,
+                    hadj as f64,
+                    vadj as f64,
+                );
+            }
+            Request::RegisterImage {
+                id,
+                data,
+                width,
+                height,
+            } => {
+                let image =
+                    peniko::Image::new(data.into_blob(), peniko::Format::Rgba8, width, height)
+                        .with_extend(peniko::Extend::Pad);
+                self.images.insert(id, image);
+            }
+            Request::DrawRaster {
+                image_id,
+                alpha,
+                pos,
+                size,
+                angle,
+            } => match self.images.get(&image_id) {
+                Some(image) => {
+                    let image = peniko::Image {
+                        alpha,
+                        ..image.clone()
+                    };
+                    scene.draw_raster(&image, pos, size, angle);
+                }
+                None => log::warn!("the image {image_id} is not registered"),
+            },
+            Request::DrawGlyph {
+                glyph_ids,
+                x,
+                y,
+                glyph_params,
+            } => {
+                // Since the draw requests don't have any response, the error
+                // cannot be sent to the client.
+                if let Err(e) = scene.draw_glyph_raw(&glyph_ids, &x, &y, &glyph_params) {
+                    log::warn!("failed to draw glyphs: {e}");
+                }
+            }
+            Request::Clip { p0, p1 } => {
+                scene.clip(p0, p1);
+            }
+            Request::PrepareForSaveAsTile { x, y } => {
+                scene.begin_tile(x, y);
+            }
+            Request::SetDrawingMode { drawing } => {
+                self.scene.set_drawing(drawing);
+            }
+            _ => {}
+        }
+    }
+}
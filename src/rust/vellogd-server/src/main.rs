@@ -1,165 +1,325 @@
-use std::sync::{
-    atomic::{AtomicBool, AtomicU32},
-    Arc, Mutex,
-};
+mod cli;
+mod devices;
+mod handler;
+mod replay;
+
+use std::sync::{atomic::AtomicU32, Arc, Mutex};
 
-use ipc_channel::ipc::{IpcOneShotServer, IpcReceiver, IpcSender};
+use clap::Parser;
+use cli::Cli;
+use devices::{EventSink, Router, ServerApp};
+use handler::SceneRequestHandler;
+use ipc_channel::ipc::{IpcOneShotServer, IpcSender};
 use vellogd_shared::{
-    protocol::{Request, Response},
-    winit_app::{calc_y_translate, create_event_loop, SceneDrawer, VelloApp},
+    discovery::{self, ServerEntry},
+    protocol::{AppResponseRelay, Request, Response, PROTOCOL_VERSION, SERVER_CAPABILITIES},
+    queue::EventQueue,
+    transport::{Address, BoxedReceiver, BoxedSender, Listener, TransportError},
+    winit_app::{calc_y_translate, create_event_loop, SceneDrawer, VelloApp, Waker},
 };
 
-// TODO: make this configurable
-const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(16); // = 60fps
+enum Endpoint {
+    /// The name of the IpcOneShotServer of the client
+    Ipc(String),
+    /// Connect to the client waiting at the address
+    Connect(Address),
+}
 
-struct SceneRequestHandler {
-    pub scene: SceneDrawer,
+/// The settings of the app, which are common to all the modes.
+pub(crate) struct AppConfig {
+    pub width: u32,
+    pub height: u32,
+    pub max_fps: u32,
+    pub queue_capacity: usize,
+    pub title: String,
+    pub background: u32,
 }
 
-impl SceneRequestHandler {
-    fn handle_event(&self, event: Request) {
-        match event {
-            Request::DrawCircle {
-                center,
-                radius,
-                fill_params,
-                stroke_params,
-            } => {
-                self.scene
-                    .draw_circle(center, radius, fill_params, stroke_params);
-            }
-            Request::DrawLine {
-                p0,
-                p1,
-                stroke_params,
-            } => {
-                self.scene.draw_line(p0, p1, stroke_params);
-            }
-            Request::DrawPolygon {
-                path,
-                fill_params,
-                stroke_params,
-            } => {
-                self.scene.draw_polygon(path, fill_params, stroke_params);
-            }
-            Request::DrawPolyline {
-                path,
-                stroke_params,
-            } => {
-                self.scene.draw_polyline(path, stroke_params);
-            }
-            Request::DrawRect {
-                p0,
-                p1,
-                fill_params,
-                stroke_params,
-            } => {
-                self.scene.draw_rect(p0, p1, fill_params, stroke_params);
+/// Forward the request to the app. Returns false if the app is gone.
+pub(crate) type Forward<'a> = dyn FnMut(Request) -> bool + 'a;
+
+// Tell the server name and the device id to the client
+fn send_connect(
+    tx: &BoxedSender<Response>,
+    server_name: String,
+    device_id: u32,
+    attach_address: Option<&Address>,
+) -> Result<(), String> {
+    tx.send_message(Response::Connect {
+        server_name,
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: SERVER_CAPABILITIES.iter().map(|x| x.to_string()).collect(),
+        device_id,
+        attach_address: attach_address.map(|x| x.to_string()),
+    })
+    .map_err(|e| format!("failed to send the server name: {e}"))
+}
+
+fn connect(
+    endpoint: Endpoint,
+    device_id: u32,
+    attach_address: Option<&Address>,
+) -> Result<(BoxedSender<Response>, BoxedReceiver<Request>), String> {
+    let (tx, rx, first_request) = match endpoint {
+        Endpoint::Ipc(tx_server_name) => {
+            // First, connect from server to client
+            let tx: IpcSender<Response> = IpcSender::connect(tx_server_name)
+                .map_err(|e| format!("failed to connect to the client: {e}"))?;
+            let tx: BoxedSender<Response> = Box::new(tx);
+            // Then, create a connection of the opposite direction
+            let (rx_server, rx_server_name) = IpcOneShotServer::<Request>::new()
+                .map_err(|e| format!("failed to create a server: {e}"))?;
+            send_connect(&tx, rx_server_name, device_id, attach_address)?;
+            // Wait for the client is ready
+            //
+            // If this fails, probably, this is because the client is of a
+            // different version and the data cannot be deserialized.
+            let (rx, first_request) = rx_server
+                .accept()
+                .map_err(|e| format!("failed to accept connection: {e}"))?;
+            let rx: BoxedReceiver<Request> = Box::new(rx);
+            (tx, rx, first_request)
+        }
+        Endpoint::Connect(address) => {
+            let (tx, rx) = address.connect().map_err(|e| e.to_string())?;
+            return handshake(tx, rx, address.to_string(), device_id, attach_address);
+        }
+    };
+
+    check_connection_ready(first_request)?;
+    Ok((tx, rx))
+}
+
+// Handshake on a TCP or WebSocket connection
+fn handshake(
+    tx: BoxedSender<Response>,
+    rx: BoxedReceiver<Request>,
+    server_name: String,
+    device_id: u32,
+    attach_address: Option<&Address>,
+) -> Result<(BoxedSender<Response>, BoxedReceiver<Request>), String> {
+    send_connect(&tx, server_name, device_id, attach_address)?;
+    let first_request = rx.recv_message().map_err(|e| e.to_string())?;
+    check_connection_ready(first_request)?;
+    Ok((tx, rx))
+}
+
+fn check_connection_ready(first_request: Request) -> Result<(), String> {
+    match first_request {
+        // The client should have already checked the version, but check here
+        // as well just in case.
+        Request::ConnectionReady { protocol_version } if protocol_version != PROTOCOL_VERSION => {
+            Err(format!(
+                "The protocol version of the client ({protocol_version}) doesn't match the server's ({PROTOCOL_VERSION})"
+            ))
+        }
+        Request::ConnectionReady { .. } => Ok(()),
+        data => Err(format!("got unexpected data: {data:?}")),
+    }
+}
+
+// Accept the clients one after another. Each client is a new device.
+fn accept_devices(listener: Listener, address: Address, router: Router<BoxedSender<Response>>) {
+    loop {
+        let (tx, rx) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::warn!("failed to accept a connection: {e}");
+                continue;
             }
-            Request::DrawText { .. } => {
-                // TODO: where to store layout?
-
-                // self.build_layout(text, size, lineheight);
-
-                // let width = self.layout.width() as f64;
-                // let transform = vello::kurbo::Affine::translate((-(width * hadj), 0.0))
-                //     .then_rotate(-angle.to_radians())
-                //     .then_translate((pos.0, self.height - pos.1).into()); // Y-axis is flipped
-
-                // for line in self.layout.lines() {
-                //     let vadj = line.metrics().ascent * 0.5;
-                //     for item in line.items() {
-                //         // ignore inline box
-                //         let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item else {
-                //             continue;
-                //         };
-
-                //         // TODO: do not lock per glyph
-                //         scene_drawer.draw_glyph(glyph_run, color, transform, vadj);
-                //     }
-                // }
+        };
+
+        let router = router.clone();
+        let address = address.clone();
+        std::thread::spawn(move || {
+            let device_id = router.new_device_id();
+            match handshake(tx, rx, address.to_string(), device_id, Some(&address)) {
+                Ok((tx, rx)) => serve(&router, device_id, tx, rx),
+                Err(e) => log::warn!("failed to connect to the client: {e}"),
             }
-            _ => {}
-        }
+        });
     }
 }
 
-// expect `vellogd-server SERVER_NAME [WIDTH [HEIGHT]]`
-fn parse_args() -> (String, u32, u32) {
-    let tx_server_name = std::env::args().nth(1).unwrap();
+// Receive the requests of the device until the client is gone.
+fn serve(
+    router: &Router<BoxedSender<Response>>,
+    device_id: u32,
+    tx: BoxedSender<Response>,
+    rx: BoxedReceiver<Request>,
+) {
+    log::debug!("connected to the client of device {device_id}");
 
-    let width = match std::env::args().nth(2).map(|x| x.parse::<u32>()) {
-        Some(Ok(w)) => w,
-        _ => 480,
-    };
+    let mut forward = router.attach(device_id, tx);
+    loop {
+        let event = match rx.recv_message() {
+            Ok(event) => event,
+            Err(e) => {
+                log::info!("disconnected from the client of device {device_id}: {e}");
+                break;
+            }
+        };
+        log::trace!("received {event:?} from device {device_id}");
+        if !forward(event) {
+            break;
+        }
+    }
 
-    let height = match std::env::args().nth(3).map(|x| x.parse::<u32>()) {
-        Some(Ok(w)) => w,
-        _ => width,
-    };
+    router.detach(device_id);
+}
 
-    (tx_server_name, width, height)
+// The address for the clients on the same machine. If the server listens on
+// all the interfaces, use the loopback one.
+fn local_address(listener: &Listener) -> std::io::Result<Address> {
+    let mut addr = listener.local_addr()?;
+    if addr.ip().is_unspecified() {
+        let loopback = match addr {
+            std::net::SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            std::net::SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        };
+        addr.set_ip(loopback);
+    }
+    Ok(match listener.address()? {
+        Address::Tcp(_) => Address::Tcp(addr.to_string()),
+        Address::WebSocket(_) => Address::WebSocket(addr.to_string()),
+    })
 }
 
-fn main() {
-    let (tx_server_name, width, height) = parse_args();
+fn exit_with_error(e: String) -> ! {
+    log::error!("{e}");
+    std::process::exit(1);
+}
 
-    let width = Arc::new(AtomicU32::new(width));
+fn main() {
+    let cli = Cli::parse();
 
-    let y_transform = Arc::new(Mutex::new(calc_y_translate(height as f32)));
-    let height = Arc::new(AtomicU32::new(height));
+    env_logger::Builder::new()
+        .filter_level(cli.log_level)
+        .format_timestamp(None)
+        .init();
 
-    // First, connect from server to client
-    let tx: IpcSender<Response> = IpcSender::connect(tx_server_name).unwrap();
-    // Then, create a connection of the opposite direction
-    let (rx_server, rx_server_name) = IpcOneShotServer::<Request>::new().unwrap();
-    // Tell the server name to the client
-    tx.send(Response::Connect {
-        server_name: rx_server_name,
-    })
-    .unwrap();
-    // Wait for the client is ready
-    let rx: IpcReceiver<Request> = match rx_server.accept() {
-        Ok((rx, Request::ConnectionReady)) => rx,
-        Ok((_, data)) => panic!("got unexpected data: {data:?}"),
-        Err(e) => panic!("failed to accept connection: {e}"),
+    let config = AppConfig {
+        width: cli.width,
+        height: cli.height.unwrap_or(cli.width),
+        max_fps: cli.max_fps,
+        queue_capacity: cli.queue_capacity,
+        title: cli.title,
+        background: cli.background,
     };
 
-    let event_loop = create_event_loop(false);
-    let proxy = event_loop.create_proxy();
+    if let Some(file) = cli.replay {
+        let (header, entries) = replay::load(&file).unwrap_or_else(|e| exit_with_error(e));
+        let config = AppConfig {
+            width: header.width,
+            height: header.height,
+            ..config
+        };
 
-    let proxy_for_refresh = proxy.clone();
-    // TODO: stop refreshing when no window
-    std::thread::spawn(move || loop {
-        proxy_for_refresh.send_event(Request::RedrawWindow).unwrap();
-        std::thread::sleep(REFRESH_INTERVAL);
+        match cli.png {
+            Some(out) => {
+                let mut app = run_headless(&config, replay::DiscardResponse, move |forward| {
+                    replay::feed(entries, false, forward)
+                });
+                app.save_as_png(out, config.width, config.height)
+                    .unwrap_or_else(|e| exit_with_error(e.to_string()));
+            }
+            None if cli.headless => {
+                run_headless(&config, replay::DiscardResponse, move |forward| {
+                    replay::feed(entries, true, forward)
+                });
+            }
+            // Note: the window is kept open after the replay finishes
+            None => run(config, false, move |router| {
+                std::thread::spawn(move || {
+                    let mut forward = router.attach(0, replay::DiscardResponse);
+                    replay::feed(entries, true, &mut forward);
+                });
+            }),
+        }
+        return;
+    }
+
+    // Note: the listener is created first so that the address can be told
+    // to the first client.
+    let listener = cli.listen.map(|address| {
+        let listener = address
+            .listen(cli.allow_remote)
+            .unwrap_or_else(|e| match e {
+                TransportError::RemoteNotAllowed(_) => {
+                    exit_with_error(format!("{e}, or specify --allow-remote"))
+                }
+                _ => exit_with_error(e.to_string()),
+            });
+        let address = listener
+            .address()
+            .unwrap_or_else(|e| exit_with_error(e.to_string()));
+        log::info!("Listening at {address}");
+        (listener, address)
     });
+    let attach_address = listener.as_ref().map(|(_, address)| address.clone());
 
-    let needs_redraw = Arc::new(AtomicBool::new(false));
-    let scene = SceneDrawer::new(y_transform.clone(), height.clone(), needs_redraw.clone());
+    // Let the R sessions on this machine find the server. The entry is removed
+    // when this is dropped at the end of main().
+    let _registration = listener.as_ref().and_then(|(listener, _)| {
+        let entry = ServerEntry {
+            pid: std::process::id(),
+            name: cli.name.clone(),
+            address: local_address(listener).ok()?.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+        };
+        discovery::register(&entry)
+            .inspect_err(|e| log::warn!("failed to register the server: {e}"))
+            .ok()
+    });
 
-    let request_handler = SceneRequestHandler {
-        scene: scene.clone(),
+    let endpoint = match (cli.server_name, cli.connect) {
+        (Some(server_name), _) => Some(Endpoint::Ipc(server_name)),
+        (_, Some(address)) => Some(Endpoint::Connect(address)),
+        _ => None,
     };
 
-    // Since the main thread will be occupied by event_loop, the server needs to
-    // run in a spawned thread. rx waits for the event and forward it to
-    // event_loop via proxy.
-    std::thread::spawn(move || loop {
-        let event = rx.recv().unwrap();
-        match event {
-            Request::DrawCircle { .. }
-            | Request::DrawLine { .. }
-            | Request::DrawPolygon { .. }
-            | Request::DrawPolyline { .. }
-            | Request::DrawRect { .. }
-            | Request::DrawText { .. } => request_handler.handle_event(event),
-            _ => proxy.send_event(event).unwrap(),
+    // If the server is launched only to listen, it keeps waiting for new
+    // clients. Otherwise, the server exits when all the clients are gone.
+    let exit_when_empty = endpoint.is_some();
+
+    let start = move |router: Router<BoxedSender<Response>>| {
+        if let Some(endpoint) = endpoint {
+            let router = router.clone();
+            let attach_address = attach_address.clone();
+            std::thread::spawn(move || {
+                let device_id = router.new_device_id();
+                let (tx, rx) = connect(endpoint, device_id, attach_address.as_ref())
+                    .unwrap_or_else(|e| exit_with_error(e));
+                serve(&router, device_id, tx, rx);
+            });
         }
-    });
+        if let Some((listener, address)) = listener {
+            std::thread::spawn(move || accept_devices(listener, address, router));
+        }
+    };
+
+    if cli.headless {
+        serve_headless(config, exit_when_empty, start);
+    } else {
+        run(config, exit_when_empty, start);
+    }
+}
+
+pub(crate) fn create_app<'a, T: AppResponseRelay>(
+    config: &AppConfig,
+    tx: T,
+    waker: Option<Waker>,
+) -> (VelloApp<'a, T>, SceneRequestHandler) {
+    let width = Arc::new(AtomicU32::new(config.width));
+    let height = Arc::new(AtomicU32::new(config.height));
+    let y_transform = Arc::new(Mutex::new(calc_y_translate(config.height as f32)));
+
+    let scene = SceneDrawer::new(y_transform.clone(), width.clone(), height.clone(), waker);
+
+    let request_handler = SceneRequestHandler::new(scene.clone());
 
-    // This is not used for server; the base color is set via Request::SetBaseColor
-    let base_color = Arc::new(AtomicU32::new(0));
+    // This is updated by Request::SetBaseColor
+    let base_color = Arc::new(AtomicU32::new(config.background));
 
     let mut app = VelloApp::new(
         width,
@@ -167,8 +327,79 @@ fn main() {
         y_transform,
         tx,
         scene,
-        needs_redraw,
         base_color,
+        Arc::new(AtomicU32::new(config.max_fps)),
     );
-    event_loop.run_app(&mut app).unwrap();
+    app.set_window_title(&config.title);
+
+    (app, request_handler)
+}
+
+/// Run the event loop. `start` is called with the router to attach devices,
+/// which should spawn threads to receive the requests.
+fn run<T, F>(config: AppConfig, exit_when_empty: bool, start: F)
+where
+    T: AppResponseRelay + Send + 'static,
+    F: FnOnce(Router<T>),
+{
+    let event_loop = create_event_loop(false);
+    let proxy = event_loop.create_proxy();
+    let queue = Arc::new(EventQueue::new(config.queue_capacity));
+
+    // Since the main thread will be occupied by event_loop, the requests need
+    // to be received in spawned threads, and forwarded to event_loop via
+    // proxy.
+    start(Router::new(
+        config,
+        EventSink::new(queue.clone(), move |event| proxy.send_event(event).is_ok()),
+    ));
+
+    event_loop
+        .run_app(&mut ServerApp::new(queue, exit_when_empty))
+        .unwrap();
+}
+
+/// The same as `run()`, but without opening any window.
+fn serve_headless<T, F>(config: AppConfig, exit_when_empty: bool, start: F)
+where
+    T: AppResponseRelay + Send + 'static,
+    F: FnOnce(Router<T>),
+{
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let queue = Arc::new(EventQueue::new(config.queue_capacity));
+    start(Router::new(
+        config,
+        EventSink::new(queue.clone(), move |event| sender.send(event).is_ok()),
+    ));
+
+    let mut app = ServerApp::new(queue, exit_when_empty);
+    while let Ok(event) = receiver.recv() {
+        if !app.handle(event, None) {
+            break;
+        }
+    }
+}
+
+/// Process the requests without opening any window. Unlike `run()`, `feed`
+/// runs on the current thread. The app is returned so that the caller can save
+/// the result.
+fn run_headless<'a, T, F>(config: &AppConfig, tx: T, feed: F) -> VelloApp<'a, T>
+where
+    T: AppResponseRelay,
+    F: FnOnce(&mut Forward),
+{
+    let (mut app, mut request_handler) = create_app(config, tx, None);
+
+    let mut forward = |event| {
+        match request_handler.handle(event) {
+            // These requests are about the window
+            None
+            | Some(Request::NewWindow { .. } | Request::RedrawWindow | Request::CloseWindow) => {}
+            Some(event) => app.handle_request(event),
+        }
+        true
+    };
+    feed(&mut forward);
+
+    app
 }
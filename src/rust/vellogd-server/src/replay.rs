@@ -0,0 +1,45 @@
+// Replay a recording of the requests, which is created by
+// `vellogd_with_server(record = ...)`.
+
+use std::time::{Duration, Instant};
+
+use vellogd_shared::{
+    protocol::{AppResponseRelay, Response},
+    recording::{read_recording, RecordedRequest, RecordingHeader},
+};
+
+use crate::Forward;
+
+/// There's no client to receive the responses on replaying.
+pub(crate) struct DiscardResponse;
+
+impl AppResponseRelay for DiscardResponse {
+    fn respond(&self, _response: Response) {}
+}
+
+pub(crate) fn load(file: &str) -> Result<(RecordingHeader, Vec<RecordedRequest>), String> {
+    read_recording(file).map_err(|e| format!("failed to read {file}: {e}"))
+}
+
+/// Forward the recorded requests. If `paced` is true, the requests are
+/// forwarded at the same pace as they were recorded.
+pub(crate) fn feed(entries: Vec<RecordedRequest>, paced: bool, forward: &mut Forward) {
+    let start = Instant::now();
+    for RecordedRequest {
+        timestamp_us,
+        request,
+    } in entries
+    {
+        if paced {
+            let wait = Duration::from_micros(timestamp_us).saturating_sub(start.elapsed());
+            if !wait.is_zero() {
+                std::thread::sleep(wait);
+            }
+        }
+
+        // The window is closed
+        if !forward(request) {
+            return;
+        }
+    }
+}
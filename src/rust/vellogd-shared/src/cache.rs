@@ -0,0 +1,61 @@
+// The counters of the hits and misses of the caches. These are always counted
+// because it's cheap, and shown by the tracing feature.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct CacheCounter {
+    name: &'static str,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheCounter {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The font data read from the files, keyed by the path and the index
+pub static FONT_FILE_CACHE: CacheCounter = CacheCounter::new("font file cache");
+/// The metrics of the text, keyed by the text and the font parameters
+pub static TEXT_METRIC_CACHE: CacheCounter = CacheCounter::new("text metric cache");
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+/// Return the counts since the last call, and reset them.
+pub fn take_cache_stats() -> Vec<CacheStats> {
+    [&FONT_FILE_CACHE, &TEXT_METRIC_CACHE]
+        .into_iter()
+        .map(|counter| CacheStats {
+            name: counter.name,
+            hits: counter.hits.swap(0, Ordering::Relaxed),
+            misses: counter.misses.swap(0, Ordering::Relaxed),
+        })
+        .collect()
+}
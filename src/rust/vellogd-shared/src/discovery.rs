@@ -0,0 +1,205 @@
+// Find the servers running on the same machine. A server waiting for clients
+// writes its endpoint to a file in the runtime directory so that the R session
+// can attach to it without knowing the address beforehand.
+//
+// The file is removed when the server exits. But, if the server is killed, the
+// file is left, so the entries of dead processes are removed on listing.
+//
+// Since an entry tells where to connect, the directory must not be writable by
+// other users. The directory is created with mode 0700, and a directory owned
+// by someone else is refused.
+
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServerEntry {
+    pub pid: u32,
+    /// The name specified by `--name`, if any
+    pub name: Option<String>,
+    /// The address to connect to (e.g. `tcp://127.0.0.1:9000`)
+    pub address: String,
+    pub protocol_version: u32,
+}
+
+/// The directory to put the entries. This is `$XDG_RUNTIME_DIR/vellogd` if the
+/// environment variable is set, otherwise `vellogd-<uid>` under the temporary
+/// directory, which is shared with other users.
+pub fn runtime_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("vellogd"),
+        _ => std::env::temp_dir().join(shared_dir_name()),
+    }
+}
+
+#[cfg(unix)]
+fn shared_dir_name() -> String {
+    format!("vellogd-{}", unsafe { libc::geteuid() })
+}
+
+#[cfg(not(unix))]
+fn shared_dir_name() -> String {
+    "vellogd".to_string()
+}
+
+/// Create the runtime directory if it doesn't exist, and check it's safe to
+/// use.
+fn create_runtime_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    match builder.create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+
+    check_runtime_dir(dir)
+}
+
+/// Check the directory is a real directory owned by the current user and not
+/// accessible by others.
+#[cfg(unix)]
+fn check_runtime_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    // Note: symlink_metadata() doesn't follow the symlink so that a symlink to
+    // another user's directory is refused.
+    let metadata = std::fs::symlink_metadata(dir)?;
+    let refuse = |reason: &str| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("refusing to use {}: {reason}", dir.display()),
+        ))
+    };
+
+    if !metadata.is_dir() {
+        return refuse("not a directory");
+    }
+    if metadata.uid() != unsafe { libc::geteuid() } {
+        return refuse("owned by another user");
+    }
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return refuse("accessible by other users (the mode should be 0700)");
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_runtime_dir(dir: &Path) -> std::io::Result<()> {
+    if !std::fs::symlink_metadata(dir)?.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("refusing to use {}: not a directory", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+fn entry_path(dir: &Path, pid: u32) -> PathBuf {
+    dir.join(format!("{pid}.json"))
+}
+
+/// The entry is removed when this is dropped.
+pub struct Registration {
+    path: PathBuf,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Write the entry to the runtime directory.
+pub fn register(entry: &ServerEntry) -> std::io::Result<Registration> {
+    let dir = runtime_dir();
+    if let Some(parent) = dir.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    create_runtime_dir(&dir)?;
+
+    // Write to a temporary file first and then rename it so that the readers
+    // never see a half-written entry. The temporary file is created exclusively
+    // to avoid writing through a file that someone else placed.
+    let tmp_path = dir.join(format!(".{}.json.tmp", entry.pid));
+    let _ = std::fs::remove_file(&tmp_path);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    let res = serde_json::to_writer(&mut writer, entry)
+        .map_err(std::io::Error::from)
+        .and_then(|_| writer.flush());
+    drop(writer);
+
+    let path = entry_path(&dir, entry.pid);
+    if let Err(e) = res.and_then(|_| std::fs::rename(&tmp_path, &path)) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    Ok(Registration { path })
+}
+
+/// List the servers running on this machine, in the order of the process ids.
+pub fn list_servers() -> Vec<ServerEntry> {
+    let dir = runtime_dir();
+    if let Err(e) = check_runtime_dir(&dir) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log::warn!("{e}");
+        }
+        return Vec::new();
+    }
+    let Ok(files) = std::fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+
+    let mut entries: Vec<ServerEntry> = files
+        .flatten()
+        .filter(|file| file.path().extension().is_some_and(|x| x == "json"))
+        .filter_map(|file| {
+            let data = std::fs::read(file.path()).ok()?;
+            serde_json::from_slice(&data).ok()
+        })
+        .filter(|entry: &ServerEntry| {
+            let alive = is_alive(entry.pid);
+            if !alive {
+                unregister(entry.pid);
+            }
+            alive
+        })
+        .collect();
+
+    entries.sort_by_key(|entry| entry.pid);
+    entries
+}
+
+/// Remove the entry of the server. This is for the case when the server is
+/// found unreachable.
+pub fn unregister(pid: u32) {
+    let _ = std::fs::remove_file(entry_path(&runtime_dir(), pid));
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    // Signal 0 checks only the existence of the process. EPERM means the
+    // process exists, but is owned by another user.
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// TODO: check the process on Windows as well. For now, a dead server is found
+// when connecting to it fails.
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
@@ -346,6 +346,8 @@ extern "C" {
     pub fn GEcreateDevDesc(dev: pDevDesc) -> pGEDevDesc;
     pub fn GEinitDisplayList(dd: pGEDevDesc);
     pub fn GEaddDevice2(arg1: pGEDevDesc, arg2: *const c_char);
+    pub fn GEgetDevice(arg1: c_int) -> pGEDevDesc;
+    pub fn Rf_curDevice() -> c_int;
 
     // pattern
     pub fn R_GE_patternType(pattern: SEXP) -> c_int;
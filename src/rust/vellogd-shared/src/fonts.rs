@@ -0,0 +1,492 @@
+// The fonts registered by the user on top of the system fonts.
+//
+// R specifies a font by a family name and a face (plain, bold, italic, or bold
+// italic). The family can be an alias of a registered font file, or one of R's
+// standard families ("sans", "serif", and "mono"), which are mapped to the
+// generic families unless the user registers a font for them.
+//
+// The face 5 (symbol) is special; R uses it for the Greek letters and the
+// mathematical symbols of plotmath regardless of the family, so it's mapped to
+// the symbol font instead of the family. Since the device sets wantSymbolUTF8,
+// the characters are already converted to Unicode, so any font that covers
+// them can be used.
+//
+// Since the text is laid out both on the R session (to measure the text) and on
+// the server (to draw the text), the registrations need to be reproduced on
+// the server. So, they are kept as the requests to send.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use parley::{
+    fontique::{FontInfo, SourceId, SourceInfo, SourceKind},
+    FontFamily, FontStyle, FontWeight, GenericFamily,
+};
+use vello::skrifa::{instance::NormalizedCoord, FontRef, MetadataProvider, Tag};
+
+use crate::{
+    cache::FONT_FILE_CACHE,
+    protocol::{FontFeature, Request},
+    text_layouter::{clear_text_metrics, fontface_to_weight_and_style},
+};
+
+pub(crate) static FONT_CTX: LazyLock<Mutex<parley::FontContext>> =
+    LazyLock::new(|| Mutex::new(parley::FontContext::new()));
+
+static FONT_REGISTRY: LazyLock<Mutex<FontRegistry>> =
+    LazyLock::new(|| Mutex::new(FontRegistry::default()));
+
+// The fonts read from the files specified by R's glyph API. The number of the
+// font files is usually small, so these are never evicted.
+static FONT_FILES: LazyLock<Mutex<FontFiles>> = LazyLock::new(|| Mutex::new(FontFiles::default()));
+
+// Since the server might be on a different machine, the fonts are referred to
+// by the ids calculated from their content instead of the paths.
+#[derive(Default)]
+struct FontFiles {
+    // The ids of the files read on this process
+    ids: HashMap<(String, u32), u64>,
+    fonts: HashMap<u64, parley::Font>,
+}
+
+fn font_file_id(data: &[u8], index: u32) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    index.hash(&mut hasher);
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Read the font file, and returns the id to get the font by `font_file()`.
+/// The data is cached, so the file is read only once.
+pub fn load_font_file(path: &str, index: u32) -> std::io::Result<u64> {
+    let key = (path.to_string(), index);
+    if let Some(id) = FONT_FILES.lock().unwrap().ids.get(&key) {
+        FONT_FILE_CACHE.hit();
+        return Ok(*id);
+    }
+    FONT_FILE_CACHE.miss();
+
+    // Note: the file is read without the lock because it might take time
+    let data = std::fs::read(std::fs::canonicalize(path)?)?;
+    let id = font_file_id(&data, index);
+    let mut font_files = FONT_FILES.lock().unwrap();
+    font_files.ids.insert(key, id);
+    font_files
+        .fonts
+        .entry(id)
+        .or_insert_with(|| parley::Font::new(data.into(), index));
+    Ok(id)
+}
+
+/// The font read by `load_font_file()` or registered by
+/// `register_font_file()`.
+pub fn font_file(id: u64) -> Option<parley::Font> {
+    FONT_FILES.lock().unwrap().fonts.get(&id).cloned()
+}
+
+/// Register the font read by `load_font_file()` on the other process.
+pub fn register_font_file(id: u64, index: u32, data: Vec<u8>) {
+    FONT_FILES
+        .lock()
+        .unwrap()
+        .fonts
+        .entry(id)
+        .or_insert_with(|| parley::Font::new(data.into(), index));
+}
+
+/// The request to reproduce the font read by `load_font_file()` on the other
+/// process. Returns None if the id is unknown.
+pub fn font_file_request(id: u64) -> Option<Request> {
+    let font = font_file(id)?;
+    Some(Request::RegisterFontFile {
+        id,
+        index: font.index,
+        data: font.data.as_ref().to_vec(),
+    })
+}
+
+// The synthesis and the presence of colour glyphs need to parse the tables of
+// the font, so they are cached per font. A font is identified by the id of its
+// data and the index in the collection. Like FONT_FILES, these are never
+// evicted.
+type FontKey = (u64, u32);
+type SynthesisKey = (FontKey, u32, (u8, Option<u32>));
+
+static GLYPH_SYNTHESES: LazyLock<Mutex<HashMap<SynthesisKey, GlyphSynthesis>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static COLOR_FONTS: LazyLock<Mutex<HashMap<FontKey, bool>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn font_key(font: &parley::Font) -> FontKey {
+    (font.data.id(), font.index)
+}
+
+fn synthesis_key(font: &parley::Font, weight: FontWeight, style: FontStyle) -> SynthesisKey {
+    let style = match style {
+        FontStyle::Normal => (0, None),
+        FontStyle::Italic => (1, None),
+        FontStyle::Oblique(angle) => (2, angle.map(f32::to_bits)),
+    };
+    (font_key(font), weight.value().to_bits(), style)
+}
+
+/// How to draw the glyphs of a font in the weight and the style that the font
+/// doesn't have.
+#[derive(Debug, Clone, Default)]
+pub struct GlyphSynthesis {
+    /// The normalized coordinates of the variation axes (e.g. `wght`)
+    pub coords: Vec<NormalizedCoord>,
+    /// The skew angle in degrees for a faux italic
+    pub skew: Option<f32>,
+    /// Whether to embolden the outlines for a faux bold
+    pub embolden: bool,
+}
+
+impl GlyphSynthesis {
+    /// The synthesis that parley chose for the run of a text layout.
+    pub fn from_run(run: &parley::Run<'_, peniko::Brush>) -> Self {
+        let synthesis = run.synthesis();
+        Self {
+            coords: run
+                .normalized_coords()
+                .iter()
+                .map(|coord| NormalizedCoord::from_bits(*coord))
+                .collect(),
+            skew: synthesis.skew(),
+            embolden: synthesis.embolden(),
+        }
+    }
+
+    /// The synthesis for the font file specified by R's glyph API. Since the
+    /// file is already chosen by the caller, this only fills the gap between
+    /// the file and the requested weight and style, in the same way as parley
+    /// does; the weight is mapped onto the `wght` axis if the font is a
+    /// variable font, otherwise the glyphs are emboldened.
+    pub fn for_font(font: &parley::Font, weight: FontWeight, style: FontStyle) -> Self {
+        let key = synthesis_key(font, weight, style);
+        if let Some(synthesis) = GLYPH_SYNTHESES.lock().unwrap().get(&key) {
+            return synthesis.clone();
+        }
+
+        let synthesis = Self::compute(font, weight, style);
+        GLYPH_SYNTHESES
+            .lock()
+            .unwrap()
+            .insert(key, synthesis.clone());
+        synthesis
+    }
+
+    fn compute(font: &parley::Font, weight: FontWeight, style: FontStyle) -> Self {
+        let source = SourceInfo::new(SourceId::new(), SourceKind::Memory(font.data.clone()));
+        let Some(info) = FontInfo::from_source(source, font.index) else {
+            return Self::default();
+        };
+        let synthesis = info.synthesis(info.stretch(), style, weight);
+
+        let coords = match FontRef::from_index(font.data.as_ref(), font.index) {
+            Ok(font_ref) if !synthesis.variation_settings().is_empty() => font_ref
+                .axes()
+                .location(synthesis.variation_settings().iter().copied())
+                .coords()
+                .to_vec(),
+            _ => Vec::new(),
+        };
+
+        Self {
+            coords,
+            skew: synthesis.skew(),
+            embolden: synthesis.embolden(),
+        }
+    }
+}
+
+/// Whether the font has colour glyphs, i.e. the COLR table (v0 or v1) or the
+/// bitmap tables (CBDT or sbix), which are typical for emoji fonts. vello
+/// draws such glyphs in their own colours, using the brush only for the
+/// foreground colour of COLR.
+pub fn has_color_glyphs(font: &parley::Font) -> bool {
+    let key = font_key(font);
+    if let Some(has_color) = COLOR_FONTS.lock().unwrap().get(&key) {
+        return *has_color;
+    }
+
+    let has_color = find_color_tables(font);
+    COLOR_FONTS.lock().unwrap().insert(key, has_color);
+    has_color
+}
+
+fn find_color_tables(font: &parley::Font) -> bool {
+    let Ok(font_ref) = FontRef::from_index(font.data.as_ref(), font.index) else {
+        return false;
+    };
+    let has_table = |tag: &[u8; 4]| font_ref.table_data(Tag::new(tag)).is_some();
+    (has_table(b"COLR") && has_table(b"CPAL")) || has_table(b"CBDT") || has_table(b"sbix")
+}
+
+/// Combine the OpenType features so that the ones in `overrides` take
+/// precedence over the ones in `base` with the same tags.
+pub fn merge_font_features(base: &[FontFeature], overrides: &[FontFeature]) -> Vec<FontFeature> {
+    base.iter()
+        .filter(|feature| !overrides.iter().any(|x| x.tag == feature.tag))
+        .chain(overrides)
+        .copied()
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum FontError {
+    InvalidFont(String),
+    InvalidFace(i32),
+    InvalidScript(String),
+    UnknownFamily(String),
+}
+
+impl std::fmt::Display for FontError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FontError::InvalidFont(family) => write!(f, "no font is found in the data of {family}"),
+            FontError::InvalidFace(face) => {
+                write!(f, "invalid font face: {face} (expected 1, 2, 3, or 4)")
+            }
+            FontError::InvalidScript(script) => write!(
+                f,
+                "invalid script: {script} (expected an ISO 15924 code, e.g. Hani)"
+            ),
+            FontError::UnknownFamily(family) => write!(f, "unknown font family: {family}"),
+        }
+    }
+}
+
+impl std::error::Error for FontError {}
+
+#[derive(Debug, Clone)]
+struct RegisteredFont {
+    // The family name in the font file, which can differ from R's one
+    family: String,
+    weight: FontWeight,
+    style: FontStyle,
+}
+
+#[derive(Debug, Default)]
+struct FontRegistry {
+    // The key is R's family name and the face (1: plain, 2: bold, 3: italic,
+    // 4: bold italic)
+    fonts: HashMap<(String, i32), RegisteredFont>,
+    // The families to try when a character is not found in the font, for any
+    // script
+    fallbacks: Vec<String>,
+    // The family used for the face 5 (symbol). If None, the generic math and
+    // serif families are used.
+    symbol: Option<String>,
+    // The requests to reproduce the registrations
+    history: Vec<Request>,
+}
+
+impl FontRegistry {
+    fn family_name<'a>(&'a self, family: &'a str) -> &'a str {
+        self.fonts
+            .get(&(family.to_string(), 1))
+            .map_or(family, |font| font.family.as_str())
+    }
+}
+
+fn to_face(weight: FontWeight, style: FontStyle) -> i32 {
+    let bold = weight >= FontWeight::SEMI_BOLD;
+    let italic = !matches!(style, FontStyle::Normal);
+    1 + bold as i32 + 2 * italic as i32
+}
+
+/// Register the font data as the face of the family. The family can be used
+/// as `par(family = ...)` in R. Returns the family name in the font data.
+pub fn register_font(family: &str, face: i32, data: Vec<u8>) -> Result<String, FontError> {
+    if !(1..=4).contains(&face) {
+        return Err(FontError::InvalidFace(face));
+    }
+
+    let font = {
+        let mut font_ctx = FONT_CTX.lock().unwrap();
+        let collection = &mut font_ctx.collection;
+        // Note: if the data is a font collection (e.g. .ttc), the first font
+        // is used.
+        let (family_id, fonts) = collection
+            .register_fonts(data.clone())
+            .into_iter()
+            .find(|(_, fonts)| !fonts.is_empty())
+            .ok_or_else(|| FontError::InvalidFont(family.to_string()))?;
+        let name = collection
+            .family_name(family_id)
+            .ok_or_else(|| FontError::InvalidFont(family.to_string()))?;
+        RegisteredFont {
+            family: name.to_string(),
+            weight: fonts[0].weight(),
+            style: fonts[0].style(),
+        }
+    };
+    let name = font.family.clone();
+
+    let mut registry = FONT_REGISTRY.lock().unwrap();
+    registry.fonts.insert((family.to_string(), face), font);
+    // The metrics of the family might change
+    clear_text_metrics();
+    registry.history.push(Request::RegisterFont {
+        family: family.to_string(),
+        face,
+        data,
+    });
+
+    Ok(name)
+}
+
+/// Set the families to try when a character is not found in the font. If
+/// `script` (an ISO 15924 code, e.g. `Hani`) is specified, the families are
+/// used only for the characters of the script; otherwise, for all characters.
+pub fn set_font_fallbacks(script: Option<&str>, families: Vec<String>) -> Result<(), FontError> {
+    let mut registry = FONT_REGISTRY.lock().unwrap();
+    let mut font_ctx = FONT_CTX.lock().unwrap();
+    let collection = &mut font_ctx.collection;
+
+    let mut family_ids = Vec::with_capacity(families.len());
+    for family in &families {
+        let name = registry.family_name(family);
+        let id = collection
+            .family_id(name)
+            .ok_or_else(|| FontError::UnknownFamily(family.to_string()))?;
+        family_ids.push(id);
+    }
+
+    match script {
+        Some(script) => {
+            if script.len() != 4 || !script.is_ascii() {
+                return Err(FontError::InvalidScript(script.to_string()));
+            }
+            collection.set_fallbacks(script, family_ids.into_iter());
+        }
+        None => {
+            let fallbacks = families
+                .iter()
+                .map(|family| registry.family_name(family).to_string())
+                .collect();
+            registry.fallbacks = fallbacks;
+        }
+    }
+
+    clear_text_metrics();
+    registry.history.push(Request::SetFontFallbacks {
+        script: script.map(|x| x.to_string()),
+        families,
+    });
+    Ok(())
+}
+
+/// Set the family used for the symbol font face (i.e. `fontface = 5`), which
+/// R uses for the Greek letters and the mathematical symbols of plotmath. The
+/// family can be either a registered one or a system one.
+pub fn set_symbol_font(family: &str) -> Result<(), FontError> {
+    let mut registry = FONT_REGISTRY.lock().unwrap();
+    let mut font_ctx = FONT_CTX.lock().unwrap();
+
+    let name = registry.family_name(family).to_string();
+    if font_ctx.collection.family_id(&name).is_none() {
+        return Err(FontError::UnknownFamily(family.to_string()));
+    }
+    registry.symbol = Some(name);
+
+    clear_text_metrics();
+    registry.history.push(Request::SetSymbolFont {
+        family: family.to_string(),
+    });
+    Ok(())
+}
+
+/// Apply the request created by `register_font()`, `set_font_fallbacks()`,
+/// `set_symbol_font()`, or `font_file_request()` on the other process. Returns
+/// None if the request is not about fonts.
+pub fn handle_font_request(request: Request) -> Option<Result<(), FontError>> {
+    match request {
+        Request::RegisterFontFile { id, index, data } => {
+            register_font_file(id, index, data);
+            Some(Ok(()))
+        }
+        Request::RegisterFont { family, face, data } => {
+            Some(register_font(&family, face, data).map(|_| ()))
+        }
+        Request::SetFontFallbacks { script, families } => {
+            Some(set_font_fallbacks(script.as_deref(), families))
+        }
+        Request::SetSymbolFont { family } => Some(set_symbol_font(&family)),
+        _ => None,
+    }
+}
+
+/// The requests to reproduce the registrations, skipping the first `skip`
+/// ones (i.e. the ones already sent).
+pub fn font_requests(skip: usize) -> Vec<Request> {
+    let registry = FONT_REGISTRY.lock().unwrap();
+    registry.history.iter().skip(skip).cloned().collect()
+}
+
+/// Resolve R's family and font face to the families to use and the weight and
+/// style to request.
+pub(crate) fn resolve_font(
+    family: &str,
+    fontface: i32,
+) -> (Vec<FontFamily<'static>>, FontWeight, FontStyle) {
+    let registry = FONT_REGISTRY.lock().unwrap();
+    let (weight, style) = fontface_to_weight_and_style(fontface);
+    let face = to_face(weight, style);
+
+    let (mut families, weight, style) = match registry.fonts.get(&(family.to_string(), face)) {
+        // The symbol font is used regardless of the family
+        _ if fontface == 5 => {
+            let families = match &registry.symbol {
+                Some(symbol) => vec![FontFamily::Named(Cow::Owned(symbol.clone()))],
+                None => vec![GenericFamily::Math.into(), GenericFamily::Serif.into()],
+            };
+            (families, weight, style)
+        }
+        // Use the exact font
+        Some(font) => (
+            vec![FontFamily::Named(Cow::Owned(font.family.clone()))],
+            font.weight,
+            font.style,
+        ),
+        None => {
+            let primary = match registry.fonts.get(&(family.to_string(), 1)) {
+                // The face is not registered, so it's synthesized from the
+                // plain one
+                Some(font) => FontFamily::Named(Cow::Owned(font.family.clone())),
+                None => match family {
+                    "" | "sans" => GenericFamily::SansSerif.into(),
+                    "serif" => GenericFamily::Serif.into(),
+                    "mono" => GenericFamily::Monospace.into(),
+                    _ => match FontFamily::parse(family) {
+                        Some(FontFamily::Named(name)) => {
+                            FontFamily::Named(Cow::Owned(name.into_owned()))
+                        }
+                        Some(FontFamily::Generic(generic)) => generic.into(),
+                        None => GenericFamily::SansSerif.into(),
+                    },
+                },
+            };
+            (vec![primary], weight, style)
+        }
+    };
+
+    families.extend(
+        registry
+            .fallbacks
+            .iter()
+            .map(|family| FontFamily::Named(Cow::Owned(family.clone()))),
+    );
+    // The emoji font is the last resort. parley adds it for the characters
+    // that are emoji by default, but not for the ones that become emoji by a
+    // variation selector (e.g. U+2764 U+FE0F).
+    families.push(GenericFamily::Emoji.into());
+    (families, weight, style)
+}
@@ -1,6 +1,12 @@
+pub mod cache;
+pub mod discovery;
 pub mod ffi;
+pub mod fonts;
 pub mod protocol;
+pub mod queue;
+pub mod recording;
 pub mod text_layouter;
+pub mod transport;
 
 #[cfg(feature = "winit")]
 pub mod winit_app;
@@ -1,5 +1,35 @@
 use serde::{Deserialize, Serialize};
 
+/// The version of the protocol between the R session and vellogd-server.
+///
+/// `Request` and `Response` are serialized by serde, so the messages are not
+/// compatible between different versions of the enums. This MUST be
+/// incremented whenever they are changed.
+pub const PROTOCOL_VERSION: u32 = 14;
+
+/// The features the server supports. These are sent to the client on the
+/// handshake. These are strings instead of an enum so that a client can read
+/// the capabilities of a server of a different version.
+pub const SERVER_CAPABILITIES: &[&str] = &[
+    "shapes",
+    "batch",
+    "text",
+    "raster",
+    "glyph",
+    "clip",
+    "pattern",
+    "mode",
+    "image_cache",
+    "multi_device",
+    "shutdown",
+    "queue_stats",
+    "fonts",
+    "rich_text",
+    "font_features",
+    "text_halo",
+    "font_files",
+];
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum FillBrush {
     /// color
@@ -20,11 +50,99 @@ pub struct StrokeParams {
     pub stroke: kurbo::Stroke,
 }
 
+/// An OpenType feature setting, e.g. `tnum` = 1 for the tabular figures, or
+/// `liga` = 0 to disable the ligatures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct FontFeature {
+    pub tag: [u8; 4],
+    pub value: u16,
+}
+
+impl FontFeature {
+    /// Returns None if the tag is not four ASCII characters.
+    pub fn new(tag: &str, value: u16) -> Option<Self> {
+        let tag: [u8; 4] = tag.as_bytes().try_into().ok()?;
+        if !tag.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+            return None;
+        }
+        Some(Self { tag, value })
+    }
+}
+
+impl From<FontFeature> for parley::FontFeature {
+    fn from(value: FontFeature) -> Self {
+        parley::FontFeature {
+            tag: parley::swash::tag_from_bytes(&value.tag),
+            value: value.value,
+        }
+    }
+}
+
+/// A part of a [RichText] with its own style. The fields of `None` inherit the
+/// ones of the `RichText`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TextSpan {
+    pub text: String,
+    pub family: Option<String>,
+    pub face: Option<i32>,
+    pub size: Option<f32>,
+    pub color: Option<peniko::Color>,
+    /// These override the ones of the `RichText` with the same tags.
+    pub features: Vec<FontFeature>,
+}
+
+/// The alignment of the lines of a [RichText] within its width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+    /// The last line of a paragraph is left-aligned.
+    Justify,
+}
+
+impl From<TextAlign> for parley::Alignment {
+    fn from(value: TextAlign) -> Self {
+        match value {
+            TextAlign::Left => parley::Alignment::Start,
+            TextAlign::Center => parley::Alignment::Middle,
+            TextAlign::Right => parley::Alignment::End,
+            TextAlign::Justify => parley::Alignment::Justified,
+        }
+    }
+}
+
+/// A stroke drawn along the outlines of the glyphs under the fill, which keeps
+/// the text readable on a busy background (e.g. a map). `width` is the
+/// distance the halo extends beyond the outlines.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct TextHalo {
+    pub color: peniko::Color,
+    pub width: f32,
+}
+
+/// A text that consists of multiple styled spans. See
+/// `text_layouter::build_rich_layout()`.
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct GlyphParams<'a> {
-    pub fontfile: &'a str,
-    pub index: u32,
-    pub family: &'a str,
+pub struct RichText {
+    pub spans: Vec<TextSpan>,
+    pub family: String,
+    pub face: i32,
+    pub size: f32,
+    pub color: peniko::Color,
+    pub lineheight: f32,
+    pub features: Vec<FontFeature>,
+    /// The lines are wrapped at this width, if specified.
+    pub max_width: Option<f32>,
+    pub align: TextAlign,
+    pub halo: Option<TextHalo>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GlyphParams {
+    /// The id of the font file (c.f. `fonts::load_font_file()`)
+    pub font_id: u64,
+    pub family: String,
     pub weight_raw: f32, // TODO: parley::FontWeight is not serializable
     pub style_raw: u32,  // TODO: parley::FontStyle is not serializable
     pub angle: f64,
@@ -32,11 +150,9 @@ pub struct GlyphParams<'a> {
     pub color: peniko::Color,
 }
 
-impl<'a> GlyphParams<'a> {
-    pub fn font(&self) -> std::io::Result<parley::Font> {
-        let p = std::fs::canonicalize(self.fontfile).unwrap();
-        let data = std::fs::read(p)?;
-        Ok(parley::Font::new(data.into(), self.index))
+impl GlyphParams {
+    pub fn font(&self) -> Option<parley::Font> {
+        crate::fonts::font_file(self.font_id)
     }
 
     pub fn weight(&self) -> parley::FontWeight {
@@ -55,18 +171,35 @@ impl<'a> GlyphParams<'a> {
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Request {
-    ConnectionReady,
-    NewWindow,
+    // Note: ConnectionReady must be the first variant so that the handshake
+    // can be decoded even if the versions of the server and the client
+    // differ.
+    ConnectionReady {
+        protocol_version: u32,
+    },
+    /// Open the window of the device. `device_id` is the one assigned by the
+    /// server on the handshake.
+    NewWindow {
+        device_id: u32,
+        size: (u32, u32),
+    },
+    // Wake up the event loop to check if the window needs to be redrawn
     RedrawWindow,
     CloseWindow,
+    /// Close all the windows and exit the server process. The server responds
+    /// `Response::Done` before exiting.
+    Shutdown,
     NewPage,
 
     SaveAsPng {
         filename: String,
     },
 
+    /// Start drawing a tile of a tiling pattern. The subsequent draw requests
+    /// are drawn on a temporary scene until `SaveAsTile` is sent.
     PrepareForSaveAsTile {
-        height: u32,
+        x: f64,
+        y: f64,
     },
     SaveAsTile {
         width: f64,
@@ -78,6 +211,32 @@ pub enum Request {
         color: u32,
     },
     GetWindowSizes,
+    /// Register the font data as the face of the family. See
+    /// `fonts::register_font()`.
+    RegisterFont {
+        family: String,
+        face: i32,
+        data: Vec<u8>,
+    },
+    /// See `fonts::set_font_fallbacks()`.
+    SetFontFallbacks {
+        script: Option<String>,
+        families: Vec<String>,
+    },
+    /// See `fonts::set_symbol_font()`.
+    SetSymbolFont {
+        family: String,
+    },
+    /// Register the font file used by `DrawGlyph`. See
+    /// `fonts::font_file_request()`.
+    RegisterFontFile {
+        id: u64,
+        index: u32,
+        data: Vec<u8>,
+    },
+    /// Get the statistics of the queue between the receiver of the requests
+    /// and the event loop.
+    GetQueueStats,
     DrawCircle {
         center: kurbo::Point,
         radius: f64,
@@ -104,6 +263,7 @@ pub enum Request {
         fill_params: Option<FillParams>,
         stroke_params: Option<StrokeParams>,
     },
+    /// `angle` is in degrees.
     DrawText {
         pos: kurbo::Point,
         text: String,
@@ -114,31 +274,371 @@ pub enum Request {
         face: i32,
         angle: f32,
         hadj: f32,
+        features: Vec<FontFeature>,
+    },
+    /// `hadj` and `vadj` are the position of `pos` relative to the box of the
+    /// text (0 means the left or the bottom edge, 1 means the right or the top
+    /// edge). `angle` is in degrees.
+    DrawRichText {
+        pos: kurbo::Point,
+        text: RichText,
+        angle: f32,
+        hadj: f32,
+        vadj: f32,
+    },
+    /// Register the RGBA pixels of an image so that `DrawRaster` can refer to
+    /// it by `id`. The registered images are cleared on `NewPage`.
+    RegisterImage {
+        id: u64,
+        data: ImageBytes,
+        width: u32,
+        height: u32,
+    },
+    /// `image_id` is the id of the image registered by `RegisterImage`. `pos`
+    /// is the bottom-left corner, and `angle` is in degrees.
+    DrawRaster {
+        image_id: u64,
+        alpha: u8,
+        pos: kurbo::Point,
+        size: (f64, f64),
+        angle: f64,
+    },
+    DrawGlyph {
+        glyph_ids: Vec<u32>,
+        x: Vec<f64>,
+        y: Vec<f64>,
+        glyph_params: GlyphParams,
+    },
+    Clip {
+        p0: kurbo::Point,
+        p1: kurbo::Point,
+    },
+    RegisterGradient {
+        gradient: peniko::Gradient,
+    },
+    /// Corresponds to the mode() API of R's graphics device; `drawing` is true
+    /// while the R session is drawing.
+    SetDrawingMode {
+        drawing: bool,
     },
     AddLottieAnimation {
         filename: String,
     },
+
+    /// Multiple draw requests sent at once to reduce the number of IPC
+    /// communications.
+    Batch(Vec<Request>),
+}
+
+impl Request {
+    /// Returns true if the request only modifies the scene and doesn't expect
+    /// any response. Such requests can be sent in a batch.
+    pub fn can_be_batched(&self) -> bool {
+        matches!(
+            self,
+            Request::DrawCircle { .. }
+                | Request::DrawLine { .. }
+                | Request::DrawPolyline { .. }
+                | Request::DrawPolygon { .. }
+                | Request::DrawRect { .. }
+                | Request::DrawText { .. }
+                | Request::DrawRichText { .. }
+                | Request::RegisterImage { .. }
+                | Request::DrawRaster { .. }
+                | Request::DrawGlyph { .. }
+                | Request::Clip { .. }
+                | Request::PrepareForSaveAsTile { .. }
+                | Request::SetDrawingMode { .. }
+        )
+    }
+
+    /// Returns the capabilities (c.f. `SERVER_CAPABILITIES`) the server needs
+    /// to handle the request. The basic shapes need nothing.
+    pub fn required_capabilities(&self) -> Vec<&'static str> {
+        match self {
+            Request::Shutdown => vec!["shutdown"],
+            Request::PrepareForSaveAsTile { .. }
+            | Request::SaveAsTile { .. }
+            | Request::RegisterGradient { .. } => vec!["pattern"],
+            Request::RegisterFont { .. }
+            | Request::SetFontFallbacks { .. }
+            | Request::SetSymbolFont { .. } => vec!["fonts"],
+            Request::RegisterFontFile { .. } => vec!["glyph", "font_files"],
+            Request::GetQueueStats => vec!["queue_stats"],
+            Request::DrawText { features, .. } => {
+                let mut capabilities = vec!["text"];
+                if !features.is_empty() {
+                    capabilities.push("font_features");
+                }
+                capabilities
+            }
+            Request::DrawRichText { text, .. } => {
+                let mut capabilities = vec!["rich_text"];
+                if !text.features.is_empty() || text.spans.iter().any(|x| !x.features.is_empty()) {
+                    capabilities.push("font_features");
+                }
+                if text.halo.is_some() {
+                    capabilities.push("text_halo");
+                }
+                capabilities
+            }
+            Request::RegisterImage { .. } => vec!["raster", "image_cache"],
+            Request::DrawRaster { .. } => vec!["raster"],
+            Request::DrawGlyph { .. } => vec!["glyph"],
+            Request::Clip { .. } => vec!["clip"],
+            Request::SetDrawingMode { .. } => vec!["mode"],
+            Request::Batch(_) => vec!["batch"],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The fill of the request, if the request is a shape that can be filled.
+    pub fn fill_params_mut(&mut self) -> Option<&mut Option<FillParams>> {
+        match self {
+            Request::DrawCircle { fill_params, .. }
+            | Request::DrawPolygon { fill_params, .. }
+            | Request::DrawRect { fill_params, .. } => Some(fill_params),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the server sends a response to the request.
+    pub fn expects_response(&self) -> bool {
+        matches!(
+            self,
+            Request::GetWindowSizes
+                | Request::GetQueueStats
+                | Request::SaveAsPng { .. }
+                | Request::SaveAsTile { .. }
+                | Request::RegisterGradient { .. }
+                | Request::AddLottieAnimation { .. }
+                | Request::Shutdown
+        )
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Response {
-    WindowSizes { width: u32, height: u32 },
-    Connect { server_name: String },
-    PatternRegistered { index: usize },
+    // Note: Connect must be the first variant so that the handshake can be
+    // decoded even if the versions of the server and the client differ.
+    Connect {
+        server_name: String,
+        protocol_version: u32,
+        capabilities: Vec<String>,
+        /// The id of the device assigned to this connection
+        device_id: u32,
+        /// The address other devices can attach to, if the server is
+        /// listening
+        attach_address: Option<String>,
+    },
+    WindowSizes {
+        width: u32,
+        height: u32,
+    },
+    PatternRegistered {
+        index: usize,
+    },
+    QueueStats {
+        stats: QueueStats,
+    },
+    /// A response to a request that has nothing to return.
+    Done,
+    Error {
+        kind: ErrorKind,
+        message: String,
+    },
+}
+
+/// The statistics of the queue of the events waiting for the event loop.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct QueueStats {
+    /// The number of the events waiting now
+    pub depth: u64,
+    pub capacity: u64,
+    /// The maximum depth so far
+    pub peak: u64,
+    /// How many times the producer waited because the queue was full
+    pub blocked: u64,
+    /// How many wake-ups were dropped because the event loop was busy
+    pub coalesced: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ErrorKind {
+    Io,
+    Font,
+    Lottie,
+    Render,
+    InvalidRequest,
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            ErrorKind::Io => "I/O error",
+            ErrorKind::Font => "font error",
+            ErrorKind::Lottie => "Lottie error",
+            ErrorKind::Render => "rendering error",
+            ErrorKind::InvalidRequest => "invalid request",
+        };
+        f.write_str(kind)
+    }
+}
+
+/// An error that happens while handling a request. This is sent to the client
+/// as `Response::Error`.
+#[derive(Debug, Clone)]
+pub struct AppError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl AppError {
+    pub fn new(kind: ErrorKind, message: impl ToString) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<std::io::Error> for AppError {
+    fn from(value: std::io::Error) -> Self {
+        Self::new(ErrorKind::Io, value)
+    }
+}
+
+impl From<vello::Error> for AppError {
+    fn from(value: vello::Error) -> Self {
+        Self::new(ErrorKind::Render, value)
+    }
+}
+
+impl From<AppError> for Response {
+    fn from(value: AppError) -> Self {
+        Response::Error {
+            kind: value.kind,
+            message: value.message,
+        }
+    }
+}
+
+impl From<Result<(), AppError>> for Response {
+    fn from(value: Result<(), AppError>) -> Self {
+        match value {
+            Ok(_) => Response::Done,
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// Note: I'm hoping to use no copy here. However, this raster might
+//    be drawn after the raster() Graphics API call. There's no
+//    guarantee that this still exists on R's memory at the time.
+//    So, this needs to be kept on Rust's memory.
+pub fn convert_to_image(
+    raster: &[u8],
+    width: usize,
+    height: usize,
+    extend: peniko::Extend,
+    alpha: u8,
+) -> peniko::Image {
+    let raster_blob = peniko::Blob::new(std::sync::Arc::new(raster.to_vec()));
+    peniko::Image {
+        data: raster_blob,
+        format: peniko::Format::Rgba8,
+        width: width as u32,
+        height: height as u32,
+        extend,
+        alpha,
+    }
+}
+
+/// The pixel data of an image.
+///
+/// Raster images can be megabytes, so they are passed via shared memory
+/// instead of being copied into the message when the transport is
+/// ipc_channel. Note that `Shared` can be (de)serialized only by ipc_channel.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum ImageBytes {
+    Shared(ipc_channel::ipc::IpcSharedMemory),
+    Inline(Vec<u8>),
+}
+
+// A wrapper to use the shared memory as the data of peniko::Blob without
+// copying.
+struct SharedBytes(ipc_channel::ipc::IpcSharedMemory);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl ImageBytes {
+    pub fn new(data: &[u8], shared: bool) -> Self {
+        if shared {
+            Self::Shared(ipc_channel::ipc::IpcSharedMemory::from_bytes(data))
+        } else {
+            Self::Inline(data.to_vec())
+        }
+    }
+
+    /// Copy the data into `Inline` so that it can be serialized by other than
+    /// ipc_channel (e.g. for recording).
+    pub fn to_inline(&self) -> Self {
+        match self {
+            Self::Shared(data) => Self::Inline(data.to_vec()),
+            Self::Inline(data) => Self::Inline(data.clone()),
+        }
+    }
+
+    pub fn into_blob(self) -> peniko::Blob<u8> {
+        match self {
+            Self::Shared(data) => peniko::Blob::new(std::sync::Arc::new(SharedBytes(data))),
+            Self::Inline(data) => peniko::Blob::new(std::sync::Arc::new(data)),
+        }
+    }
+}
+
+/// Calculate the id of an image from its content so that the same image is
+/// registered only once.
+pub fn image_id(data: &[u8], width: u32, height: u32) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (width, height).hash(&mut hasher);
+    data.hash(&mut hasher);
+    hasher.finish()
 }
 
 pub trait AppResponseRelay {
     fn respond(&self, response: Response);
 }
 
+// Note: if the response cannot be sent, it means the client is gone. There's
+// nothing to do other than reporting it.
+
 impl AppResponseRelay for std::sync::mpsc::Sender<Response> {
     fn respond(&self, response: Response) {
-        self.send(response).unwrap();
+        if let Err(e) = self.send(response) {
+            log::warn!("failed to send a response: {e}");
+        }
     }
 }
 
-impl AppResponseRelay for ipc_channel::ipc::IpcSender<Response> {
+impl AppResponseRelay for crate::transport::BoxedSender<Response> {
     fn respond(&self, response: Response) {
-        self.send(response).unwrap();
+        if let Err(e) = self.send_message(response) {
+            log::warn!("failed to send a response: {e}");
+        }
     }
 }
@@ -0,0 +1,101 @@
+// Bound the number of the events waiting for the event loop. Without this, a
+// fast R loop can queue requests far faster than the window can process them,
+// and the memory grows without limit while the window lags.
+//
+// The events themselves are carried by the existing channel (e.g. winit's
+// EventLoopProxy); this only counts them. A producer takes a slot before
+// sending an event and the consumer returns it after processing the event, so
+// a producer blocks while the queue is full.
+
+use std::sync::{Condvar, Mutex};
+
+use crate::protocol::QueueStats;
+
+/// The default of the maximum number of the events waiting for the event loop.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+struct QueueState {
+    capacity: usize,
+    depth: usize,
+    peak: usize,
+    blocked: u64,
+    coalesced: u64,
+}
+
+#[derive(Debug)]
+pub struct EventQueue {
+    state: Mutex<QueueState>,
+    released: Condvar,
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                capacity: capacity.max(1),
+                depth: 0,
+                peak: 0,
+                blocked: 0,
+                coalesced: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        self.state.lock().unwrap().capacity = capacity.max(1);
+        // The producers might be able to proceed with the new capacity
+        self.released.notify_all();
+    }
+
+    /// Take a slot. This blocks while the queue is full.
+    pub fn acquire(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.depth >= state.capacity {
+            state.blocked += 1;
+            state = self
+                .released
+                .wait_while(state, |state| state.depth >= state.capacity)
+                .unwrap();
+        }
+        state.depth += 1;
+        state.peak = state.peak.max(state.depth);
+    }
+
+    /// Take a slot only if no event is waiting. This is for the events that
+    /// just wake up the event loop; if any event is pending, the event loop
+    /// wakes up anyway, so the event can be dropped.
+    ///
+    /// Note: this never blocks because a wake-up can be sent while the scene
+    /// is locked, which the event loop might be waiting for.
+    pub fn acquire_if_idle(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.depth > 0 {
+            state.coalesced += 1;
+            return false;
+        }
+        state.depth += 1;
+        state.peak = state.peak.max(state.depth);
+        true
+    }
+
+    /// Return the slot. This must be called after the event is processed (or
+    /// failed to be sent).
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.depth = state.depth.saturating_sub(1);
+        self.released.notify_one();
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            depth: state.depth as u64,
+            capacity: state.capacity as u64,
+            peak: state.peak as u64,
+            blocked: state.blocked,
+            coalesced: state.coalesced,
+        }
+    }
+}
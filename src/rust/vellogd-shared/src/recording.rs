@@ -0,0 +1,275 @@
+// Record the requests sent from the R session to a file, and read them back.
+//
+// There are two formats:
+//
+// - binary: the magic bytes followed by the length-prefixed (u32, little
+//   endian) bincode-serialized header and entries. This is compact.
+// - JSON: JSON Lines; the first line is the header and the rest are the
+//   entries. This is human-readable, but the file size is large.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    protocol::{ImageBytes, Request, PROTOCOL_VERSION},
+    transport::MAX_MESSAGE_SIZE,
+};
+
+const MAGIC: &[u8; 8] = b"VELLOGDR";
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(std::io::Error),
+    Binary(bincode::Error),
+    Json(serde_json::Error),
+    VersionMismatch(u32),
+}
+
+impl std::fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordingError::Io(e) => write!(f, "I/O error: {e}"),
+            RecordingError::Binary(e) => write!(f, "invalid binary recording: {e}"),
+            RecordingError::Json(e) => write!(f, "invalid JSON recording: {e}"),
+            RecordingError::VersionMismatch(version) => write!(
+                f,
+                "the recording uses protocol version {version}, but this uses {PROTOCOL_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+impl From<std::io::Error> for RecordingError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<bincode::Error> for RecordingError {
+    fn from(value: bincode::Error) -> Self {
+        Self::Binary(value)
+    }
+}
+
+impl From<serde_json::Error> for RecordingError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingFormat {
+    Binary,
+    Json,
+}
+
+impl RecordingFormat {
+    /// JSON if the extension is `.json` or `.jsonl`, otherwise binary.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension().and_then(|x| x.to_str()) {
+            Some("json" | "jsonl") => Self::Json,
+            _ => Self::Binary,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RecordingHeader {
+    pub protocol_version: u32,
+    /// The sizes of the device when the recording started
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RecordedRequest {
+    /// The elapsed time since the recording started, in microseconds
+    pub timestamp_us: u64,
+    pub request: Request,
+}
+
+// Note: this is the same as RecordedRequest except that this doesn't require
+// the ownership of the request.
+#[derive(Serialize)]
+struct RecordedRequestRef<'a> {
+    timestamp_us: u64,
+    request: &'a Request,
+}
+
+pub struct Recorder {
+    writer: BufWriter<File>,
+    format: RecordingFormat,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(
+        path: impl AsRef<Path>,
+        format: RecordingFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, RecordingError> {
+        let writer = BufWriter::new(File::create(path)?);
+        let mut recorder = Self {
+            writer,
+            format,
+            start: Instant::now(),
+        };
+
+        let header = RecordingHeader {
+            protocol_version: PROTOCOL_VERSION,
+            width,
+            height,
+        };
+        if format == RecordingFormat::Binary {
+            recorder.writer.write_all(MAGIC)?;
+        }
+        recorder.write_entry(&header)?;
+
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, request: &Request) -> Result<(), RecordingError> {
+        // The shared memory can be serialized only by ipc_channel, so the data
+        // needs to be copied.
+        let inlined = match request {
+            Request::RegisterImage {
+                id,
+                data: data @ ImageBytes::Shared(_),
+                width,
+                height,
+            } => Some(Request::RegisterImage {
+                id: *id,
+                data: data.to_inline(),
+                width: *width,
+                height: *height,
+            }),
+            _ => None,
+        };
+        let request = inlined.as_ref().unwrap_or(request);
+
+        let entry = RecordedRequestRef {
+            timestamp_us: self.start.elapsed().as_micros() as u64,
+            request,
+        };
+        self.write_entry(&entry)
+    }
+
+    pub fn flush(&mut self) -> Result<(), RecordingError> {
+        Ok(self.writer.flush()?)
+    }
+
+    fn write_entry<T: Serialize>(&mut self, entry: &T) -> Result<(), RecordingError> {
+        match self.format {
+            RecordingFormat::Binary => {
+                let data = bincode::serialize(entry)?;
+                check_entry_size(data.len())?;
+                self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+                self.writer.write_all(&data)?;
+            }
+            RecordingFormat::Json => {
+                serde_json::to_writer(&mut self.writer, entry)?;
+                self.writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// An entry is limited to the same size as a message so that a broken file
+// cannot make the reader allocate an arbitrary amount of memory.
+fn check_entry_size(len: usize) -> Result<(), RecordingError> {
+    if len > MAX_MESSAGE_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "the entry is too large ({len} bytes while the limit is {MAX_MESSAGE_SIZE} bytes)"
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Read a recording file. The format is detected from the content.
+pub fn read_recording(
+    path: impl AsRef<Path>,
+) -> Result<(RecordingHeader, Vec<RecordedRequest>), RecordingError> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let is_binary = reader.fill_buf()?.starts_with(MAGIC);
+    let (header, entries) = if is_binary {
+        reader.consume(MAGIC.len());
+        read_binary(reader)?
+    } else {
+        read_json(reader)?
+    };
+
+    if header.protocol_version != PROTOCOL_VERSION {
+        return Err(RecordingError::VersionMismatch(header.protocol_version));
+    }
+
+    Ok((header, entries))
+}
+
+fn read_binary(
+    mut reader: impl BufRead,
+) -> Result<(RecordingHeader, Vec<RecordedRequest>), RecordingError> {
+    fn read_entry<T: serde::de::DeserializeOwned>(
+        reader: &mut impl BufRead,
+    ) -> Result<Option<T>, RecordingError> {
+        // EOF
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        check_entry_size(len)?;
+        let mut data = vec![0u8; len];
+        reader.read_exact(&mut data)?;
+        Ok(Some(bincode::deserialize(&data)?))
+    }
+
+    let header: RecordingHeader = read_entry(&mut reader)?.ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "no header is found")
+    })?;
+
+    let mut entries = Vec::new();
+    while let Some(entry) = read_entry(&mut reader)? {
+        entries.push(entry);
+    }
+
+    Ok((header, entries))
+}
+
+fn read_json(
+    reader: impl BufRead,
+) -> Result<(RecordingHeader, Vec<RecordedRequest>), RecordingError> {
+    let mut lines = reader.lines();
+
+    let header_line = lines.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "no header is found")
+    })??;
+    let header: RecordingHeader = serde_json::from_str(&header_line)?;
+
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+
+    Ok((header, entries))
+}
@@ -1,45 +1,114 @@
-use std::sync::{LazyLock, Mutex};
+use std::{
+    borrow::Cow,
+    num::NonZeroUsize,
+    sync::{LazyLock, Mutex},
+};
 
-use crate::ffi::R_GE_gcontext;
+use kurbo::Shape;
+use lru::LruCache;
+use parley::swash::text::{BidiClass, Codepoint};
+use vello::skrifa::{
+    instance::{NormalizedCoord, Size},
+    outline::{DrawSettings, OutlinePen},
+    FontRef, GlyphId, MetadataProvider,
+};
 
+use crate::{
+    cache::TEXT_METRIC_CACHE,
+    ffi::R_GE_gcontext,
+    fonts::{merge_font_features, resolve_font, GlyphSynthesis, FONT_CTX},
+    protocol::{FontFeature, RichText},
+};
+
+// The number of the text metrics to keep
+const TEXT_METRICS_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(4096).unwrap();
+
+static LAYOUT_CTX: LazyLock<Mutex<parley::LayoutContext<peniko::Brush>>> =
+    LazyLock::new(|| Mutex::new(parley::LayoutContext::new()));
+
+// The locale of the process (e.g. "ja-JP"), which is used to choose the
+// fallback font for the characters shared by multiple languages (e.g. Han
+// characters are drawn differently in Japanese and Chinese).
+static LOCALE: LazyLock<Option<String>> = LazyLock::new(|| {
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .into_iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|x| !x.is_empty())?;
+    // e.g. "ja_JP.UTF-8" -> "ja-JP"
+    let locale = locale.split(['.', '@']).next()?.replace('_', "-");
+    match locale.as_str() {
+        "" | "C" | "POSIX" => None,
+        _ => Some(locale),
+    }
+});
+
+static TEXT_METRICS: LazyLock<Mutex<LruCache<TextMetricKey, TextMetric>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(TEXT_METRICS_CACHE_SIZE)));
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TextMetricKey {
+    text: String,
+    family: String,
+    fontface: i32,
+    // f32 is not hashable, so the bits are used
+    size: u32,
+    lineheight: u32,
+    features: Vec<FontFeature>,
+}
+
+/// Forget the cached metrics. This needs to be called when the fonts change.
+pub(crate) fn clear_text_metrics() {
+    TEXT_METRICS.lock().unwrap().clear();
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct TextMetric {
     pub ascent: f64,
     pub descent: f64,
     pub width: f64,
 }
 
-static FONT_CTX: LazyLock<Mutex<parley::FontContext>> =
-    LazyLock::new(|| Mutex::new(parley::FontContext::new()));
-
 pub trait TextLayouter {
     fn layout_mut(&mut self) -> &mut parley::Layout<peniko::Brush>;
     fn layout_ref(&self) -> &parley::Layout<peniko::Brush>;
 
+    /// The OpenType features applied to the text measured by
+    /// [TextLayouter::measure_text]. These must be the same as the ones the
+    /// text is drawn with.
+    fn font_features(&self) -> &[FontFeature] {
+        &[]
+    }
+
     fn build_layout(
         &mut self,
         text: impl AsRef<str>,
         family: impl AsRef<str>,
-        weight: parley::FontWeight,
-        style: parley::FontStyle,
+        fontface: i32,
         size: f32,
         lineheight: f32,
+        features: &[FontFeature],
     ) {
+        let text = isolate_rtl_paragraphs(text.as_ref());
         let text = text.as_ref();
+        // Note: this needs to be done before locking FONT_CTX
+        let (families, weight, style) = resolve_font(family.as_ref(), fontface);
         let mut font_ctx = FONT_CTX.lock().unwrap();
         // Note: parley is probably a little bit overkill, but it seems
         // this is the only interface.
-        let mut layout_ctx: parley::LayoutContext<peniko::Brush> = parley::LayoutContext::new();
+        let mut layout_ctx = LAYOUT_CTX.lock().unwrap();
         let mut layout_builder = layout_ctx.ranged_builder(&mut font_ctx, text, 1.0);
         // TODO: should scale be configurable?
         layout_builder.push_default(parley::StyleProperty::FontSize(size));
         layout_builder.push_default(parley::StyleProperty::LineHeight(lineheight));
 
-        let family = parley::FontFamily::parse(family.as_ref())
-            .unwrap_or(parley::GenericFamily::SansSerif.into());
-        layout_builder.push_default(family);
+        layout_builder.push_default(parley::StyleProperty::FontStack(parley::FontStack::List(
+            Cow::Owned(families),
+        )));
 
         layout_builder.push_default(parley::StyleProperty::FontWeight(weight));
         layout_builder.push_default(parley::StyleProperty::FontStyle(style));
+        layout_builder.push_default(parley::StyleProperty::Locale(LOCALE.as_deref()));
+        layout_builder.push_default(font_features_property(features));
 
         // TODO: use build_into() to reuse a Layout?
         let layout = self.layout_mut();
@@ -52,47 +121,369 @@ pub trait TextLayouter {
     }
 
     fn get_text_width<T: AsRef<str>>(&mut self, text: T, gc: R_GE_gcontext) -> f64 {
-        let family = unsafe {
-            std::ffi::CStr::from_ptr(gc.fontfamily.as_ptr())
-                .to_str()
-                .unwrap_or("Arial")
-        }
-        .to_string();
-        let size = gc.cex * gc.ps;
-        let (weight, style) = fontface_to_weight_and_style(gc.fontface);
-        self.build_layout(text, &family, weight, style, size as _, gc.lineheight as _);
-        self.layout_ref().width() as _
+        self.measure_text(text.as_ref(), gc).width
     }
 
     fn get_char_metric(&mut self, c: char, gc: R_GE_gcontext) -> TextMetric {
+        // R asks the metric of 0 to get the font-wide metrics, for which the
+        // ones of 'M' are used like other devices do.
+        let c = if c == '\0' { 'M' } else { c };
+        self.measure_text(&c.to_string(), gc)
+    }
+
+    // R asks the metrics of the same text (e.g. the tick labels) over and over
+    // again, so the results are cached.
+    //
+    // The ascent and the descent are the ones of the ink (i.e. the outlines of
+    // the glyphs), not the ones of the font, as Cairo-based devices do. R uses
+    // the metrics of each character to place the superscripts, the fractions,
+    // and the radicals of plotmath.
+    fn measure_text(&mut self, text: &str, gc: R_GE_gcontext) -> TextMetric {
         let family = unsafe {
             std::ffi::CStr::from_ptr(gc.fontfamily.as_ptr())
                 .to_str()
                 .unwrap_or("Arial")
         }
         .to_string();
-        let size = gc.cex * gc.ps;
-        let text = c.to_string();
-        let (weight, style) = fontface_to_weight_and_style(gc.fontface);
-        self.build_layout(text, &family, weight, style, size as _, gc.lineheight as _);
+        let size = (gc.cex * gc.ps) as f32;
+        let lineheight = gc.lineheight as f32;
+
+        let key = TextMetricKey {
+            text: text.to_string(),
+            family,
+            fontface: gc.fontface,
+            size: size.to_bits(),
+            lineheight: lineheight.to_bits(),
+            features: self.font_features().to_vec(),
+        };
+        if let Some(metric) = TEXT_METRICS.lock().unwrap().get(&key) {
+            TEXT_METRIC_CACHE.hit();
+            return *metric;
+        }
+        TEXT_METRIC_CACHE.miss();
+
+        self.build_layout(
+            text,
+            &key.family,
+            gc.fontface,
+            size,
+            lineheight,
+            &key.features,
+        );
         let layout_ref = self.layout_ref();
-        let line = layout_ref.lines().next();
-        match line {
-            Some(line) => {
-                let metrics = line.metrics();
-                TextMetric {
-                    ascent: metrics.ascent as _,
-                    descent: metrics.descent as _,
-                    width: layout_ref.width() as _, // TOOD: should this be run.metrics().width of the first char?
+        let (ascent, descent) = ink_extents(layout_ref);
+        let metric = TextMetric {
+            ascent,
+            descent,
+            // Note: the trailing whitespace counts, e.g. the width of " " is not 0
+            width: layout_ref.full_width() as _,
+        };
+
+        TEXT_METRICS.lock().unwrap().put(key, metric);
+        metric
+    }
+}
+
+fn font_features_property(features: &[FontFeature]) -> parley::StyleProperty<'_, peniko::Brush> {
+    let features = features.iter().map(|x| (*x).into()).collect::<Vec<_>>();
+    parley::StyleProperty::FontFeatures(parley::FontSettings::List(Cow::Owned(features)))
+}
+
+// parley always lays out a paragraph as a left-to-right one, which puts the
+// runs of a right-to-left paragraph (e.g. Arabic text with numbers or Latin
+// words) in the wrong order. So, the base direction of each paragraph is
+// detected from its first strong character (the rules P2 and P3 of the Unicode
+// Bidirectional Algorithm), and a right-to-left one is wrapped in a
+// right-to-left isolate. The isolate characters have no glyph.
+fn isolate_rtl_paragraphs(text: &str) -> Cow<'_, str> {
+    let is_rtl = |paragraph: &str| {
+        paragraph
+            .chars()
+            .find_map(|c| match c.bidi_class() {
+                BidiClass::L => Some(false),
+                BidiClass::R | BidiClass::AL => Some(true),
+                _ => None,
+            })
+            .unwrap_or(false)
+    };
+
+    if !text.split('\n').any(is_rtl) {
+        return Cow::Borrowed(text);
+    }
+
+    let paragraphs: Vec<Cow<'_, str>> = text
+        .split('\n')
+        .map(|paragraph| match is_rtl(paragraph) {
+            true => Cow::Owned(format!("\u{2067}{paragraph}\u{2069}")),
+            false => Cow::Borrowed(paragraph),
+        })
+        .collect();
+    Cow::Owned(paragraphs.join("\n"))
+}
+
+/// The distance between the baselines of two consecutive lines. This follows
+/// R's graphics engine, which separates lines by `lineheight` times the
+/// character height of the device (`cra[1]`, i.e. 1.2 times the font size).
+pub fn line_spacing(size: f32, lineheight: f32) -> f64 {
+    1.2 * size as f64 * lineheight as f64
+}
+
+/// Return the lines of the layout with the offsets of their origins (i.e. the
+/// left end of the baseline) from the position of the text, before rotation.
+/// The y-axis points downwards.
+///
+/// The baseline of the first line is placed at the position, which is where R
+/// expects, and the following lines are placed `line_spacing` below the
+/// previous one. Each line is adjusted horizontally by `hadj` separately, as R
+/// does for a multi-line string.
+///
+/// `hadj` is applied to the visual extent of the line regardless of the
+/// direction of the text, as Cairo-based devices do; 0 means the left end of
+/// the line is at the position even if it's a right-to-left one. The alignment
+/// offset of parley is cancelled so that the left end is always at 0.
+pub fn line_origins<'a>(
+    layout: &'a parley::Layout<peniko::Brush>,
+    hadj: f64,
+    line_spacing: f64,
+) -> impl Iterator<Item = (parley::Line<'a, peniko::Brush>, kurbo::Vec2)> + 'a {
+    layout.lines().enumerate().map(move |(i, line)| {
+        let metrics = line.metrics();
+        let left = -(metrics.advance as f64 * hadj) - metrics.offset as f64;
+        let origin = kurbo::Vec2::new(left, i as f64 * line_spacing);
+        (line, origin)
+    })
+}
+
+/// Lay out the spans of the rich text into `layout`.
+///
+/// Unlike [TextLayouter::build_layout], the lines are separated by parley so
+/// that a line containing a larger span gets taller. The line height of each
+/// span is `lineheight` times the character height of R (cf. [line_spacing]).
+/// The base direction of the paragraphs is always left-to-right.
+pub fn build_rich_layout(layout: &mut parley::Layout<peniko::Brush>, text: &RichText) {
+    let content: String = text.spans.iter().map(|span| span.text.as_str()).collect();
+
+    // Note: this needs to be done before locking FONT_CTX
+    let (families, weight, style) = resolve_font(&text.family, text.face);
+    let mut span_fonts = Vec::with_capacity(text.spans.len());
+    let mut start = 0;
+    for span in &text.spans {
+        let range = start..start + span.text.len();
+        start = range.end;
+        if span.family.is_some() || span.face.is_some() {
+            let family = span.family.as_deref().unwrap_or(&text.family);
+            let face = span.face.unwrap_or(text.face);
+            span_fonts.push((range.clone(), Some(resolve_font(family, face))));
+        } else {
+            span_fonts.push((range.clone(), None));
+        }
+    }
+
+    let mut font_ctx = FONT_CTX.lock().unwrap();
+    let mut layout_ctx = LAYOUT_CTX.lock().unwrap();
+    let mut layout_builder = layout_ctx.ranged_builder(&mut font_ctx, &content, 1.0);
+    layout_builder.push_default(parley::StyleProperty::FontSize(text.size));
+    layout_builder.push_default(parley::StyleProperty::LineHeight(1.2 * text.lineheight));
+    layout_builder.push_default(parley::StyleProperty::FontStack(parley::FontStack::List(
+        Cow::Owned(families),
+    )));
+    layout_builder.push_default(parley::StyleProperty::FontWeight(weight));
+    layout_builder.push_default(parley::StyleProperty::FontStyle(style));
+    layout_builder.push_default(parley::StyleProperty::Brush(peniko::Brush::Solid(
+        text.color,
+    )));
+    layout_builder.push_default(parley::StyleProperty::Locale(LOCALE.as_deref()));
+    layout_builder.push_default(font_features_property(&text.features));
+
+    for (span, (range, font)) in text.spans.iter().zip(span_fonts) {
+        if let Some((families, weight, style)) = font {
+            layout_builder.push(
+                parley::StyleProperty::FontStack(parley::FontStack::List(Cow::Owned(families))),
+                range.clone(),
+            );
+            layout_builder.push(parley::StyleProperty::FontWeight(weight), range.clone());
+            layout_builder.push(parley::StyleProperty::FontStyle(style), range.clone());
+        }
+        if let Some(size) = span.size {
+            layout_builder.push(parley::StyleProperty::FontSize(size), range.clone());
+        }
+        if let Some(color) = span.color {
+            layout_builder.push(
+                parley::StyleProperty::Brush(peniko::Brush::Solid(color)),
+                range.clone(),
+            );
+        }
+        if !span.features.is_empty() {
+            let features = merge_font_features(&text.features, &span.features);
+            layout_builder.push(font_features_property(&features), range.clone());
+        }
+    }
+
+    layout_builder.build_into(layout, &content);
+    layout.break_all_lines(text.max_width);
+    layout.align(text.max_width, text.align.into());
+}
+
+/// Return the lines of the rich text with the offsets of their origins from
+/// the position of the text, before rotation. The y-axis points downwards.
+///
+/// The text is placed as a box whose width is `max_width` if specified,
+/// otherwise the width of the longest line, and whose height is the total
+/// height of the lines. `hadj` and `vadj` are the position relative to the box
+/// (e.g. (0, 0) is the bottom-left corner, (0.5, 0.5) is the center). Unlike
+/// [line_origins], the alignment offset of parley is kept, so the lines are
+/// aligned within the box.
+pub fn rich_line_origins(
+    layout: &parley::Layout<peniko::Brush>,
+    max_width: Option<f32>,
+    hadj: f64,
+    vadj: f64,
+) -> impl Iterator<Item = (parley::Line<'_, peniko::Brush>, kurbo::Vec2)> + '_ {
+    let width = max_width.unwrap_or(layout.full_width()) as f64;
+    let height = layout.height() as f64;
+    let left = -width * hadj;
+    let top = -height * (1.0 - vadj);
+    layout.lines().map(move |line| {
+        let baseline = line.metrics().baseline as f64;
+        (line, kurbo::Vec2::new(left, top + baseline))
+    })
+}
+
+// Return the ascent and the descent of the ink of the first line, i.e. how far
+// the outlines of the glyphs extend above and below the baseline.
+fn ink_extents(layout: &parley::Layout<peniko::Brush>) -> (f64, f64) {
+    let Some(line) = layout.lines().next() else {
+        return (0.0, 0.0);
+    };
+
+    let (mut ascent, mut descent) = (0.0_f64, 0.0_f64);
+    for item in line.items() {
+        let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item else {
+            continue;
+        };
+        let run = glyph_run.run();
+        let font = run.font();
+        let Ok(font_ref) = FontRef::from_index(font.data.as_ref(), font.index) else {
+            continue;
+        };
+        let coords = run
+            .normalized_coords()
+            .iter()
+            .map(|coord| NormalizedCoord::from_bits(*coord))
+            .collect::<Vec<_>>();
+        let size = Size::new(run.font_size());
+        let outlines = font_ref.outline_glyphs();
+        let color_glyphs = font_ref.color_glyphs();
+
+        for glyph in glyph_run.glyphs() {
+            let glyph_id = GlyphId::from(glyph.id);
+            // A colour glyph (e.g. an emoji) is drawn from the layers or the
+            // bitmap, not from the outline, so the extents of the font are
+            // used instead.
+            let outline = match outlines.get(glyph_id) {
+                Some(outline) if color_glyphs.get(glyph_id).is_none() => outline,
+                _ => {
+                    let metrics = run.metrics();
+                    ascent = ascent.max(metrics.ascent as f64);
+                    descent = descent.max(metrics.descent as f64);
+                    continue;
                 }
+            };
+            let mut pen = BezPathPen::default();
+            if outline
+                .draw(DrawSettings::unhinted(size, coords.as_slice()), &mut pen)
+                .is_err()
+                || pen.0.is_empty()
+            {
+                continue;
             }
-            None => TextMetric {
-                ascent: 0.0,
-                descent: 0.0,
-                width: 0.0,
-            },
+            // Note: the outline is y-up while the glyph offset is y-down
+            let bbox = pen.0.bounding_box();
+            let offset = glyph.y as f64;
+            ascent = ascent.max(bbox.y1 - offset);
+            descent = descent.max(-bbox.y0 + offset);
         }
     }
+    (ascent, descent)
+}
+
+/// Convert the glyphs of the run into outlines, placed as they are drawn, i.e.
+/// relative to the origin of the line with the y-axis pointing downwards. This
+/// is for drawing the text as paths (e.g. a halo, or a vector format that
+/// doesn't embed fonts).
+///
+/// The variation and the faux italic of the run are applied, but the faux bold
+/// is not. The colour glyphs are converted to their base outlines, if any.
+pub fn glyph_run_outlines(glyph_run: &parley::GlyphRun<'_, peniko::Brush>) -> kurbo::BezPath {
+    let mut path = kurbo::BezPath::new();
+
+    let run = glyph_run.run();
+    let font = run.font();
+    let Ok(font_ref) = FontRef::from_index(font.data.as_ref(), font.index) else {
+        return path;
+    };
+    let synthesis = GlyphSynthesis::from_run(run);
+    let size = Size::new(run.font_size());
+    let outlines = font_ref.outline_glyphs();
+    // Note: the outline is y-up, so it's skewed before flipped (cf.
+    // draw_synthesized_glyphs())
+    let skew = synthesis.skew.map_or(kurbo::Affine::IDENTITY, |angle| {
+        kurbo::Affine::skew(angle.to_radians().tan() as f64, 0.0)
+    });
+
+    let mut x = glyph_run.offset();
+    for glyph in glyph_run.glyphs() {
+        let (gx, gy) = (x + glyph.x, glyph.y);
+        x += glyph.advance;
+
+        let Some(outline) = outlines.get(GlyphId::from(glyph.id)) else {
+            continue;
+        };
+        let mut pen = BezPathPen::default();
+        if outline
+            .draw(
+                DrawSettings::unhinted(size, synthesis.coords.as_slice()),
+                &mut pen,
+            )
+            .is_err()
+        {
+            continue;
+        }
+        let transform =
+            kurbo::Affine::translate((gx as f64, gy as f64)) * kurbo::Affine::FLIP_Y * skew;
+        path.extend(transform * pen.0);
+    }
+    path
+}
+
+#[derive(Default)]
+struct BezPathPen(kurbo::BezPath);
+
+impl OutlinePen for BezPathPen {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.0.move_to((x as f64, y as f64));
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.0.line_to((x as f64, y as f64));
+    }
+
+    fn quad_to(&mut self, cx0: f32, cy0: f32, x: f32, y: f32) {
+        self.0
+            .quad_to((cx0 as f64, cy0 as f64), (x as f64, y as f64));
+    }
+
+    fn curve_to(&mut self, cx0: f32, cy0: f32, cx1: f32, cy1: f32, x: f32, y: f32) {
+        self.0.curve_to(
+            (cx0 as f64, cy0 as f64),
+            (cx1 as f64, cy1 as f64),
+            (x as f64, y as f64),
+        );
+    }
+
+    fn close(&mut self) {
+        self.0.close_path();
+    }
 }
 
 pub fn fontface_to_weight_and_style(fontface: i32) -> (parley::FontWeight, parley::FontStyle) {
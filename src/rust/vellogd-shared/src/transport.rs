@@ -0,0 +1,442 @@
+// Transports to carry `Request`s and `Response`s between the R session and
+// vellogd-server.
+//
+// ipc_channel is the default, but it works only when both processes are on the
+// same machine. TCP and WebSocket are for the case when the window needs to be
+// shown on a different machine (e.g. R runs on a remote workstation).
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tungstenite::{protocol::WebSocketConfig, Message, WebSocket};
+
+/// The maximum size of a message on TCP and WebSocket. A larger message is
+/// rejected before its data is allocated, so that a broken or malicious peer
+/// cannot make the process allocate an arbitrary amount of memory.
+pub const MAX_MESSAGE_SIZE: usize = 512 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(std::io::Error),
+    Serialize(bincode::Error),
+    Ipc(ipc_channel::ipc::IpcError),
+    // Note: tungstenite::Error is large, so it's boxed
+    WebSocket(Box<tungstenite::Error>),
+    Handshake(String),
+    InvalidAddress(String),
+    MessageTooLarge(usize),
+    RemoteNotAllowed(String),
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportError::Io(e) => write!(f, "I/O error: {e}"),
+            TransportError::Serialize(e) => write!(f, "failed to (de)serialize message: {e}"),
+            TransportError::Ipc(e) => write!(f, "IPC error: {e:?}"),
+            TransportError::WebSocket(e) => write!(f, "WebSocket error: {e}"),
+            TransportError::Handshake(e) => write!(f, "WebSocket handshake failed: {e}"),
+            TransportError::InvalidAddress(addr) => write!(
+                f,
+                "invalid address: {addr} (expected tcp://HOST:PORT or ws://HOST:PORT)"
+            ),
+            TransportError::MessageTooLarge(len) => write!(
+                f,
+                "the message is too large ({len} bytes while the limit is {MAX_MESSAGE_SIZE} bytes)"
+            ),
+            TransportError::RemoteNotAllowed(addr) => write!(
+                f,
+                "refused to listen on {addr}, which other machines might reach without authentication \
+                 (use a loopback address like tcp://127.0.0.1:PORT with an SSH tunnel)"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<std::io::Error> for TransportError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<bincode::Error> for TransportError {
+    fn from(value: bincode::Error) -> Self {
+        Self::Serialize(value)
+    }
+}
+
+impl From<ipc_channel::ipc::IpcError> for TransportError {
+    fn from(value: ipc_channel::ipc::IpcError) -> Self {
+        Self::Ipc(value)
+    }
+}
+
+impl From<tungstenite::Error> for TransportError {
+    fn from(value: tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(value))
+    }
+}
+
+pub trait MessageSender<T>: Send {
+    fn send_message(&self, msg: T) -> Result<(), TransportError>;
+
+    /// Whether the message can contain `IpcSharedMemory`.
+    fn supports_shared_memory(&self) -> bool {
+        false
+    }
+}
+
+pub trait MessageReceiver<T>: Send {
+    fn recv_message(&self) -> Result<T, TransportError>;
+}
+
+pub type BoxedSender<T> = Box<dyn MessageSender<T>>;
+pub type BoxedReceiver<T> = Box<dyn MessageReceiver<T>>;
+
+//
+// ipc_channel
+//
+
+impl<T: Serialize + DeserializeOwned + Send> MessageSender<T> for ipc_channel::ipc::IpcSender<T> {
+    fn send_message(&self, msg: T) -> Result<(), TransportError> {
+        Ok(self.send(msg)?)
+    }
+
+    fn supports_shared_memory(&self) -> bool {
+        true
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Send> MessageReceiver<T>
+    for ipc_channel::ipc::IpcReceiver<T>
+{
+    fn recv_message(&self) -> Result<T, TransportError> {
+        Ok(self.recv()?)
+    }
+}
+
+//
+// TCP
+//
+// A message is a bincode-serialized data prefixed by its length (u32, little
+// endian).
+
+struct TcpSender(TcpStream);
+struct TcpReceiver(TcpStream);
+
+fn check_message_size(len: usize) -> Result<(), TransportError> {
+    if len > MAX_MESSAGE_SIZE {
+        return Err(TransportError::MessageTooLarge(len));
+    }
+    Ok(())
+}
+
+impl<T: Serialize> MessageSender<T> for TcpSender {
+    fn send_message(&self, msg: T) -> Result<(), TransportError> {
+        let data = bincode::serialize(&msg)?;
+        check_message_size(data.len())?;
+
+        // Note: &TcpStream implements Write, so no lock is needed here.
+        let mut stream = &self.0;
+        stream.write_all(&(data.len() as u32).to_le_bytes())?;
+        stream.write_all(&data)?;
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned> MessageReceiver<T> for TcpReceiver {
+    fn recv_message(&self) -> Result<T, TransportError> {
+        let mut stream = &self.0;
+
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        check_message_size(len)?;
+        let mut data = vec![0u8; len];
+        stream.read_exact(&mut data)?;
+
+        Ok(bincode::deserialize(&data)?)
+    }
+}
+
+fn tcp_pair<S: Serialize, R: DeserializeOwned>(
+    stream: TcpStream,
+) -> Result<(BoxedSender<S>, BoxedReceiver<R>), TransportError> {
+    // Small messages (e.g. GetWindowSizes) should be sent immediately
+    stream.set_nodelay(true)?;
+    let tx = TcpSender(stream.try_clone()?);
+    let rx = TcpReceiver(stream);
+    Ok((Box::new(tx), Box::new(rx)))
+}
+
+//
+// WebSocket
+//
+// A message is a bincode-serialized data sent as a binary message.
+//
+// Note: WebSocket requires &mut to read and write, and it writes by itself
+// while reading (e.g. Pong and Close), so the reader and the writer must share
+// the same WebSocket. To avoid blocking the writer while the reader waits for
+// a message, the reader doesn't hold the lock while waiting; it waits for data
+// by peeking a clone of the stream, and reads the WebSocket without blocking.
+// Likewise, the writer releases the lock while the peer doesn't read, because
+// the peer might be waiting for this side to read.
+
+// How long the writer holds the lock while the data cannot be written
+const WS_WRITE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(10);
+
+struct SharedWebSocket {
+    ws: Mutex<WebSocket<TcpStream>>,
+    // A clone of the underlying stream to wait for data without the lock
+    stream: TcpStream,
+}
+
+impl SharedWebSocket {
+    // Returns None if no message is available yet.
+    fn try_read(&self) -> Result<Option<Message>, TransportError> {
+        let mut ws = self.ws.lock().unwrap();
+        ws.get_ref().set_nonblocking(true)?;
+        let res = ws.read();
+        ws.get_ref().set_nonblocking(false)?;
+        match res {
+            Ok(msg) => Ok(Some(msg)),
+            Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+struct WsSender(Arc<SharedWebSocket>);
+struct WsReceiver(Arc<SharedWebSocket>);
+
+impl<T: Serialize> MessageSender<T> for WsSender {
+    fn send_message(&self, msg: T) -> Result<(), TransportError> {
+        let data = bincode::serialize(&msg)?;
+        check_message_size(data.len())?;
+        let mut res = self.0.ws.lock().unwrap().send(Message::Binary(data));
+        // The unsent data is kept in the WebSocket, so flush it later.
+        while let Err(tungstenite::Error::Io(e)) = &res {
+            if !matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            res = self.0.ws.lock().unwrap().flush();
+        }
+        Ok(res?)
+    }
+}
+
+impl<T: DeserializeOwned> MessageReceiver<T> for WsReceiver {
+    fn recv_message(&self) -> Result<T, TransportError> {
+        loop {
+            match self.0.try_read()? {
+                Some(Message::Binary(data)) => return Ok(bincode::deserialize(&data)?),
+                Some(Message::Close(_)) => return Err(tungstenite::Error::ConnectionClosed.into()),
+                // ignore ping, pong, and text
+                Some(_) => {}
+                // Wait for more data. Note that this doesn't consume the data,
+                // and returns immediately when the peer closes the connection.
+                None => {
+                    self.0.stream.peek(&mut [0u8; 1])?;
+                }
+            }
+        }
+    }
+}
+
+// Note: the default limits of tungstenite are too small for raster images
+fn ws_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..Default::default()
+    }
+}
+
+fn ws_pair<S: Serialize, R: DeserializeOwned>(
+    ws: WebSocket<TcpStream>,
+) -> Result<(BoxedSender<S>, BoxedReceiver<R>), TransportError> {
+    ws.get_ref().set_nodelay(true)?;
+    ws.get_ref().set_write_timeout(Some(WS_WRITE_TIMEOUT))?;
+    let shared = Arc::new(SharedWebSocket {
+        stream: ws.get_ref().try_clone()?,
+        ws: Mutex::new(ws),
+    });
+    let tx = WsSender(shared.clone());
+    let rx = WsReceiver(shared);
+    Ok((Box::new(tx), Box::new(rx)))
+}
+
+//
+// Address
+//
+
+/// An address of TCP or WebSocket transport. The format is `tcp://HOST:PORT`
+/// or `ws://HOST:PORT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    WebSocket(String),
+}
+
+impl FromStr for Address {
+    type Err = TransportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TransportError::InvalidAddress(s.to_string());
+
+        let (scheme, host) = s.split_once("://").ok_or_else(invalid)?;
+        // Note: ws://HOST:PORT/PATH is accepted, but the path is ignored
+        let host = host.split('/').next().unwrap_or_default();
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        match scheme {
+            "tcp" => Ok(Self::Tcp(host.to_string())),
+            "ws" => Ok(Self::WebSocket(host.to_string())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Tcp(host) => write!(f, "tcp://{host}"),
+            Address::WebSocket(host) => write!(f, "ws://{host}"),
+        }
+    }
+}
+
+impl Address {
+    fn host(&self) -> &str {
+        match self {
+            Address::Tcp(host) | Address::WebSocket(host) => host,
+        }
+    }
+
+    pub fn connect<S: Serialize, R: DeserializeOwned>(
+        &self,
+    ) -> Result<(BoxedSender<S>, BoxedReceiver<R>), TransportError> {
+        let stream = TcpStream::connect(self.host())?;
+        match self {
+            Address::Tcp(_) => tcp_pair(stream),
+            Address::WebSocket(_) => {
+                let (ws, _) = tungstenite::client::client_with_config(
+                    self.to_string(),
+                    stream,
+                    Some(ws_config()),
+                )
+                .map_err(|e| TransportError::Handshake(e.to_string()))?;
+                ws_pair(ws)
+            }
+        }
+    }
+
+    /// Since the connection has no authentication, the address must be a
+    /// loopback one unless `allow_remote` is true.
+    pub fn listen(&self, allow_remote: bool) -> Result<Listener, TransportError> {
+        if !allow_remote
+            && !self
+                .host()
+                .to_socket_addrs()?
+                .all(|addr| addr.ip().is_loopback())
+        {
+            return Err(TransportError::RemoteNotAllowed(self.to_string()));
+        }
+
+        Ok(Listener {
+            listener: TcpListener::bind(self.host())?,
+            websocket: matches!(self, Address::WebSocket(_)),
+        })
+    }
+}
+
+pub struct Listener {
+    listener: TcpListener,
+    websocket: bool,
+}
+
+impl Listener {
+    /// Wait for a connection from the peer.
+    pub fn accept<S: Serialize, R: DeserializeOwned>(
+        &self,
+    ) -> Result<(BoxedSender<S>, BoxedReceiver<R>), TransportError> {
+        let (stream, _) = self.listener.accept()?;
+        self.wrap(stream)
+    }
+
+    /// The same as `accept()`, but gives up if no peer connects within the
+    /// timeout.
+    pub fn accept_timeout<S: Serialize, R: DeserializeOwned>(
+        &self,
+        timeout: std::time::Duration,
+    ) -> Result<(BoxedSender<S>, BoxedReceiver<R>), TransportError> {
+        let deadline = std::time::Instant::now() + timeout;
+
+        // TcpListener has no accept with timeout, so poll it.
+        self.listener.set_nonblocking(true)?;
+        let res = loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => break Ok(stream),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    if std::time::Instant::now() >= deadline {
+                        break Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!("no connection in {} seconds", timeout.as_secs()),
+                        ));
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.listener.set_nonblocking(false)?;
+
+        // The accepted stream might inherit the non-blocking mode
+        let stream = res?;
+        stream.set_nonblocking(false)?;
+        self.wrap(stream)
+    }
+
+    fn wrap<S: Serialize, R: DeserializeOwned>(
+        &self,
+        stream: TcpStream,
+    ) -> Result<(BoxedSender<S>, BoxedReceiver<R>), TransportError> {
+        if self.websocket {
+            let ws = tungstenite::accept_with_config(stream, Some(ws_config()))
+                .map_err(|e| TransportError::Handshake(e.to_string()))?;
+            ws_pair(ws)
+        } else {
+            tcp_pair(stream)
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The address the peer can connect to. Unlike the address to listen,
+    /// the port is resolved if it's `0`.
+    pub fn address(&self) -> std::io::Result<Address> {
+        let host = self.local_addr()?.to_string();
+        Ok(if self.websocket {
+            Address::WebSocket(host)
+        } else {
+            Address::Tcp(host)
+        })
+    }
+}
@@ -12,6 +12,7 @@ use std::{
         atomic::{AtomicBool, AtomicU32, Ordering},
         Arc, LazyLock, Mutex,
     },
+    time::{Duration, Instant},
 };
 
 use vello::{
@@ -23,15 +24,21 @@ use winit::{
     application::ApplicationHandler,
     dpi::PhysicalSize,
     event::WindowEvent,
-    event_loop::{EventLoop, EventLoopProxy},
+    event_loop::{ControlFlow, EventLoop, EventLoopClosed, EventLoopProxy},
     window::{Window, WindowAttributes},
 };
 
 use crate::{
+    fonts::{has_color_glyphs, GlyphSynthesis},
     protocol::{
-        AppResponseRelay, FillBrush, FillParams, GlyphParams, Request, Response, StrokeParams,
+        convert_to_image, AppError, AppResponseRelay, ErrorKind, FillBrush, FillParams,
+        GlyphParams, Request, Response, RichText, StrokeParams,
+    },
+    queue::{EventQueue, DEFAULT_QUEUE_CAPACITY},
+    text_layouter::{
+        build_rich_layout, glyph_run_outlines, line_origins, line_spacing, rich_line_origins,
+        TextLayouter,
     },
-    text_layouter::{fontface_to_weight_and_style, TextLayouter},
 };
 
 pub struct ActiveRenderState<'a> {
@@ -40,6 +47,7 @@ pub struct ActiveRenderState<'a> {
     window: Arc<Window>,
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum RenderState<'a> {
     Active(ActiveRenderState<'a>),
     Suspended(Option<Arc<Window>>),
@@ -70,16 +78,39 @@ pub struct SceneDrawer {
     // of the layer. The positions definitely need to be flipped, but, the drawn
     // items (e.g. glyph) are not.
     y_transform: Arc<Mutex<vello::kurbo::Affine>>,
+    window_width: Arc<AtomicU32>,
     window_height: Arc<AtomicU32>,
 
+    // Some while a tile of a tiling pattern is being drawn.
+    tile: Arc<Mutex<Option<TileState>>>,
+
     needs_redraw: Arc<AtomicBool>,
+
+    // Set by mode() API while the R session is drawing so that the window
+    // doesn't show the half-drawn scene.
+    stop_rendering: Arc<AtomicBool>,
+
+    // The event loop sleeps while there's nothing to redraw, so it needs to be
+    // woken up when the scene is modified. None if there's no event loop (e.g.
+    // headless rendering).
+    waker: Option<Waker>,
+}
+
+/// Wake up the event loop that renders the scene.
+pub type Waker = Arc<dyn Fn() + Send + Sync>;
+
+// The original states to restore after drawing a tile.
+struct TileState {
+    orig_scene: Scene,
+    orig_y_transform: vello::kurbo::Affine,
 }
 
 impl SceneDrawer {
     pub fn new(
         y_transform: Arc<Mutex<vello::kurbo::Affine>>,
+        window_width: Arc<AtomicU32>,
         window_height: Arc<AtomicU32>,
-        needs_redraw: Arc<AtomicBool>,
+        waker: Option<Waker>,
     ) -> Self {
         let scene = Arc::new(Mutex::new(Scene::new()));
         Self {
@@ -87,11 +118,43 @@ impl SceneDrawer {
             edited_scene: scene,
             patterns: Arc::new(Mutex::new(Vec::new())),
             y_transform,
+            window_width,
             window_height,
-            needs_redraw,
+            tile: Arc::new(Mutex::new(None)),
+            needs_redraw: Arc::new(AtomicBool::new(false)),
+            stop_rendering: Arc::new(AtomicBool::new(false)),
+            waker,
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.waker {
+            wake();
+        }
+    }
+
+    /// Mark the scene as modified. The event loop is woken up only when the
+    /// flag flips, not on every draw.
+    pub fn mark_dirty(&self) {
+        if !self.needs_redraw.swap(true, Ordering::Relaxed) {
+            self.wake();
         }
     }
 
+    /// Set whether the R session is drawing. The window is not redrawn while
+    /// drawing, and is redrawn after the drawing finishes.
+    pub fn set_drawing(&self, drawing: bool) {
+        self.stop_rendering.store(drawing, Ordering::Relaxed);
+        if !drawing {
+            self.wake();
+        }
+    }
+
+    /// Whether the scene should be shown on the window now.
+    pub fn can_render(&self) -> bool {
+        !self.stop_rendering.load(Ordering::Relaxed) && !self.is_drawing_tile()
+    }
+
     pub fn reset(&mut self) {
         self.edited_scene.lock().unwrap().reset();
     }
@@ -100,44 +163,90 @@ impl SceneDrawer {
         self.on_screen_scene.lock().unwrap()
     }
 
-    pub fn replace_edited_scene(&self, new: Scene) -> Scene {
+    pub fn is_drawing_tile(&self) -> bool {
+        self.tile.lock().unwrap().is_some()
+    }
+
+    /// Finish drawing the tile started by [LockedScene::begin_tile], restore
+    /// the original scene, and return the scene of the tile.
+    pub fn end_tile(&self) -> Option<Scene> {
+        let TileState {
+            orig_scene,
+            orig_y_transform,
+        } = self.tile.lock().unwrap().take()?;
+
+        *self.y_transform.lock().unwrap() = orig_y_transform;
         let mut scene = self.edited_scene.lock().unwrap();
-        let orig = scene.clone();
-        *scene = new;
-        orig
+        Some(std::mem::replace(&mut *scene, orig_scene))
+    }
+
+    /// Lock the edited scene to draw items. This is useful to draw many items
+    /// at once without locking the scene per item.
+    pub fn lock(&self) -> LockedScene<'_> {
+        LockedScene {
+            scene: self.edited_scene.lock().unwrap(),
+            y_transform: *self.y_transform.lock().unwrap(),
+            drawer: self,
+        }
     }
 
+    pub fn register_pattern(&self, pattern: FillPattern) -> usize {
+        let mut patterns = self.patterns.lock().unwrap();
+        patterns.push(pattern);
+
+        patterns.len() - 1 // index
+    }
+
+    pub fn release_pattern(&self) {
+        // TODO
+    }
+}
+
+/// A handle to draw items on the edited scene of [SceneDrawer] while holding
+/// the lock.
+pub struct LockedScene<'a> {
+    scene: std::sync::MutexGuard<'a, Scene>,
+    y_transform: vello::kurbo::Affine,
+    drawer: &'a SceneDrawer,
+}
+
+impl LockedScene<'_> {
     fn draw_stroke_inner(
-        &self,
+        &mut self,
         stroke: &kurbo::Stroke,
         color: peniko::Color,
         shape: &impl kurbo::Shape,
     ) {
-        let scene = &mut self.edited_scene.lock().unwrap();
-        let y_transform = *self.y_transform.lock().unwrap();
-        scene.stroke(stroke, y_transform, color, None, shape);
+        self.scene
+            .stroke(stroke, self.y_transform, color, None, shape);
     }
 
     fn draw_fill_inner(
-        &self,
+        &mut self,
         fill_rule: peniko::Fill,
         brush: FillBrush,
         shape: &impl kurbo::Shape,
     ) {
-        let scene = &mut self.edited_scene.lock().unwrap();
-        let y_transform = *self.y_transform.lock().unwrap();
+        let y_transform = self.y_transform;
         match brush {
             FillBrush::Color(color) => {
-                scene.fill(fill_rule, y_transform, color, None, shape);
+                self.scene.fill(fill_rule, y_transform, color, None, shape);
             }
             FillBrush::PatternRef(index) => {
-                let patterns = self.patterns.lock().unwrap();
-                match patterns.get(index as usize).unwrap() {
+                let patterns = self.drawer.patterns.lock().unwrap();
+                // This can happen when the patterns are lost on the client's
+                // side (e.g. the server is respawned without them).
+                let Some(pattern) = patterns.get(index as usize) else {
+                    log::warn!("unknown pattern (index: {index}), so the fill is skipped");
+                    return;
+                };
+                match pattern {
                     FillPattern::Gradient(gradient) => {
-                        scene.fill(fill_rule, y_transform, gradient, None, shape);
+                        self.scene
+                            .fill(fill_rule, y_transform, gradient, None, shape);
                     }
                     FillPattern::Tiling(image) => {
-                        scene.fill(fill_rule, y_transform, image, None, shape);
+                        self.scene.fill(fill_rule, y_transform, image, None, shape);
                     }
                 }
             }
@@ -145,7 +254,7 @@ impl SceneDrawer {
     }
 
     pub fn draw_circle(
-        &self,
+        &mut self,
         center: kurbo::Point,
         radius: f64,
         fill_params: Option<FillParams>,
@@ -161,22 +270,22 @@ impl SceneDrawer {
             self.draw_stroke_inner(&stroke_params.stroke, stroke_params.color, &circle);
         }
 
-        self.needs_redraw.store(true, Ordering::Relaxed);
+        self.drawer.mark_dirty();
     }
 
-    pub fn draw_line(&self, p0: kurbo::Point, p1: kurbo::Point, stroke_params: StrokeParams) {
+    pub fn draw_line(&mut self, p0: kurbo::Point, p1: kurbo::Point, stroke_params: StrokeParams) {
         let line = vello::kurbo::Line::new(p0, p1);
         self.draw_stroke_inner(&stroke_params.stroke, stroke_params.color, &line);
-        self.needs_redraw.store(true, Ordering::Relaxed);
+        self.drawer.mark_dirty();
     }
 
-    pub fn draw_polyline(&self, path: kurbo::BezPath, stroke_params: StrokeParams) {
+    pub fn draw_polyline(&mut self, path: kurbo::BezPath, stroke_params: StrokeParams) {
         self.draw_stroke_inner(&stroke_params.stroke, stroke_params.color, &path);
-        self.needs_redraw.store(true, Ordering::Relaxed);
+        self.drawer.mark_dirty();
     }
 
     pub fn draw_polygon(
-        &self,
+        &mut self,
         path: kurbo::BezPath,
         fill_params: Option<FillParams>,
         stroke_params: Option<StrokeParams>,
@@ -194,11 +303,11 @@ impl SceneDrawer {
             self.draw_stroke_inner(&stroke_params.stroke, stroke_params.color, &path);
         }
 
-        self.needs_redraw.store(true, Ordering::Relaxed);
+        self.drawer.mark_dirty();
     }
 
     pub fn draw_rect(
-        &self,
+        &mut self,
         p0: kurbo::Point,
         p1: kurbo::Point,
         fill_params: Option<FillParams>,
@@ -214,88 +323,204 @@ impl SceneDrawer {
             self.draw_stroke_inner(&stroke_params.stroke, stroke_params.color, &rect);
         }
 
-        self.needs_redraw.store(true, Ordering::Relaxed);
+        self.drawer.mark_dirty();
     }
 
     pub fn draw_raster(
-        &self,
+        &mut self,
         image: &peniko::Image,
-        scale: (f64, f64),
-        pos: kurbo::Vec2, // top left corner
+        pos: kurbo::Point, // bottom left corner
+        size: (f64, f64),
         angle: f64,
     ) {
+        let scale = (size.0 / image.width as f64, size.1 / image.height as f64);
+
+        let window_height = self.drawer.window_height.load(Ordering::Relaxed) as f64;
+        let pos = kurbo::Vec2::new(pos.x, window_height - (pos.y + size.1)); // change to top-left corner
+
         let transform = kurbo::Affine::scale_non_uniform(scale.0, scale.1)
             .then_translate(pos)
             .then_rotate(-angle.to_radians());
-        let scene = &mut self.edited_scene.lock().unwrap();
-
-        scene.draw_image(image, transform);
+        self.scene.draw_image(image, transform);
 
-        self.needs_redraw.store(true, Ordering::Relaxed);
+        self.drawer.mark_dirty();
     }
 
     pub fn draw_glyph(
-        &self,
+        &mut self,
         glyph_run: parley::GlyphRun<peniko::Brush>,
-        color: peniko::Color,
+        brush: peniko::BrushRef<'_>,
         transform: kurbo::Affine,
     ) {
-        let scene = &mut self.edited_scene.lock().unwrap();
-
         let mut x = glyph_run.offset();
         let y = 0.0;
         let run = glyph_run.run();
 
-        let font = run.font();
-        let font_size = run.font_size();
+        let glyphs = glyph_run
+            .glyphs()
+            .map(|g| {
+                let gx = x + g.x;
+                let gy = y + g.y;
+                x += g.advance;
+                vello::Glyph {
+                    id: g.id as _,
+                    x: gx,
+                    y: gy,
+                }
+            })
+            .collect();
+
+        self.draw_synthesized_glyphs(
+            run.font(),
+            run.font_size(),
+            &GlyphSynthesis::from_run(run),
+            brush,
+            transform,
+            glyphs,
+        );
+    }
 
-        // TODO:  It seems this is to handle italic. Is this necessary?
-        //
-        // https://github.com/linebender/parley/blob/be9e9ab3fc3fe92b3887048d5123c963cffac3d5/examples/vello_editor/src/text.rs#L364-L366
-        // https://docs.rs/kurbo/latest/kurbo/struct.Affine.html#method.skew
+    // Draw the glyphs with the faux italic and bold if the font doesn't have
+    // the face.
+    fn draw_synthesized_glyphs(
+        &mut self,
+        font: &parley::Font,
+        font_size: f32,
+        synthesis: &GlyphSynthesis,
+        brush: peniko::BrushRef<'_>,
+        transform: kurbo::Affine,
+        glyphs: Vec<vello::Glyph>,
+    ) {
+        // Note: the glyph transform is applied on the outline, whose y-axis
+        // points upwards, so a positive skew leans the glyph to the right.
+        let glyph_transform = synthesis
+            .skew
+            .map(|angle| kurbo::Affine::skew(angle.to_radians().tan() as f64, 0.0));
+
+        // For a faux bold, the outlines are widened by stroking them in
+        // addition to filling. The strength is the same as FreeType's
+        // FT_GlyphSlot_Embolden (i.e. 1/24 em in total).
         //
-        // let glyph_xform = run.synthesis().skew().map(|angle| {
-        //     vello::kurbo::Affine::skew(angle.to_radians().tan() as f64, 0.0)
-        // });
-
-        let coords = run
-            .normalized_coords()
+        // Note: vello ignores the style for colour glyphs (e.g. emoji), so
+        // they would be drawn twice. Since emboldening doesn't make sense for
+        // them anyway, it's skipped for the fonts with colour glyphs. The
+        // monochrome glyphs are drawn with the brush (i.e. the gc colour).
+        let stroke = (synthesis.embolden && !has_color_glyphs(font))
+            .then(|| kurbo::Stroke::new(font_size as f64 / 24.0));
+        let styles = stroke
             .iter()
-            .map(|coord| vello::skrifa::instance::NormalizedCoord::from_bits(*coord))
-            .collect::<Vec<_>>();
-
-        scene
-            .draw_glyphs(font)
-            .brush(color)
-            .transform(transform)
-            .font_size(font_size)
-            .normalized_coords(&coords)
-            .draw(
-                peniko::Fill::NonZero,
-                glyph_run.glyphs().map(|g| {
-                    let gx = x + g.x;
-                    let gy = y + g.y;
-                    x += g.advance;
-                    vello::Glyph {
-                        id: g.id as _,
-                        x: gx,
-                        y: gy,
+            .map(peniko::StyleRef::from)
+            .chain(std::iter::once(peniko::Fill::NonZero.into()));
+
+        for style in styles {
+            self.scene
+                .draw_glyphs(font)
+                .brush(brush)
+                .transform(transform)
+                .glyph_transform(glyph_transform)
+                .font_size(font_size)
+                .normalized_coords(&synthesis.coords)
+                .draw(style, glyphs.iter().copied());
+        }
+
+        self.drawer.mark_dirty();
+    }
+
+    /// Draw a text laid out by [TextLayouter].
+    ///
+    /// `pos` is the position on R's coordinate. `angle` is the rotation in
+    /// degrees, with positive rotation anticlockwise from the positive x-axis.
+    /// `hadj` is the horizontal adjustment (0 means left-aligned, 1 means
+    /// right-aligned). `line_spacing` is the distance between the baselines
+    /// (cf. [line_spacing]).
+    pub fn draw_layout(
+        &mut self,
+        layout: &parley::Layout<peniko::Brush>,
+        color: peniko::Color,
+        pos: kurbo::Point,
+        angle: f64,
+        hadj: f64,
+        line_spacing: f64,
+    ) {
+        let window_height = self.drawer.window_height.load(Ordering::Relaxed) as f64;
+
+        for (line, origin) in line_origins(layout, hadj, line_spacing) {
+            let transform = vello::kurbo::Affine::translate(origin)
+                .then_rotate(-angle.to_radians())
+                .then_translate((pos.x, window_height - pos.y).into()); // Y-axis is flipped
+
+            for item in line.items() {
+                // ignore inline box
+                let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item else {
+                    continue;
+                };
+
+                self.draw_glyph(glyph_run, color.into(), transform);
+            }
+        }
+    }
+
+    /// Draw a rich text laid out by [build_rich_layout]. Each span is drawn
+    /// with its own colour. If the text has a halo, it's drawn under the whole
+    /// text so that it doesn't cover the neighbouring glyphs.
+    ///
+    /// `pos` is the position on R's coordinate. `angle` is the rotation in
+    /// degrees. `hadj` and `vadj` are the position relative to the box of the
+    /// text (cf. [rich_line_origins]).
+    pub fn draw_rich_layout(
+        &mut self,
+        layout: &parley::Layout<peniko::Brush>,
+        text: &RichText,
+        pos: kurbo::Point,
+        angle: f64,
+        hadj: f64,
+        vadj: f64,
+    ) {
+        let window_height = self.drawer.window_height.load(Ordering::Relaxed) as f64;
+        let lines = || {
+            rich_line_origins(layout, text.max_width, hadj, vadj).map(|(line, origin)| {
+                let transform = vello::kurbo::Affine::translate(origin)
+                    .then_rotate(-angle.to_radians())
+                    .then_translate((pos.x, window_height - pos.y).into()); // Y-axis is flipped
+                (line, transform)
+            })
+        };
+
+        if let Some(halo) = &text.halo {
+            // The stroke extends by the half of its width on both sides
+            let stroke = kurbo::Stroke::new(2.0 * halo.width as f64).with_join(kurbo::Join::Round);
+            for (line, transform) in lines() {
+                for item in line.items() {
+                    if let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item {
+                        let outlines = glyph_run_outlines(&glyph_run);
+                        self.scene
+                            .stroke(&stroke, transform, halo.color, None, &outlines);
                     }
-                }),
-            );
+                }
+            }
+        }
+
+        for (line, transform) in lines() {
+            for item in line.items() {
+                // ignore inline box
+                let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item else {
+                    continue;
+                };
 
-        self.needs_redraw.store(true, Ordering::Relaxed);
+                let brush = glyph_run.style().brush.clone();
+                self.draw_glyph(glyph_run, (&brush).into(), transform);
+            }
+        }
     }
 
     pub fn draw_glyph_raw(
-        &self,
+        &mut self,
         glyph_ids: &[u32],
         x: &[f64],
         y: &[f64],
-        glyph_params: GlyphParams,
-    ) {
-        let scene = &mut self.edited_scene.lock().unwrap();
-        let window_height = self.window_height.load(Ordering::Relaxed) as f32;
+        glyph_params: &GlyphParams,
+    ) -> Result<(), AppError> {
+        let window_height = self.drawer.window_height.load(Ordering::Relaxed) as f32;
 
         let glyphs = x
             .iter()
@@ -309,69 +534,75 @@ impl SceneDrawer {
 
         let transform = kurbo::Affine::rotate(-glyph_params.angle);
 
-        let font = glyph_params.font().unwrap(); // TODO: handle error
-
-        scene
-            .draw_glyphs(&font)
-            .brush(glyph_params.color)
-            .transform(transform)
-            .font_size(glyph_params.size)
-            .draw(peniko::Fill::NonZero, glyphs);
+        let font = glyph_params.font().ok_or_else(|| {
+            AppError::new(
+                ErrorKind::Font,
+                format!("unknown font file (id: {})", glyph_params.font_id),
+            )
+        })?;
+
+        let synthesis =
+            GlyphSynthesis::for_font(&font, glyph_params.weight(), glyph_params.style());
+        self.draw_synthesized_glyphs(
+            &font,
+            glyph_params.size,
+            &synthesis,
+            glyph_params.color.into(),
+            transform,
+            glyphs.collect(),
+        );
 
-        self.needs_redraw.store(true, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn push_clip(&self, p0: kurbo::Point, p1: kurbo::Point) {
-        let scene = &mut self.edited_scene.lock().unwrap();
-        let y_transform = *self.y_transform.lock().unwrap();
+    /// Set the clipping area. If the area covers the whole window, the
+    /// clipping is removed.
+    pub fn clip(&mut self, p0: kurbo::Point, p1: kurbo::Point) {
+        let window_width = self.drawer.window_width.load(Ordering::Relaxed) as f64;
+        let window_height = self.drawer.window_height.load(Ordering::Relaxed) as f64;
+
+        if p0.x <= 0.0 && p0.y <= 0.0 && p1.x >= window_width && p1.y >= window_height {
+            self.pop_clip();
+        } else {
+            self.push_clip(p0, p1);
+        }
+    }
 
+    fn push_clip(&mut self, p0: kurbo::Point, p1: kurbo::Point) {
         // R's graphics device always replaces the clipping strategy (really?)
-        scene.pop_layer();
+        self.scene.pop_layer();
 
-        scene.push_layer(
+        self.scene.push_layer(
             peniko::Mix::Clip,
             1.0,
-            y_transform,
+            self.y_transform,
             &kurbo::Rect::new(p0.x, p0.y, p1.x, p1.y),
         );
     }
 
-    pub fn pop_clip(&self) {
-        let scene = &mut self.edited_scene.lock().unwrap();
-        scene.pop_layer();
+    fn pop_clip(&mut self) {
+        self.scene.pop_layer();
     }
 
-    pub fn register_pattern(&self, pattern: FillPattern) -> usize {
-        let mut patterns = self.patterns.lock().unwrap();
-        patterns.push(pattern);
-
-        patterns.len() - 1 // index
-    }
+    /// Start drawing a tile of a tiling pattern whose bottom-left corner is at
+    /// (x, y). The subsequent drawings go to a temporary scene until
+    /// [SceneDrawer::end_tile] is called.
+    pub fn begin_tile(&mut self, x: f64, y: f64) {
+        // the pattern tile is drawn on the screen of original sizes,
+        // but it needs to be clipped at the specified area.
+        //
+        // TODO: to match the actual pixels and logical sizes, this needs to be scaled.
+        let orig_y_transform = self.y_transform;
+        self.y_transform = orig_y_transform.then_translate((-x, -y).into());
+        *self.drawer.y_transform.lock().unwrap() = self.y_transform;
 
-    pub fn release_pattern(&self) {
-        // TODO
-    }
-}
+        // Use a new scene to preserve the current scene
+        let orig_scene = std::mem::take(&mut *self.scene);
 
-// Note: I'm hoping to use no copy here. However, this raster might
-//    be drawn after the raster() Graphics API call. There's no
-//    guarantee that this still exists on R's memory at the time.
-//    So, this needs to be kept on Rust's memory.
-pub fn convert_to_image(
-    raster: &[u8],
-    width: usize,
-    height: usize,
-    extend: peniko::Extend,
-    alpha: u8,
-) -> peniko::Image {
-    let raster_blob = peniko::Blob::new(Arc::new(raster.to_vec()));
-    peniko::Image {
-        data: raster_blob,
-        format: peniko::Format::Rgba8,
-        width: width as u32,
-        height: height as u32,
-        extend,
-        alpha,
+        *self.drawer.tile.lock().unwrap() = Some(TileState {
+            orig_scene,
+            orig_y_transform,
+        });
     }
 }
 
@@ -383,13 +614,16 @@ pub struct VelloApp<'a, T: AppResponseRelay> {
     lottie_renderer: velato::Renderer,
     lottie_compositions: Vec<velato::Composition>,
     elapsed: std::time::Instant,
-    needs_redraw: Arc<AtomicBool>,
+    // The maximum number of frames per second
+    max_fps: Arc<AtomicU32>,
+    last_frame: Instant,
     width: Arc<AtomicU32>,
     height: Arc<AtomicU32>,
     y_transform: Arc<Mutex<vello::kurbo::Affine>>,
     base_color: Arc<AtomicU32>,
     layout: parley::Layout<peniko::Brush>,
     tx: T,
+    queue: Option<Arc<EventQueue>>,
 
     window_title: String,
 }
@@ -401,8 +635,8 @@ impl<'a, T: AppResponseRelay> VelloApp<'a, T> {
         y_transform: Arc<Mutex<vello::kurbo::Affine>>,
         tx: T,
         scene: SceneDrawer,
-        needs_redraw: Arc<AtomicBool>,
         base_color: Arc<AtomicU32>,
+        max_fps: Arc<AtomicU32>,
     ) -> Self {
         Self {
             context: RenderContext::new(),
@@ -412,17 +646,31 @@ impl<'a, T: AppResponseRelay> VelloApp<'a, T> {
             lottie_renderer: velato::Renderer::new(),
             lottie_compositions: vec![],
             elapsed: std::time::Instant::now(),
-            needs_redraw,
+            max_fps,
+            last_frame: Instant::now(),
             width,
             height,
             y_transform,
             base_color,
             layout: parley::Layout::new(),
             tx,
+            queue: None,
             window_title: "vellogd".to_string(),
         }
     }
 
+    /// This takes effect on the window created after this.
+    pub fn set_window_title(&mut self, title: &str) {
+        self.window_title = title.to_string();
+    }
+
+    /// Set the queue of the events sent to this app. Its statistics are
+    /// responded to `GetQueueStats`, and `user_event()` releases the slot of
+    /// every event.
+    pub fn set_queue(&mut self, queue: Arc<EventQueue>) {
+        self.queue = Some(queue);
+    }
+
     pub fn set_size(&self, width: u32, height: u32) {
         self.width.store(width, Ordering::Relaxed);
         self.height.store(height, Ordering::Relaxed);
@@ -508,10 +756,10 @@ fn add_platform_specific_attributes(attrs: WindowAttributes) -> WindowAttributes
 }
 
 #[cfg(target_os = "windows")]
-pub fn create_event_loop(any_thread: bool) -> EventLoop<Request> {
+pub fn create_event_loop<T: 'static>(any_thread: bool) -> EventLoop<T> {
     use winit::platform::windows::EventLoopBuilderExtWindows;
 
-    let event_loop = EventLoop::<Request>::with_user_event()
+    let event_loop = EventLoop::<T>::with_user_event()
         .with_any_thread(any_thread)
         .build()
         .unwrap();
@@ -520,10 +768,10 @@ pub fn create_event_loop(any_thread: bool) -> EventLoop<Request> {
 }
 
 #[cfg(target_os = "linux")]
-pub fn create_event_loop(any_thread: bool) -> EventLoop<Request> {
+pub fn create_event_loop<T: 'static>(any_thread: bool) -> EventLoop<T> {
     use winit::platform::wayland::EventLoopBuilderExtWayland;
 
-    let event_loop = EventLoop::<Request>::with_user_event()
+    let event_loop = EventLoop::<T>::with_user_event()
         .with_any_thread(any_thread)
         .build()
         .unwrap();
@@ -532,15 +780,58 @@ pub fn create_event_loop(any_thread: bool) -> EventLoop<Request> {
 }
 
 #[cfg(target_os = "macos")]
-pub fn create_event_loop(any_thread: bool) -> EventLoop<Request> {
+pub fn create_event_loop<T: 'static>(any_thread: bool) -> EventLoop<T> {
     if any_thread {
         panic!("Not supported!");
     }
-    let event_loop = EventLoop::<Request>::with_user_event().build().unwrap();
+    let event_loop = EventLoop::<T>::with_user_event().build().unwrap();
     event_loop.set_control_flow(winit::event_loop::ControlFlow::Wait);
     event_loop
 }
 
+/// `EventLoopProxy` with a bounded queue. The event loop must release the slot
+/// of every event it processes by `EventQueue::release()`.
+pub struct BoundedProxy<T: 'static> {
+    proxy: EventLoopProxy<T>,
+    queue: Arc<EventQueue>,
+}
+
+impl<T: 'static> Clone for BoundedProxy<T> {
+    fn clone(&self) -> Self {
+        Self {
+            proxy: self.proxy.clone(),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T: 'static> BoundedProxy<T> {
+    pub fn new(proxy: EventLoopProxy<T>, queue: Arc<EventQueue>) -> Self {
+        Self { proxy, queue }
+    }
+
+    pub fn queue(&self) -> &Arc<EventQueue> {
+        &self.queue
+    }
+
+    /// Send the event. This blocks while the queue is full.
+    pub fn send_event(&self, event: T) -> Result<(), EventLoopClosed<T>> {
+        self.queue.acquire();
+        self.proxy
+            .send_event(event)
+            .inspect_err(|_| self.queue.release())
+    }
+
+    /// Send the event only to wake up the event loop. This never blocks, and
+    /// the event is dropped if other events are pending.
+    pub fn wake(&self, event: T) {
+        // If this fails, the event loop has already exited.
+        if self.queue.acquire_if_idle() && self.proxy.send_event(event).is_err() {
+            self.queue.release();
+        }
+    }
+}
+
 impl<'a, T: AppResponseRelay> TextLayouter for VelloApp<'a, T> {
     fn layout_mut(&mut self) -> &mut parley::Layout<peniko::Brush> {
         &mut self.layout
@@ -598,10 +889,31 @@ impl<'a, T: AppResponseRelay> ApplicationHandler<Request> for VelloApp<'a, T> {
 
                 let device_handle = &self.context.devices[surface.dev_id];
 
-                let surface_texture = surface
-                    .surface
-                    .get_current_texture()
-                    .expect("failed to get surface texture");
+                let surface_texture = match surface.surface.get_current_texture() {
+                    Ok(surface_texture) => surface_texture,
+                    // These happen routinely (e.g. on resizing or minimizing
+                    // the window), so reconfigure the surface and try again.
+                    Err(vello::wgpu::SurfaceError::Lost | vello::wgpu::SurfaceError::Outdated) => {
+                        self.context
+                            .resize_surface(&mut render_state.surface, width, height);
+                        render_state.window.request_redraw();
+                        return;
+                    }
+                    // Skip this frame, and draw the scene on the next frame.
+                    Err(vello::wgpu::SurfaceError::Timeout) => {
+                        self.scene.needs_redraw.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                    Err(e) => {
+                        log::error!("failed to get the surface texture: {e}");
+                        return;
+                    }
+                };
+
+                // Note: this needs to be cleared before cloning the scene.
+                // Otherwise, the modification after the cloning would be lost.
+                self.scene.needs_redraw.store(false, Ordering::Relaxed);
+                self.last_frame = Instant::now();
 
                 // TODO: `scene` needs to be cloned because the lottie animation
                 // needs to be drawn freshly on every frame. Can this be more
@@ -627,23 +939,26 @@ impl<'a, T: AppResponseRelay> ApplicationHandler<Request> for VelloApp<'a, T> {
                         let [r, g, b, a] = self.base_color.load(Ordering::Relaxed).to_ne_bytes();
                         Color::rgba8(r, g, b, a)
                     };
-                    renderer
-                        .render_to_surface(
-                            &device_handle.device,
-                            &device_handle.queue,
-                            &scene,
-                            &surface_texture,
-                            &vello::RenderParams {
-                                base_color,
-                                width,
-                                height,
-                                antialiasing_method: AaConfig::Msaa16,
-                            },
-                        )
-                        .expect("failed to render");
-
-                    // surface is now up-to-date!
-                    self.needs_redraw.store(false, Ordering::Relaxed);
+                    let res = renderer.render_to_surface(
+                        &device_handle.device,
+                        &device_handle.queue,
+                        &scene,
+                        &surface_texture,
+                        &vello::RenderParams {
+                            base_color,
+                            width,
+                            height,
+                            antialiasing_method: AaConfig::Msaa16,
+                        },
+                    );
+                    // The texture is dropped without presenting
+                    if let Err(e) = res {
+                        log::error!("failed to render: {e}");
+                        return;
+                    }
+                } else {
+                    // Not rendered yet
+                    self.scene.needs_redraw.store(true, Ordering::Relaxed);
                 }
 
                 surface_texture.present();
@@ -654,44 +969,112 @@ impl<'a, T: AppResponseRelay> ApplicationHandler<Request> for VelloApp<'a, T> {
     }
 
     fn user_event(&mut self, event_loop: &winit::event_loop::ActiveEventLoop, event: Request) {
-        if matches!(event, Request::NewWindow) {
-            self.create_new_window(event_loop);
-            return;
+        // Release the slot first so that the producer can send the next event
+        // while this one is processed.
+        if let Some(queue) = &self.queue {
+            queue.release();
+        }
+        match event {
+            Request::NewWindow { size, .. } => self.open_window(event_loop, size),
+            event => self.handle_request(event),
         }
+    }
 
-        let render_state = match &mut self.state {
-            RenderState::Active(state) => state,
-            // TODO: this must NOT return if the event has return value.
-            // incoming event must be consumed otherwise the UI freezes
-            _ => return,
+    // Schedule the next redraw. The event loop sleeps until the next event if
+    // there's nothing to redraw, so the CPU is not used while idle.
+    fn about_to_wait(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
+        event_loop.set_control_flow(self.schedule_redraw());
+    }
+}
+
+impl<'a, T: AppResponseRelay> VelloApp<'a, T> {
+    /// Request a redraw if the frame is due, and return how long the event
+    /// loop can sleep. This is separated from about_to_wait() so that a server
+    /// hosting multiple apps can combine the results.
+    pub fn schedule_redraw(&self) -> ControlFlow {
+        let RenderState::Active(state) = &self.state else {
+            return ControlFlow::Wait;
         };
 
+        // Always redraw if there's animation.
+        let wants_redraw =
+            self.scene.needs_redraw.load(Ordering::Relaxed) || !self.lottie_compositions.is_empty();
+        if !wants_redraw || !self.scene.can_render() {
+            return ControlFlow::Wait;
+        }
+
+        let max_fps = self.max_fps.load(Ordering::Relaxed).max(1);
+        let next_frame = self.last_frame + Duration::from_secs(1) / max_fps;
+        if Instant::now() >= next_frame {
+            state.window.request_redraw();
+            ControlFlow::Wait
+        } else {
+            ControlFlow::WaitUntil(next_frame)
+        }
+    }
+
+    /// Open the window with the size unless it's already open.
+    pub fn open_window(
+        &mut self,
+        event_loop: &winit::event_loop::ActiveEventLoop,
+        (width, height): (u32, u32),
+    ) {
+        // The size might be changed by the user after the window is opened
+        if !matches!(self.state, RenderState::Active(_)) {
+            self.set_size(width, height);
+        }
+        self.create_new_window(event_loop);
+    }
+
+    /// Handle the requests other than `NewWindow`. Since this doesn't require
+    /// the event loop, this can be used for headless rendering as well.
+    pub fn handle_request(&mut self, event: Request) {
+        // Note: the requests that expect a response must be responded even when
+        // there's no active window, otherwise the client waits forever.
         match event {
-            Request::ConnectionReady => {
+            Request::ConnectionReady { .. } => {
                 unreachable!("This event should not be sent to app")
             }
-            Request::NewWindow => {
-                // TODO
-            }
-            Request::RedrawWindow => {
-                // always redraw if there's animation
-                if self.needs_redraw.load(Ordering::Relaxed) || !self.lottie_compositions.is_empty()
-                {
-                    render_state.window.request_redraw();
-                }
+            Request::NewWindow { .. } => {
+                unreachable!("This event should be handled by user_event()")
             }
+            // This is just to wake up the event loop. The redraw is
+            // scheduled in about_to_wait().
+            Request::RedrawWindow => {}
             Request::CloseWindow => {
                 self.state = RenderState::Suspended(None);
             }
+            // Just acknowledge; it's the caller who exits.
+            Request::Shutdown => {
+                self.state = RenderState::Suspended(None);
+                self.tx.respond(Response::Done);
+            }
             Request::NewPage => {
                 self.scene.reset();
                 self.lottie_compositions.clear();
-                self.needs_redraw.store(true, Ordering::Relaxed);
+                self.scene.mark_dirty();
             }
             Request::GetWindowSizes => {
-                let PhysicalSize { width, height } = render_state.window.inner_size();
+                let (width, height) = match &self.state {
+                    RenderState::Active(state) => {
+                        let PhysicalSize { width, height } = state.window.inner_size();
+                        (width, height)
+                    }
+                    _ => (
+                        self.width.load(Ordering::Relaxed),
+                        self.height.load(Ordering::Relaxed),
+                    ),
+                };
                 self.tx.respond(Response::WindowSizes { width, height });
             }
+            Request::GetQueueStats => {
+                let stats = self
+                    .queue
+                    .as_ref()
+                    .map(|queue| queue.stats())
+                    .unwrap_or_default();
+                self.tx.respond(Response::QueueStats { stats });
+            }
             Request::SetBaseColor { color } => self.base_color.store(color, Ordering::Relaxed),
             Request::DrawText {
                 pos,
@@ -703,33 +1086,36 @@ impl<'a, T: AppResponseRelay> ApplicationHandler<Request> for VelloApp<'a, T> {
                 face,
                 angle,
                 hadj,
+                features,
             } => {
-                let (weight, style) = fontface_to_weight_and_style(face);
-                self.build_layout(text, &family, weight, style, size, lineheight);
-
-                let layout_width = self.layout.width();
-                let window_height = self.height.load(Ordering::Relaxed) as f64;
-
-                for line in self.layout.lines() {
-                    let line_metrics = line.metrics();
-                    let transform = vello::kurbo::Affine::translate((
-                        -(layout_width * hadj) as f64,
-                        (line_metrics.baseline - line_metrics.line_height) as f64, // TODO: is this correct?
-                    ))
-                    .then_rotate(-angle as f64)
-                    .then_translate((pos.x, window_height - pos.y).into()); // Y-axis is flipped
-
-                    for item in line.items() {
-                        // ignore inline box
-                        let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item else {
-                            continue;
-                        };
-
-                        self.scene.draw_glyph(glyph_run, color, transform);
-                    }
-                }
-
-                self.needs_redraw.store(true, Ordering::Relaxed);
+                self.build_layout(text, &family, face, size, lineheight, &features);
+
+                self.scene.lock().draw_layout(
+                    &self.layout,
+                    color,
+                    pos,
+                    angle as f64,
+                    hadj as f64,
+                    line_spacing(size, lineheight),
+                );
+            }
+            Request::DrawRichText {
+                pos,
+                text,
+                angle,
+                hadj,
+                vadj,
+            } => {
+                build_rich_layout(&mut self.layout, &text);
+
+                self.scene.lock().draw_rich_layout(
+                    &self.layout,
+                    &text,
+                    pos,
+                    angle as f64,
+                    hadj as f64,
+                    vadj as f64,
+                );
             }
 
             // Note: this doesn't relates to window, so it might be possible to
@@ -739,12 +1125,8 @@ impl<'a, T: AppResponseRelay> ApplicationHandler<Request> for VelloApp<'a, T> {
                 let width = self.width.load(Ordering::Relaxed);
                 let height = self.height.load(Ordering::Relaxed);
 
-                // TODO: handle error
-                let _ = self.save_as_png(filename, width, height);
-            }
-
-            Request::PrepareForSaveAsTile { height: _ } => {
-                // TODO
+                let res = self.save_as_png(filename, width, height);
+                self.tx.respond(res.into());
             }
 
             Request::SaveAsTile {
@@ -755,25 +1137,46 @@ impl<'a, T: AppResponseRelay> ApplicationHandler<Request> for VelloApp<'a, T> {
                 let width = width.ceil() as u32;
                 let height = height.ceil() as u32;
 
-                let scene = self.scene.edited_scene.lock().unwrap().clone();
-                let data = self.rasterize(&scene, width, height).unwrap();
+                // If PrepareForSaveAsTile is not sent beforehand, this results
+                // in an empty tile.
+                let scene = self.scene.end_tile().unwrap_or_default();
+                let data = match self.rasterize(&scene, width, height) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        self.tx.respond(e.into());
+                        return;
+                    }
+                };
 
                 // register to tiles
 
                 let image =
                     convert_to_image(&data, width as usize, height as usize, extend, u8::MAX);
+                let index = self.scene.register_pattern(FillPattern::Tiling(image));
 
-                let mut patterns = self.scene.patterns.lock().unwrap();
-                patterns.push(FillPattern::Tiling(image));
-                let index = patterns.len() - 1;
+                self.tx.respond(Response::PatternRegistered { index });
+            }
 
+            Request::RegisterGradient { gradient } => {
+                let index = self.scene.register_pattern(FillPattern::Gradient(gradient));
                 self.tx.respond(Response::PatternRegistered { index });
             }
 
             Request::AddLottieAnimation { filename } => {
-                let lottie = std::fs::read_to_string(&filename).unwrap();
-                let composition = velato::Composition::from_str(&lottie).unwrap();
-                self.lottie_compositions.push(composition);
+                let res = std::fs::read_to_string(&filename)
+                    .map_err(|e| {
+                        AppError::new(ErrorKind::Io, format!("failed to read {filename}: {e}"))
+                    })
+                    .and_then(|lottie| {
+                        velato::Composition::from_str(&lottie).map_err(|e| {
+                            AppError::new(
+                                ErrorKind::Lottie,
+                                format!("failed to parse {filename}: {e}"),
+                            )
+                        })
+                    })
+                    .map(|composition| self.lottie_compositions.push(composition));
+                self.tx.respond(res.into());
             }
 
             // ignore other events
@@ -788,11 +1191,12 @@ pub fn calc_y_translate(height: f32) -> vello::kurbo::Affine {
     vello::kurbo::Affine::new([1.0, 0., 0., -1.0, 0., height as _]) // = FLIP_Y.then_translate((0.0, height))
 }
 
-const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(16); // = 60fps
+/// The default of the maximum number of frames per second.
+pub const DEFAULT_MAX_FPS: u32 = 60;
 
 // Hold the communication channel between VelloApp and the shared statuses.
 pub struct VelloAppProxy {
-    pub tx: EventLoopProxy<Request>,
+    pub tx: BoundedProxy<Request>,
     pub rx: std::sync::Mutex<std::sync::mpsc::Receiver<Response>>,
 
     pub scene: SceneDrawer,
@@ -810,9 +1214,7 @@ pub struct VelloAppProxy {
 
     base_color: Arc<AtomicU32>,
 
-    // To be called by mode() API so that the device can stop rendering when it
-    // is actively written.
-    pub stop_rendering: Arc<AtomicBool>,
+    max_fps: Arc<AtomicU32>,
 }
 
 impl VelloAppProxy {
@@ -829,16 +1231,23 @@ impl VelloAppProxy {
     pub fn set_base_color(&self, color: u32) {
         self.base_color.store(color, Ordering::Relaxed);
     }
+
+    pub fn set_max_fps(&self, fps: u32) {
+        self.max_fps.store(fps, Ordering::Relaxed);
+    }
+
+    pub fn set_queue_capacity(&self, capacity: usize) {
+        self.tx.queue().set_capacity(capacity);
+    }
 }
 
 pub static VELLO_APP_PROXY: LazyLock<VelloAppProxy> = LazyLock::new(|| {
     let (sender, receiver) = std::sync::mpsc::channel();
     let _ = std::thread::spawn(move || {
-        let event_loop = create_event_loop(true);
+        let event_loop = create_event_loop::<Request>(true);
         event_loop.set_control_flow(winit::event_loop::ControlFlow::Wait);
         let (tx, rx) = std::sync::mpsc::channel::<Response>();
 
-        let needs_redraw = Arc::new(AtomicBool::new(false));
         // Note: 0 is a dummy value and should be overwritten soon after the
         // creation. Ideally, VELLO_APP_PROXY should be OnceLock so that the
         // init function can initialize this with the actual sizes, but LazyLock
@@ -848,51 +1257,36 @@ pub static VELLO_APP_PROXY: LazyLock<VelloAppProxy> = LazyLock::new(|| {
         let height = Arc::new(AtomicU32::new(0));
         let y_transform = Arc::new(Mutex::new(calc_y_translate(0.0)));
         let base_color = Arc::new(AtomicU32::new(Color::WHITE_SMOKE.to_premul_u32()));
+        let max_fps = Arc::new(AtomicU32::new(DEFAULT_MAX_FPS));
 
-        let is_drawing = Arc::new(AtomicBool::new(false));
+        let queue = Arc::new(EventQueue::new(DEFAULT_QUEUE_CAPACITY));
+        let bounded_proxy = BoundedProxy::new(event_loop.create_proxy(), queue.clone());
 
-        let scene = SceneDrawer::new(y_transform.clone(), height.clone(), needs_redraw.clone());
+        let waker = bounded_proxy.clone();
+        let scene = SceneDrawer::new(
+            y_transform.clone(),
+            width.clone(),
+            height.clone(),
+            Some(Arc::new(move || waker.wake(Request::RedrawWindow))),
+        );
         let proxy = VelloAppProxy {
-            tx: event_loop.create_proxy(),
+            tx: bounded_proxy,
             rx: std::sync::Mutex::new(rx),
             scene: scene.clone(),
             width: width.clone(),
             height: height.clone(),
             y_transform: y_transform.clone(),
             base_color: base_color.clone(),
-            stop_rendering: is_drawing.clone(),
+            max_fps: max_fps.clone(),
         };
         sender.send(proxy).unwrap();
 
-        let mut app = VelloApp::new(
-            width,
-            height,
-            y_transform,
-            tx,
-            scene,
-            needs_redraw,
-            base_color,
-        );
+        let mut app = VelloApp::new(width, height, y_transform, tx, scene, base_color, max_fps);
+        app.set_queue(queue);
 
         // this blocks until event_loop exits
         event_loop.run_app(&mut app).unwrap();
     });
 
-    let event_loop = receiver.recv().unwrap();
-    let event_loop_for_refresh = event_loop.tx.clone();
-
-    let stop_rendering = event_loop.stop_rendering.clone();
-
-    // TODO: stop refreshing when no window
-    std::thread::spawn(move || loop {
-        // Skip refreshing the window if the R session is drawing into it.
-        if !stop_rendering.load(Ordering::Relaxed) {
-            event_loop_for_refresh
-                .send_event(Request::RedrawWindow)
-                .unwrap();
-        }
-        std::thread::sleep(REFRESH_INTERVAL);
-    });
-
-    event_loop
+    receiver.recv().unwrap()
 });
@@ -1,6 +1,6 @@
 use std::{num::NonZeroUsize, sync::atomic::Ordering};
 
-use crate::protocol::AppResponseRelay;
+use crate::protocol::{AppError, AppResponseRelay, ErrorKind};
 
 use super::VelloApp;
 use peniko::Color;
@@ -47,8 +47,9 @@ impl<'a, T: AppResponseRelay> VelloApp<'a, T> {
         scene: &Scene,
         width: u32,
         height: u32,
-    ) -> Result<Vec<u8>, vello::Error> {
-        let dev_id = pollster::block_on(async { self.context.device(None).await }).unwrap();
+    ) -> Result<Vec<u8>, AppError> {
+        let dev_id = pollster::block_on(async { self.context.device(None).await })
+            .ok_or_else(|| AppError::new(ErrorKind::Render, "no compatible device found"))?;
         let device_handle = &self.context.devices[dev_id];
 
         // TODO: move to app's field
@@ -60,8 +61,7 @@ impl<'a, T: AppResponseRelay> VelloApp<'a, T> {
                 antialiasing_support: vello::AaSupport::area_only(),
                 num_init_threads: NonZeroUsize::new(1),
             },
-        )
-        .unwrap();
+        )?;
 
         let size = Extent3d {
             width,
@@ -121,8 +121,12 @@ impl<'a, T: AppResponseRelay> VelloApp<'a, T> {
         if let Some(recv_result) =
             vello::util::block_on_wgpu(&device_handle.device, receiver.receive())
         {
-            // TODO: handle error
-            recv_result.unwrap();
+            recv_result.map_err(|e| {
+                AppError::new(
+                    ErrorKind::Render,
+                    format!("failed to read the texture: {e}"),
+                )
+            })?;
         }
 
         let data = buf_slice.get_mapped_range();
@@ -141,7 +145,7 @@ impl<'a, T: AppResponseRelay> VelloApp<'a, T> {
         filename: String,
         width: u32,
         height: u32,
-    ) -> Result<(), vello::Error> {
+    ) -> Result<(), AppError> {
         // TODO: in theory, this doesn't need clone(). However, if I put the
         // scene directly to self.rasterize(), the borrow checker gives the
         // following error:
@@ -158,13 +162,21 @@ impl<'a, T: AppResponseRelay> VelloApp<'a, T> {
 
         let result_unpadded = self.rasterize(&scene, width, height)?;
 
-        let mut file = std::fs::File::create(&filename).unwrap();
+        let png_error = |e: png::EncodingError| {
+            AppError::new(ErrorKind::Io, format!("failed to write {filename}: {e}"))
+        };
+
+        let mut file = std::fs::File::create(&filename).map_err(|e| {
+            AppError::new(ErrorKind::Io, format!("failed to create {filename}: {e}"))
+        })?;
         let mut encoder = png::Encoder::new(&mut file, width, height);
         encoder.set_color(png::ColorType::Rgba);
         encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&result_unpadded).unwrap();
-        writer.finish().unwrap();
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        writer
+            .write_image_data(&result_unpadded)
+            .map_err(png_error)?;
+        writer.finish().map_err(png_error)?;
 
         Ok(())
     }
@@ -0,0 +1,180 @@
+// Register servers in a runtime directory under a temporary directory, and
+// list them.
+
+use std::{
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+use vellogd_shared::discovery::{list_servers, register, runtime_dir, unregister, ServerEntry};
+
+// The runtime directory is specified by an environment variable, which is
+// shared by all the tests in this process.
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+// Point XDG_RUNTIME_DIR to an empty temporary directory while the guard is
+// alive.
+struct TempRuntimeDir {
+    base: PathBuf,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl TempRuntimeDir {
+    fn new(name: &str) -> Self {
+        let guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let base = std::env::temp_dir().join(format!(
+            "vellogd-test-discovery-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&base).unwrap();
+        std::env::set_var("XDG_RUNTIME_DIR", &base);
+        Self {
+            base,
+            _guard: guard,
+        }
+    }
+}
+
+impl Drop for TempRuntimeDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.base);
+    }
+}
+
+fn entry(pid: u32, name: &str) -> ServerEntry {
+    ServerEntry {
+        pid,
+        name: Some(name.to_string()),
+        address: "tcp://127.0.0.1:9000".to_string(),
+        protocol_version: 1,
+    }
+}
+
+fn file_names() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(runtime_dir())
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+// The pid of a process that has exited
+#[cfg(unix)]
+fn dead_pid() -> u32 {
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let pid = child.id();
+    child.wait().unwrap();
+    pid
+}
+
+#[test]
+fn test_register() {
+    let dir = TempRuntimeDir::new("register");
+    assert_eq!(runtime_dir(), dir.base.join("vellogd"));
+
+    let pid = std::process::id();
+    let registration = register(&entry(pid, "test")).unwrap();
+
+    // Only the entry is left; the temporary file is renamed to it.
+    assert_eq!(file_names(), [format!("{pid}.json")]);
+    let data = std::fs::read(runtime_dir().join(format!("{pid}.json"))).unwrap();
+    let written: ServerEntry = serde_json::from_slice(&data).unwrap();
+    assert_eq!(written.pid, pid);
+    assert_eq!(written.name.as_deref(), Some("test"));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(runtime_dir())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    // Registering again overwrites the entry
+    let registration2 = register(&entry(pid, "test2")).unwrap();
+    assert_eq!(file_names(), [format!("{pid}.json")]);
+    let servers = list_servers();
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].name.as_deref(), Some("test2"));
+
+    // The entry is removed on drop
+    drop(registration2);
+    assert!(file_names().is_empty());
+    drop(registration);
+}
+
+// Note: a dead process is detected only on Unix
+#[cfg(unix)]
+#[test]
+fn test_list_servers() {
+    let _dir = TempRuntimeDir::new("list");
+    assert!(list_servers().is_empty());
+
+    let pid = std::process::id();
+    let dead = dead_pid();
+    let _registrations = [
+        register(&entry(pid, "alive")).unwrap(),
+        register(&entry(dead, "dead")).unwrap(),
+    ];
+    // These are not entries
+    std::fs::write(runtime_dir().join("broken.json"), "{").unwrap();
+    std::fs::write(runtime_dir().join(".123.json.tmp"), "{").unwrap();
+
+    // The entry of the dead process is removed on listing
+    let servers = list_servers();
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].pid, pid);
+    assert!(!runtime_dir().join(format!("{dead}.json")).exists());
+
+    unregister(pid);
+    assert!(list_servers().is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_list_servers_sorted() {
+    let _dir = TempRuntimeDir::new("sorted");
+
+    // pid 1 always exists
+    let pid = std::process::id();
+    let _registrations = [
+        register(&entry(pid, "self")).unwrap(),
+        register(&entry(1, "init")).unwrap(),
+    ];
+    let pids: Vec<u32> = list_servers().iter().map(|x| x.pid).collect();
+    assert_eq!(pids, [1, pid]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_refuse_shared_directory() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _dir = TempRuntimeDir::new("shared");
+
+    // Someone else could write the entries to this directory
+    std::fs::create_dir(runtime_dir()).unwrap();
+    std::fs::set_permissions(runtime_dir(), std::fs::Permissions::from_mode(0o777)).unwrap();
+    let pid = std::process::id();
+    std::fs::write(
+        runtime_dir().join(format!("{pid}.json")),
+        serde_json::to_vec(&entry(pid, "fake")).unwrap(),
+    )
+    .unwrap();
+
+    let err = register(&entry(pid, "test")).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert!(list_servers().is_empty());
+
+    // A symlink to a directory is refused as well
+    std::fs::set_permissions(runtime_dir(), std::fs::Permissions::from_mode(0o700)).unwrap();
+    let real = runtime_dir().with_file_name("real");
+    std::fs::rename(runtime_dir(), &real).unwrap();
+    std::os::unix::fs::symlink(&real, runtime_dir()).unwrap();
+    assert!(register(&entry(pid, "test")).is_err());
+    assert!(list_servers().is_empty());
+}
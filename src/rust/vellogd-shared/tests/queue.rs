@@ -0,0 +1,105 @@
+// Check the bounded queue of the events waiting for the event loop, and the
+// statistics reported by vellogd_queue_stats().
+
+use std::{
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use vellogd_shared::queue::EventQueue;
+
+// Long enough for a thread to reach the blocking point
+const WAIT: Duration = Duration::from_millis(100);
+
+// Acquire a slot on another thread, and notify when it's acquired
+fn acquire_on_thread(queue: &Arc<EventQueue>) -> mpsc::Receiver<()> {
+    let (tx, rx) = mpsc::channel();
+    let queue = queue.clone();
+    std::thread::spawn(move || {
+        queue.acquire();
+        let _ = tx.send(());
+    });
+    rx
+}
+
+#[test]
+fn test_depth_and_peak() {
+    let queue = EventQueue::new(4);
+
+    let stats = queue.stats();
+    assert_eq!((stats.depth, stats.capacity, stats.peak), (0, 4, 0));
+
+    queue.acquire();
+    queue.acquire();
+    queue.acquire();
+    queue.release();
+
+    let stats = queue.stats();
+    assert_eq!((stats.depth, stats.peak), (2, 3));
+    assert_eq!((stats.blocked, stats.coalesced), (0, 0));
+
+    queue.release();
+    queue.release();
+    // An extra release doesn't underflow
+    queue.release();
+    let stats = queue.stats();
+    assert_eq!((stats.depth, stats.peak), (0, 3));
+}
+
+#[test]
+fn test_block_at_capacity() {
+    let queue = Arc::new(EventQueue::new(2));
+    queue.acquire();
+    queue.acquire();
+
+    let acquired = acquire_on_thread(&queue);
+    assert!(acquired.recv_timeout(WAIT).is_err());
+    assert_eq!(queue.stats().blocked, 1);
+    assert_eq!(queue.stats().depth, 2);
+
+    // Releasing a slot lets the producer proceed
+    queue.release();
+    assert!(acquired.recv_timeout(Duration::from_secs(10)).is_ok());
+    let stats = queue.stats();
+    assert_eq!((stats.depth, stats.peak, stats.blocked), (2, 2, 1));
+}
+
+#[test]
+fn test_set_capacity() {
+    let queue = Arc::new(EventQueue::new(1));
+    queue.acquire();
+
+    let acquired = acquire_on_thread(&queue);
+    assert!(acquired.recv_timeout(WAIT).is_err());
+
+    // Raising the capacity lets the producer proceed as well
+    queue.set_capacity(2);
+    assert!(acquired.recv_timeout(Duration::from_secs(10)).is_ok());
+    assert_eq!(queue.stats().capacity, 2);
+
+    // The capacity is at least 1
+    queue.set_capacity(0);
+    assert_eq!(queue.stats().capacity, 1);
+    assert_eq!(EventQueue::new(0).stats().capacity, 1);
+}
+
+#[test]
+fn test_coalesce_wake_ups() {
+    let queue = EventQueue::new(1);
+
+    // The wake-up is sent only when nothing is pending
+    assert!(queue.acquire_if_idle());
+    assert!(!queue.acquire_if_idle());
+    assert!(!queue.acquire_if_idle());
+    queue.release();
+    assert!(queue.acquire_if_idle());
+
+    let stats = queue.stats();
+    assert_eq!((stats.depth, stats.coalesced, stats.blocked), (1, 2, 0));
+
+    // This doesn't block even if the queue is full
+    queue.release();
+    queue.acquire();
+    assert!(!queue.acquire_if_idle());
+    assert_eq!(queue.stats().coalesced, 3);
+}
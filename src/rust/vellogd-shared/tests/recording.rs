@@ -0,0 +1,183 @@
+// Write requests to a recording file and read them back, in both formats.
+
+use std::{io::Write, path::PathBuf};
+
+use vellogd_shared::{
+    protocol::{ImageBytes, Request, StrokeParams, PROTOCOL_VERSION},
+    recording::{read_recording, Recorder, RecordingError, RecordingFormat, RecordingHeader},
+    transport::MAX_MESSAGE_SIZE,
+};
+
+// A path under the temporary directory that is unique to the test
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("vellogd-test-{}-{name}", std::process::id()))
+}
+
+fn requests() -> Vec<Request> {
+    vec![
+        Request::NewPage,
+        Request::SetBaseColor { color: 0xffffffff },
+        Request::DrawLine {
+            p0: kurbo::Point::new(1.0, 2.0),
+            p1: kurbo::Point::new(3.0, 4.0),
+            stroke_params: StrokeParams {
+                color: peniko::Color::rgba8(1, 2, 3, 4),
+                stroke: kurbo::Stroke::new(0.5),
+            },
+        },
+        Request::RegisterImage {
+            id: 42,
+            data: ImageBytes::Inline(vec![1, 2, 3, 4, 5, 6, 7, 8]),
+            width: 2,
+            height: 1,
+        },
+    ]
+}
+
+// Request doesn't implement PartialEq, so compare the debug representations
+fn debug_strings<'a>(requests: impl IntoIterator<Item = &'a Request>) -> Vec<String> {
+    requests.into_iter().map(|x| format!("{x:?}")).collect()
+}
+
+fn round_trip(name: &str, format: RecordingFormat) {
+    let path = temp_path(name);
+    let mut recorder = Recorder::create(&path, format, 100, 200).unwrap();
+    for request in &requests() {
+        recorder.record(request).unwrap();
+    }
+    recorder.flush().unwrap();
+    drop(recorder);
+
+    let res = read_recording(&path);
+    let _ = std::fs::remove_file(&path);
+    let (header, entries) = res.unwrap();
+
+    assert_eq!(header.protocol_version, PROTOCOL_VERSION);
+    assert_eq!((header.width, header.height), (100, 200));
+    assert_eq!(
+        debug_strings(entries.iter().map(|x| &x.request)),
+        debug_strings(&requests())
+    );
+    assert!(entries
+        .windows(2)
+        .all(|x| x[0].timestamp_us <= x[1].timestamp_us));
+}
+
+#[test]
+fn test_round_trip_binary() {
+    round_trip("round-trip.vellogd", RecordingFormat::Binary);
+}
+
+#[test]
+fn test_round_trip_json() {
+    round_trip("round-trip.jsonl", RecordingFormat::Json);
+}
+
+#[test]
+fn test_format_from_path() {
+    assert_eq!(
+        RecordingFormat::from_path("plot.json"),
+        RecordingFormat::Json
+    );
+    assert_eq!(
+        RecordingFormat::from_path("plot.jsonl"),
+        RecordingFormat::Json
+    );
+    assert_eq!(
+        RecordingFormat::from_path("plot.vellogd"),
+        RecordingFormat::Binary
+    );
+    assert_eq!(RecordingFormat::from_path("plot"), RecordingFormat::Binary);
+}
+
+fn read_bytes(name: &str, data: &[u8]) -> Result<(), RecordingError> {
+    let path = temp_path(name);
+    std::fs::File::create(&path)
+        .unwrap()
+        .write_all(data)
+        .unwrap();
+    let res = read_recording(&path);
+    let _ = std::fs::remove_file(&path);
+    res.map(|_| ())
+}
+
+fn binary_entry<T: serde::Serialize>(entry: &T) -> Vec<u8> {
+    let data = bincode::serialize(entry).unwrap();
+    let mut out = (data.len() as u32).to_le_bytes().to_vec();
+    out.extend(data);
+    out
+}
+
+#[test]
+fn test_wrong_magic() {
+    // This is not the magic, so the file is read as JSON and fails
+    let mut data = b"VELLOGDX".to_vec();
+    data.extend(binary_entry(&RecordingHeader {
+        protocol_version: PROTOCOL_VERSION,
+        width: 1,
+        height: 1,
+    }));
+    assert!(matches!(
+        read_bytes("wrong-magic", &data),
+        Err(RecordingError::Json(_))
+    ));
+
+    assert!(read_bytes("empty", b"").is_err());
+}
+
+#[test]
+fn test_version_mismatch() {
+    let header = RecordingHeader {
+        protocol_version: PROTOCOL_VERSION + 1,
+        width: 1,
+        height: 1,
+    };
+
+    let mut data = b"VELLOGDR".to_vec();
+    data.extend(binary_entry(&header));
+    assert!(matches!(
+        read_bytes("version-mismatch-binary", &data),
+        Err(RecordingError::VersionMismatch(v)) if v == PROTOCOL_VERSION + 1
+    ));
+
+    let data = serde_json::to_vec(&header).unwrap();
+    assert!(matches!(
+        read_bytes("version-mismatch-json", &data),
+        Err(RecordingError::VersionMismatch(v)) if v == PROTOCOL_VERSION + 1
+    ));
+}
+
+#[test]
+fn test_broken_binary() {
+    let mut data = b"VELLOGDR".to_vec();
+    data.extend(binary_entry(&RecordingHeader {
+        protocol_version: PROTOCOL_VERSION,
+        width: 1,
+        height: 1,
+    }));
+
+    // An entry larger than the limit is rejected before allocating it
+    let mut too_large = data.clone();
+    too_large.extend((MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+    assert!(matches!(
+        read_bytes("too-large", &too_large),
+        Err(RecordingError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData
+    ));
+
+    // The entry is shorter than its length
+    let mut truncated = data.clone();
+    truncated.extend(100u32.to_le_bytes());
+    truncated.extend([0u8; 10]);
+    assert!(matches!(
+        read_bytes("truncated", &truncated),
+        Err(RecordingError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+    ));
+
+    // The entry is not a request
+    let mut invalid = data;
+    invalid.extend(binary_entry(&u32::MAX));
+    assert!(matches!(
+        read_bytes("invalid", &invalid),
+        Err(RecordingError::Binary(_))
+    ));
+}
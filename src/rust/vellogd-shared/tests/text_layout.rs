@@ -0,0 +1,526 @@
+// Compare the text layout against the metrics read directly from the font
+// file, so that the text is placed where R expects.
+//
+// The font is specified by VELLOGD_TEST_FONT (a TrueType font that covers
+// ASCII and the Greek letters). If it's not set, DejaVu Sans is used; the tests
+// are skipped if it's not installed.
+
+use std::sync::{LazyLock, Mutex};
+
+use kurbo::Shape;
+
+use vello::skrifa::{
+    instance::{LocationRef, Size},
+    metrics::GlyphMetrics,
+    FontRef, MetadataProvider,
+};
+use vellogd_shared::{
+    ffi::R_GE_gcontext,
+    fonts::{register_font, set_symbol_font, GlyphSynthesis},
+    protocol::{FontFeature, RichText, TextAlign, TextSpan},
+    text_layouter::{
+        build_rich_layout, glyph_run_outlines, line_origins, line_spacing, rich_line_origins,
+        TextLayouter,
+    },
+};
+
+const DEFAULT_TEST_FONT: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+const FAMILY: &str = "vellogd-test";
+const SIZE: f32 = 12.0;
+// The outlines are scaled in the 26.6 fixed-point format
+const TOLERANCE: f64 = 1.0 / 64.0;
+
+// The font data, registered as FAMILY
+static FONT_DATA: LazyLock<Option<Vec<u8>>> = LazyLock::new(|| {
+    let path = std::env::var("VELLOGD_TEST_FONT").unwrap_or(DEFAULT_TEST_FONT.to_string());
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("skipping the test because {path} cannot be read: {e}");
+            return None;
+        }
+    };
+    register_font(FAMILY, 1, data.clone()).unwrap();
+    set_symbol_font(FAMILY).unwrap();
+    Some(data)
+});
+
+// The layout context is shared, so the tests are not run in parallel
+static LOCK: Mutex<()> = Mutex::new(());
+
+struct Layouter {
+    layout: parley::Layout<peniko::Brush>,
+    features: Vec<FontFeature>,
+}
+
+impl Layouter {
+    fn new(features: &[FontFeature]) -> Self {
+        Self {
+            layout: parley::Layout::new(),
+            features: features.to_vec(),
+        }
+    }
+}
+
+impl TextLayouter for Layouter {
+    fn layout_mut(&mut self) -> &mut parley::Layout<peniko::Brush> {
+        &mut self.layout
+    }
+
+    fn layout_ref(&self) -> &parley::Layout<peniko::Brush> {
+        &self.layout
+    }
+
+    fn font_features(&self) -> &[FontFeature] {
+        &self.features
+    }
+}
+
+fn gc(fontface: i32, lineheight: f64) -> R_GE_gcontext {
+    let mut gc: R_GE_gcontext = unsafe { std::mem::zeroed() };
+    gc.cex = 1.0;
+    gc.ps = SIZE as f64;
+    gc.lineheight = lineheight;
+    gc.fontface = fontface;
+    for (dst, src) in gc.fontfamily.iter_mut().zip(FAMILY.bytes()) {
+        *dst = src as _;
+    }
+    gc
+}
+
+// The metrics of a character in the font, scaled to SIZE
+struct Expected {
+    advance: f64,
+    ascent: f64,
+    descent: f64,
+}
+
+fn expected(data: &[u8], c: char) -> Expected {
+    let font = FontRef::new(data).unwrap();
+    let glyph_id = font.charmap().map(c).unwrap();
+    let metrics = GlyphMetrics::new(&font, Size::new(SIZE), LocationRef::default());
+    let advance = metrics.advance_width(glyph_id).unwrap() as f64;
+    let (ascent, descent) = match metrics.bounds(glyph_id) {
+        Some(bounds) => (bounds.y_max.max(0.0), (-bounds.y_min).max(0.0)),
+        None => (0.0, 0.0),
+    };
+    Expected {
+        advance,
+        ascent: ascent as f64,
+        descent: descent as f64,
+    }
+}
+
+fn assert_near(actual: f64, expected: f64, what: &str) {
+    assert!(
+        (actual - expected).abs() < TOLERANCE,
+        "{what}: expected {expected}, got {actual}"
+    );
+}
+
+#[test]
+fn char_metric_is_ink_extents_and_advance() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut layouter = Layouter::new(&[]);
+
+    for c in ['M', 'x', 'g', 'j', '.', '-'] {
+        let metric = layouter.get_char_metric(c, gc(1, 1.0));
+        let expected = expected(data, c);
+        assert_near(metric.width, expected.advance, &format!("width of {c:?}"));
+        assert_near(metric.ascent, expected.ascent, &format!("ascent of {c:?}"));
+        assert_near(
+            metric.descent,
+            expected.descent,
+            &format!("descent of {c:?}"),
+        );
+    }
+
+    // The font-wide metrics are the ones of 'M'
+    let metric = layouter.get_char_metric('\0', gc(1, 1.0));
+    assert_near(metric.ascent, expected(data, 'M').ascent, "ascent of 0");
+
+    // A space has no ink, but has the width
+    let metric = layouter.get_char_metric(' ', gc(1, 1.0));
+    assert_near(metric.width, expected(data, ' ').advance, "width of ' '");
+    assert_near(metric.ascent, 0.0, "ascent of ' '");
+}
+
+#[test]
+fn symbol_face_uses_symbol_font() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut layouter = Layouter::new(&[]);
+
+    let metric = layouter.get_char_metric('\u{3b1}', gc(5, 1.0));
+    let expected = expected(data, '\u{3b1}');
+    assert_near(metric.width, expected.advance, "width of alpha");
+    assert_near(metric.ascent, expected.ascent, "ascent of alpha");
+}
+
+#[test]
+fn text_width_is_sum_of_advances() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut layouter = Layouter::new(&[]);
+
+    // Note: the digits are not kerned
+    for text in ["0123", "1 ", " 2 "] {
+        let width = layouter.get_text_width(text, gc(1, 1.0));
+        let expected: f64 = text.chars().map(|c| expected(data, c).advance).sum();
+        assert_near(width, expected, &format!("width of {text:?}"));
+    }
+}
+
+#[test]
+fn lines_are_placed_at_baselines() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut layouter = Layouter::new(&[]);
+
+    let lines = ["12", "3456", "7"];
+    for lineheight in [1.0, 1.5] {
+        for hadj in [0.0, 0.5, 1.0] {
+            let text = lines.join("\n");
+            layouter.build_layout(&text, FAMILY, 1, SIZE, lineheight, &[]);
+            let spacing = line_spacing(SIZE, lineheight);
+            assert_near(
+                spacing,
+                1.2 * SIZE as f64 * lineheight as f64,
+                "line spacing",
+            );
+
+            let origins = line_origins(layouter.layout_ref(), hadj, spacing).collect::<Vec<_>>();
+            assert_eq!(origins.len(), lines.len());
+
+            for (i, ((line, origin), text)) in origins.into_iter().zip(lines).enumerate() {
+                let advances: Vec<f64> = text.chars().map(|c| expected(data, c).advance).collect();
+                let width: f64 = advances.iter().sum();
+                let what = format!("line {i} (lineheight: {lineheight}, hadj: {hadj})");
+
+                // The baseline of the first line is at the position
+                assert_near(origin.x, -width * hadj, &format!("x of {what}"));
+                assert_near(origin.y, i as f64 * spacing, &format!("y of {what}"));
+
+                // The glyphs are on the baseline, separated by the advances.
+                // The positions are calculated in the same way as drawing.
+                let mut glyphs = Vec::new();
+                for item in line.items() {
+                    if let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item {
+                        let mut x = glyph_run.offset();
+                        for glyph in glyph_run.glyphs() {
+                            glyphs.push((x + glyph.x, glyph.y, glyph.advance));
+                            x += glyph.advance;
+                        }
+                    }
+                }
+                assert_eq!(glyphs.len(), advances.len(), "glyphs of {what}");
+                let mut expected_x = 0.0;
+                for ((x, y, advance), expected_advance) in glyphs.into_iter().zip(advances) {
+                    assert_near(x as f64, expected_x, &format!("glyph x of {what}"));
+                    assert_near(y as f64, 0.0, &format!("glyph y of {what}"));
+                    assert_near(
+                        advance as f64,
+                        expected_advance,
+                        &format!("advance of {what}"),
+                    );
+                    expected_x += expected_advance;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn rtl_paragraph_is_ordered_right_to_left() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut layouter = Layouter::new(&[]);
+    let font = FontRef::new(data).unwrap();
+
+    // The paragraph starts with Hebrew, so the Latin word comes on the left
+    // (cf. "\u{5d0}\u{5d1} abc" is drawn as "abc \u{5d1}\u{5d0}")
+    let text = "\u{5d0}\u{5d1} abc";
+    let visual_order = ['a', 'b', 'c', ' ', '\u{5d1}', '\u{5d0}'];
+    layouter.build_layout(text, FAMILY, 1, SIZE, 1.0, &[]);
+
+    let (line, origin) = line_origins(layouter.layout_ref(), 1.0, line_spacing(SIZE, 1.0))
+        .next()
+        .unwrap();
+    let width: f64 = text.chars().map(|c| expected(data, c).advance).sum();
+    assert_near(origin.x, -width, "x of the line");
+
+    let mut glyph_ids = Vec::new();
+    for item in line.items() {
+        if let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item {
+            glyph_ids.extend(glyph_run.glyphs().map(|glyph| glyph.id as u32));
+        }
+    }
+    let expected_ids: Vec<u32> = visual_order
+        .iter()
+        .map(|c| font.charmap().map(*c).unwrap().to_u32())
+        .collect();
+    assert_eq!(glyph_ids, expected_ids);
+}
+
+#[test]
+fn missing_faces_are_synthesized() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut layouter = Layouter::new(&[]);
+
+    // Only the plain face is registered, so the bold italic is synthesized on
+    // the layout...
+    layouter.build_layout("abc", FAMILY, 4, SIZE, 1.0, &[]);
+    let line = layouter.layout_ref().lines().next().unwrap();
+    for item in line.items() {
+        if let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item {
+            let synthesis = GlyphSynthesis::from_run(glyph_run.run());
+            assert!(synthesis.embolden);
+            assert!(synthesis.skew.is_some());
+        }
+    }
+
+    // ...and on the font file specified by the glyph API
+    let font = parley::Font::new(data.clone().into(), 0);
+    let synthesis =
+        GlyphSynthesis::for_font(&font, parley::FontWeight::BOLD, parley::FontStyle::Italic);
+    assert!(synthesis.embolden);
+    assert!(synthesis.skew.is_some());
+
+    let synthesis =
+        GlyphSynthesis::for_font(&font, parley::FontWeight::NORMAL, parley::FontStyle::Normal);
+    assert!(!synthesis.embolden);
+    assert!(synthesis.skew.is_none());
+}
+
+fn span(text: &str, size: Option<f32>, color: Option<peniko::Color>) -> TextSpan {
+    TextSpan {
+        text: text.to_string(),
+        family: None,
+        face: None,
+        size,
+        color,
+        features: Vec::new(),
+    }
+}
+
+fn rich_text(spans: Vec<TextSpan>, max_width: Option<f32>, align: TextAlign) -> RichText {
+    RichText {
+        spans,
+        family: FAMILY.to_string(),
+        face: 1,
+        size: SIZE,
+        color: peniko::Color::BLACK,
+        lineheight: 1.0,
+        features: Vec::new(),
+        max_width,
+        align,
+        halo: None,
+    }
+}
+
+#[test]
+fn rich_text_spans_have_own_styles() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut layout = parley::Layout::new();
+
+    let red = peniko::Color::rgb8(255, 0, 0);
+    let text = rich_text(
+        vec![
+            span("12", None, None),
+            span("34", Some(2.0 * SIZE), Some(red)),
+        ],
+        None,
+        TextAlign::Left,
+    );
+    build_rich_layout(&mut layout, &text);
+
+    let line = layout.lines().next().unwrap();
+    let mut runs = Vec::new();
+    for item in line.items() {
+        if let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item {
+            let color = match &glyph_run.style().brush {
+                peniko::Brush::Solid(color) => *color,
+                _ => panic!("unexpected brush"),
+            };
+            runs.push((
+                glyph_run.run().font_size(),
+                color,
+                glyph_run.glyphs().count(),
+            ));
+        }
+    }
+    assert_eq!(
+        runs,
+        vec![(SIZE, peniko::Color::BLACK, 2), (2.0 * SIZE, red, 2)]
+    );
+
+    // The larger span makes the line taller
+    let width = expected(data, '1').advance
+        + expected(data, '2').advance
+        + 2.0 * (expected(data, '3').advance + expected(data, '4').advance);
+    assert_near(layout.full_width() as f64, width, "width");
+    assert!(line.metrics().line_height >= (1.2 * 2.0 * SIZE).floor());
+}
+
+#[test]
+fn rich_text_is_wrapped_and_aligned() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut layout = parley::Layout::new();
+
+    let word_width: f64 = "0000".chars().map(|c| expected(data, c).advance).sum();
+    let space_width = expected(data, ' ').advance;
+    // Two words fit in a line, but three don't
+    let max_width = (2.0 * word_width + 2.0 * space_width) as f32;
+
+    for (align, free_space_ratio) in [
+        (TextAlign::Left, 0.0),
+        (TextAlign::Center, 0.5),
+        (TextAlign::Right, 1.0),
+    ] {
+        let text = rich_text(
+            vec![span("0000 0000 ", None, None), span("0000", None, None)],
+            Some(max_width),
+            align,
+        );
+        build_rich_layout(&mut layout, &text);
+        assert_eq!(layout.len(), 2, "lines of {align:?}");
+
+        let height = layout.height() as f64;
+        let origins = rich_line_origins(&layout, Some(max_width), 1.0, 1.0).collect::<Vec<_>>();
+        for (i, (line, origin)) in origins.into_iter().enumerate() {
+            let metrics = line.metrics();
+            let what = format!("line {i} of {align:?}");
+
+            // The box is placed left to the position, and below it
+            assert_near(origin.x, -max_width as f64, &format!("x of {what}"));
+            assert_near(origin.y, metrics.baseline as f64, &format!("y of {what}"));
+            assert!(origin.y < height);
+
+            let ink_width = (metrics.advance - metrics.trailing_whitespace) as f64;
+            let expected_offset = (max_width as f64 - ink_width) * free_space_ratio;
+            assert_near(
+                metrics.offset as f64,
+                expected_offset,
+                &format!("offset of {what}"),
+            );
+        }
+    }
+}
+
+// The glyphs of the text as laid out
+fn glyph_ids(layout: &parley::Layout<peniko::Brush>) -> Vec<u32> {
+    let mut glyph_ids = Vec::new();
+    for line in layout.lines() {
+        for item in line.items() {
+            if let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item {
+                glyph_ids.extend(glyph_run.glyphs().map(|glyph| glyph.id as u32));
+            }
+        }
+    }
+    glyph_ids
+}
+
+#[test]
+fn font_features_are_applied_to_metrics_and_layout() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let font = FontRef::new(data).unwrap();
+    let glyph_id = |c: char| font.charmap().map(c).unwrap().to_u32();
+
+    // "fi" is a ligature by default
+    let mut layouter = Layouter::new(&[]);
+    layouter.build_layout("fi", FAMILY, 1, SIZE, 1.0, &[]);
+    let ligature = glyph_ids(layouter.layout_ref());
+    assert_eq!(ligature.len(), 1);
+    let metrics = GlyphMetrics::new(&font, Size::new(SIZE), LocationRef::default());
+    let ligature_advance = metrics.advance_width(ligature[0].into()).unwrap() as f64;
+    let width = layouter.get_text_width("fi", gc(1, 1.0));
+    assert_near(width, ligature_advance, "width with ligature");
+
+    // The ligature is disabled on both the layout and the metrics
+    let no_liga = [FontFeature::new("liga", 0).unwrap()];
+    let mut layouter = Layouter::new(&no_liga);
+    layouter.build_layout("fi", FAMILY, 1, SIZE, 1.0, &no_liga);
+    assert_eq!(
+        glyph_ids(layouter.layout_ref()),
+        vec![glyph_id('f'), glyph_id('i')]
+    );
+    let width = layouter.get_text_width("fi", gc(1, 1.0));
+    assert_near(
+        width,
+        expected(data, 'f').advance + expected(data, 'i').advance,
+        "width without ligature",
+    );
+
+    // The feature of a span overrides the one of the whole text
+    let mut layout = parley::Layout::new();
+    let mut text = rich_text(
+        vec![span("fi", None, None), span("fi", None, None)],
+        None,
+        TextAlign::Left,
+    );
+    text.features = no_liga.to_vec();
+    text.spans[1].features = vec![FontFeature::new("liga", 1).unwrap()];
+    build_rich_layout(&mut layout, &text);
+    assert_eq!(
+        glyph_ids(&layout),
+        vec![glyph_id('f'), glyph_id('i'), ligature[0]]
+    );
+}
+
+#[test]
+fn glyph_outlines_are_placed_as_drawn() {
+    let Some(data) = FONT_DATA.as_ref() else {
+        return;
+    };
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut layouter = Layouter::new(&[]);
+    let font = FontRef::new(data).unwrap();
+    let metrics = GlyphMetrics::new(&font, Size::new(SIZE), LocationRef::default());
+    let bounds = |c: char| metrics.bounds(font.charmap().map(c).unwrap()).unwrap();
+
+    layouter.build_layout("1g", FAMILY, 1, SIZE, 1.0, &[]);
+    let line = layouter.layout_ref().lines().next().unwrap();
+    let mut outlines = kurbo::BezPath::new();
+    for item in line.items() {
+        if let parley::PositionedLayoutItem::GlyphRun(glyph_run) = item {
+            outlines.extend(glyph_run_outlines(&glyph_run));
+        }
+    }
+
+    // The outlines are y-down, and the second glyph is shifted by the advance
+    // of the first one
+    let (one, g) = (bounds('1'), bounds('g'));
+    let advance = expected(data, '1').advance;
+    let bbox = outlines.bounding_box();
+    assert_near(
+        bbox.x0,
+        (one.x_min as f64).min(advance + g.x_min as f64),
+        "left",
+    );
+    assert_near(bbox.x1, advance + g.x_max as f64, "right");
+    assert_near(bbox.y0, -one.y_max.max(g.y_max) as f64, "top");
+    assert_near(bbox.y1, -one.y_min.min(g.y_min) as f64, "bottom");
+}
@@ -0,0 +1,200 @@
+// Send messages over TCP and WebSocket on the loopback interface, and check the
+// framing and the limits.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use vellogd_shared::transport::{
+    Address, BoxedReceiver, BoxedSender, TransportError, MAX_MESSAGE_SIZE,
+};
+
+type Message = (u32, Vec<u8>);
+
+#[test]
+fn test_parse_address() {
+    assert_eq!(
+        "tcp://127.0.0.1:9000".parse::<Address>().unwrap(),
+        Address::Tcp("127.0.0.1:9000".to_string())
+    );
+    assert_eq!(
+        "ws://localhost:9000".parse::<Address>().unwrap(),
+        Address::WebSocket("localhost:9000".to_string())
+    );
+    // The path is ignored
+    assert_eq!(
+        "ws://localhost:9000/path".parse::<Address>().unwrap(),
+        Address::WebSocket("localhost:9000".to_string())
+    );
+
+    for address in [
+        "127.0.0.1:9000",
+        "http://127.0.0.1:9000",
+        "tcp://",
+        "ws:///path",
+        "",
+    ] {
+        assert!(
+            matches!(
+                address.parse::<Address>(),
+                Err(TransportError::InvalidAddress(x)) if x == address
+            ),
+            "{address} should be invalid"
+        );
+    }
+
+    for address in ["tcp://127.0.0.1:9000", "ws://[::1]:9000"] {
+        assert_eq!(address.parse::<Address>().unwrap().to_string(), address);
+    }
+}
+
+#[test]
+fn test_listen_only_on_loopback() {
+    for address in ["tcp://0.0.0.0:0", "ws://0.0.0.0:0"] {
+        let address: Address = address.parse().unwrap();
+        assert!(matches!(
+            address.listen(false),
+            Err(TransportError::RemoteNotAllowed(_))
+        ));
+        assert!(address.listen(true).is_ok());
+    }
+
+    for address in ["tcp://127.0.0.1:0", "tcp://localhost:0", "ws://127.0.0.1:0"] {
+        let address: Address = address.parse().unwrap();
+        assert!(address.listen(false).is_ok(), "{address} should be allowed");
+    }
+}
+
+// Connect to a listener on the loopback interface, and returns the pairs of
+// the both sides.
+#[allow(clippy::type_complexity)]
+fn connect(
+    address: &str,
+) -> (
+    (BoxedSender<Message>, BoxedReceiver<Message>),
+    (BoxedSender<Message>, BoxedReceiver<Message>),
+) {
+    let listener = address.parse::<Address>().unwrap().listen(false).unwrap();
+    let address = listener.address().unwrap();
+    let client = std::thread::spawn(move || address.connect().unwrap());
+    let server = listener.accept_timeout(Duration::from_secs(10)).unwrap();
+    (client.join().unwrap(), server)
+}
+
+fn round_trip(address: &str) {
+    let ((client_tx, client_rx), (server_tx, server_rx)) = connect(address);
+
+    let messages: Vec<Message> = vec![
+        (0, Vec::new()),
+        (1, vec![1, 2, 3]),
+        // Larger than the buffers of the socket
+        (2, (0..4 * 1024 * 1024).map(|i| i as u8).collect()),
+        (3, vec![4]),
+    ];
+
+    // Echo back on another thread so that both sides send and receive at the
+    // same time.
+    let n = messages.len();
+    let echo = std::thread::spawn(move || {
+        for _ in 0..n {
+            let msg: Message = server_rx.recv_message().unwrap();
+            server_tx.send_message(msg).unwrap();
+        }
+    });
+
+    for msg in &messages {
+        client_tx.send_message(msg.clone()).unwrap();
+    }
+    for msg in &messages {
+        assert_eq!(&client_rx.recv_message().unwrap(), msg);
+    }
+    echo.join().unwrap();
+
+    // The peer is gone
+    assert!(client_rx.recv_message().is_err());
+}
+
+#[test]
+fn test_round_trip_tcp() {
+    round_trip("tcp://127.0.0.1:0");
+}
+
+#[test]
+fn test_round_trip_websocket() {
+    round_trip("ws://127.0.0.1:0");
+}
+
+// Listen on TCP and connect to it with a raw socket
+fn raw_pair() -> (TcpStream, BoxedSender<Message>, BoxedReceiver<Message>) {
+    let listener = "tcp://127.0.0.1:0"
+        .parse::<Address>()
+        .unwrap()
+        .listen(false)
+        .unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (tx, rx) = listener.accept().unwrap();
+    (stream, tx, rx)
+}
+
+#[test]
+fn test_tcp_framing() {
+    let (mut stream, tx, rx) = raw_pair();
+
+    // A message is the length (u32, little endian) followed by the data
+    let msg: Message = (7, vec![1, 2, 3]);
+    tx.send_message(msg.clone()).unwrap();
+    let data = bincode::serialize(&msg).unwrap();
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).unwrap();
+    assert_eq!(u32::from_le_bytes(len) as usize, data.len());
+    let mut received = vec![0u8; data.len()];
+    stream.read_exact(&mut received).unwrap();
+    assert_eq!(received, data);
+
+    // Two messages written at once are read separately
+    let mut frames = Vec::new();
+    let messages: [Message; 2] = [(1, vec![1]), (2, vec![2, 2])];
+    for msg in messages {
+        let data = bincode::serialize(&msg).unwrap();
+        frames.extend((data.len() as u32).to_le_bytes());
+        frames.extend(data);
+    }
+    stream.write_all(&frames).unwrap();
+    assert_eq!(rx.recv_message().unwrap(), (1, vec![1]));
+    assert_eq!(rx.recv_message().unwrap(), (2, vec![2, 2]));
+}
+
+#[test]
+fn test_tcp_rejects_too_large_message() {
+    let (mut stream, _tx, rx) = raw_pair();
+
+    // Only the header is sent. If the receiver tried to allocate and read the
+    // data, this would block forever.
+    let len = MAX_MESSAGE_SIZE as u32 + 1;
+    stream.write_all(&len.to_le_bytes()).unwrap();
+    assert!(matches!(
+        rx.recv_message(),
+        Err(TransportError::MessageTooLarge(x)) if x == len as usize
+    ));
+}
+
+#[test]
+fn test_tcp_broken_message() {
+    let (mut stream, _tx, rx) = raw_pair();
+
+    // The data is not a Message
+    stream.write_all(&1u32.to_le_bytes()).unwrap();
+    stream.write_all(&[0]).unwrap();
+    assert!(matches!(
+        rx.recv_message(),
+        Err(TransportError::Serialize(_))
+    ));
+
+    // The connection is closed in the middle of a message
+    stream.write_all(&100u32.to_le_bytes()).unwrap();
+    stream.write_all(&[0; 10]).unwrap();
+    drop(stream);
+    assert!(matches!(rx.recv_message(), Err(TransportError::Io(_))));
+}